@@ -0,0 +1,240 @@
+mod model;
+mod params;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, LitStr, parse_macro_input, parse_quote, spanned::Spanned};
+
+/// Implements decthings_model::Model for a type from an impl block with plain `async fn`s. The
+/// functions `setup`, `initialize_weights` and `instantiate_model` are taken as the trait methods,
+/// with the same arguments as in the trait but without lifetimes, such as
+/// `async fn instantiate_model(options: InstantiateModelOptions<impl WeightsLoader>) -> MyInstantiated`.
+/// Model::Instantiated is the return type of `instantiate_model`. A plain
+/// `fn parameter_definitions()` is taken as the trait function as it is. Other items are kept in
+/// an inherent impl block.
+///
+/// A main function that runs the model on unix, and the export_decthings_model! invocation on
+/// wasm, are generated as well. The wasm bindings are expected in the module `bindings`, which can
+/// be changed with `#[model(bindings = path::to::bindings)]`. Use `#[model(main = false)]` to not
+/// generate either, for example in a library. Use `#[model(binary)]` to implement ModelBinary
+/// instead.
+#[proc_macro_attribute]
+pub fn model(attr: TokenStream, item: TokenStream) -> TokenStream {
+    expand_model(model::Kind::Model, attr, item)
+}
+
+/// Implements decthings_model::Instantiated for a type from an impl block with plain `async fn`s,
+/// in the same way as `#[model]`. The functions `evaluate`, `train`, `get_weights` and `dispose`
+/// are taken as the trait methods, such as
+/// `async fn evaluate(&self, options: EvaluateOptions<impl DataLoader>) -> Vec<EvaluateOutput>`.
+/// Use `#[instantiated(binary)]` to implement InstantiatedBinary instead.
+#[proc_macro_attribute]
+pub fn instantiated(attr: TokenStream, item: TokenStream) -> TokenStream {
+    expand_model(model::Kind::Instantiated, attr, item)
+}
+
+fn expand_model(kind: model::Kind, attr: TokenStream, item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as syn::ItemImpl);
+    model::parse_options(kind, attr.into())
+        .and_then(|options| model::expand(kind, options, input))
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Implements decthings_model::ModelInputs for a struct with named fields. Each field is a
+/// parameter named after the field, and its type must implement decthings_model::InputParam, such
+/// as `Vec<Array2<f32>>` or `Option<Vec<Array1<u8>>>` for an optional parameter.
+///
+/// Fields can be annotated with `#[param(rename = "name")]` to use another parameter name, and with
+/// `#[param(shape = [-1, 28, 28])]` to set the shape of each data point in the parameter
+/// definition, where -1 is a dimension of any size.
+#[proc_macro_derive(ModelInputs, attributes(param))]
+pub fn derive_model_inputs(input: TokenStream) -> TokenStream {
+    expand_params(params::Kind::Inputs, input)
+}
+
+/// Implements decthings_model::ModelOutputs for a struct with named fields, in the same way as
+/// `#[derive(ModelInputs)]`. The type of each field must implement decthings_model::OutputParam.
+#[proc_macro_derive(ModelOutputs, attributes(param))]
+pub fn derive_model_outputs(input: TokenStream) -> TokenStream {
+    expand_params(params::Kind::Outputs, input)
+}
+
+fn expand_params(kind: params::Kind, input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    params::expand(kind, input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Implements decthings_model::ModelWeights for a struct. Each field is stored under a key equal to
+/// its name, and the fields of nested structs under dotted keys such as "encoder.weight". Fields
+/// of tuple structs use their index as name.
+///
+/// Fields can be annotated with `#[weights(rename = "name")]` to use another key, or with
+/// `#[weights(skip)]` to not store them, in which case they are set to Default::default() when
+/// loading. The struct can be annotated with `#[weights(schema_version = 2)]` to set
+/// ModelWeights::SCHEMA_VERSION, with `#[weights(migrate = path::to::function)]` to implement
+/// ModelWeights::migrate with a function of the same signature, and with
+/// `#[weights(accept_headerless)]` to set ModelWeights::ACCEPT_HEADERLESS.
+#[proc_macro_derive(ModelWeights, attributes(weights))]
+pub fn derive_model_weights(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match model_weights(input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct Field {
+    member: syn::Member,
+    key: String,
+    skip: bool,
+}
+
+fn parse_field(index: usize, field: &syn::Field) -> syn::Result<Field> {
+    let member = match &field.ident {
+        Some(ident) => syn::Member::Named(ident.clone()),
+        None => syn::Member::Unnamed(index.into()),
+    };
+    let mut key = match &field.ident {
+        Some(ident) => ident.to_string().trim_start_matches("r#").to_owned(),
+        None => index.to_string(),
+    };
+    let mut skip = false;
+    for attr in field.attrs.iter().filter(|x| x.path().is_ident("weights")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else if meta.path.is_ident("rename") {
+                key = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `skip` or `rename`"))
+            }
+        })?;
+    }
+    Ok(Field { member, key, skip })
+}
+
+fn parse_container(attrs: &[syn::Attribute]) -> syn::Result<proc_macro2::TokenStream> {
+    let mut items = quote! {};
+    for attr in attrs.iter().filter(|x| x.path().is_ident("weights")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("schema_version") {
+                let version = meta.value()?.parse::<syn::LitInt>()?;
+                items.extend(quote! {
+                    const SCHEMA_VERSION: u32 = #version;
+                });
+                Ok(())
+            } else if meta.path.is_ident("migrate") {
+                let function = meta.value()?.parse::<syn::Path>()?;
+                items.extend(quote! {
+                    fn migrate(
+                        from_version: u32,
+                        weights: &mut ::std::collections::HashMap<
+                            ::std::string::String,
+                            ::decthings_model::bytes::Bytes,
+                        >,
+                    ) -> ::core::result::Result<(), ::decthings_model::WeightsError> {
+                        #function(from_version, weights)
+                    }
+                });
+                Ok(())
+            } else if meta.path.is_ident("accept_headerless") {
+                items.extend(quote! {
+                    const ACCEPT_HEADERLESS: bool = true;
+                });
+                Ok(())
+            } else {
+                Err(meta.error("expected `schema_version`, `migrate` or `accept_headerless`"))
+            }
+        })?;
+    }
+    Ok(items)
+}
+
+fn model_weights(mut input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let container = parse_container(&input.attrs)?;
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            "ModelWeights can only be derived for structs",
+        ));
+    };
+    let fields = data
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| parse_field(i, field))
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let save = fields.iter().filter(|x| !x.skip).map(|field| {
+        let member = &field.member;
+        let key = &field.key;
+        quote! {
+            ::decthings_model::ModelWeights::save_weights(
+                &self.#member,
+                &::decthings_model::weights_key(prefix, #key),
+                out,
+            );
+        }
+    });
+
+    let values = fields.iter().map(|field| {
+        let key = &field.key;
+        if field.skip {
+            quote! { ::core::default::Default::default() }
+        } else {
+            quote! {
+                ::decthings_model::ModelWeights::load_weights(
+                    &::decthings_model::weights_key(prefix, #key),
+                    weights,
+                )?
+            }
+        }
+    });
+    let construct = match &data.fields {
+        Fields::Named(_) => {
+            let names = fields.iter().map(|x| &x.member);
+            quote! { Self { #(#names: #values),* } }
+        }
+        Fields::Unnamed(_) => quote! { Self(#(#values),*) },
+        Fields::Unit => quote! { Self },
+    };
+
+    for param in input.generics.type_params_mut() {
+        param
+            .bounds
+            .push(parse_quote!(::decthings_model::ModelWeights));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::decthings_model::ModelWeights for #name #ty_generics #where_clause {
+            #container
+
+            #[allow(unused_variables)]
+            fn save_weights(
+                &self,
+                prefix: &str,
+                out: &mut ::std::vec::Vec<(::std::string::String, ::decthings_model::bytes::Bytes)>,
+            ) {
+                #(#save)*
+            }
+
+            #[allow(unused_variables)]
+            fn load_weights(
+                prefix: &str,
+                weights: &::std::collections::HashMap<
+                    ::std::string::String,
+                    ::decthings_model::bytes::Bytes,
+                >,
+            ) -> ::core::result::Result<Self, ::decthings_model::WeightsError> {
+                ::core::result::Result::Ok(#construct)
+            }
+        }
+    })
+}
@@ -0,0 +1,252 @@
+use quote::quote;
+use syn::{
+    FnArg, ImplItem, ImplItemFn, ItemImpl, ReturnType, Type, parse_quote, spanned::Spanned,
+    visit_mut::VisitMut,
+};
+
+/// Which trait the impl block implements.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Model,
+    Instantiated,
+}
+
+impl Kind {
+    fn methods(self) -> &'static [&'static str] {
+        match self {
+            Self::Model => &["setup", "initialize_weights", "instantiate_model"],
+            Self::Instantiated => &["evaluate", "train", "get_weights", "dispose"],
+        }
+    }
+
+    /// Trait functions that are not async, and are kept as they are.
+    fn sync_methods(self) -> &'static [&'static str] {
+        match self {
+            Self::Model => &["parameter_definitions"],
+            Self::Instantiated => &[],
+        }
+    }
+}
+
+pub struct Options {
+    binary: bool,
+    main: bool,
+    bindings: syn::Path,
+}
+
+pub fn parse_options(kind: Kind, attr: proc_macro2::TokenStream) -> syn::Result<Options> {
+    let mut options = Options {
+        binary: false,
+        main: true,
+        bindings: parse_quote!(bindings),
+    };
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("binary") {
+            options.binary = true;
+            Ok(())
+        } else if matches!(kind, Kind::Model) && meta.path.is_ident("main") {
+            options.main = meta.value()?.parse::<syn::LitBool>()?.value;
+            Ok(())
+        } else if matches!(kind, Kind::Model) && meta.path.is_ident("bindings") {
+            options.bindings = meta.value()?.parse()?;
+            Ok(())
+        } else if matches!(kind, Kind::Model) {
+            Err(meta.error("expected `binary`, `main` or `bindings`"))
+        } else {
+            Err(meta.error("expected `binary`"))
+        }
+    });
+    syn::parse::Parser::parse2(parser, attr)?;
+    Ok(options)
+}
+
+/// Adds a lifetime bound to every `impl Trait` type, and sets the lifetime of references whose
+/// lifetime is elided.
+struct AddLifetime(syn::Lifetime);
+
+impl VisitMut for AddLifetime {
+    fn visit_type_impl_trait_mut(&mut self, ty: &mut syn::TypeImplTrait) {
+        syn::visit_mut::visit_type_impl_trait_mut(self, ty);
+        ty.bounds
+            .push(syn::TypeParamBound::Lifetime(self.0.clone()));
+    }
+
+    fn visit_type_reference_mut(&mut self, ty: &mut syn::TypeReference) {
+        syn::visit_mut::visit_type_reference_mut(self, ty);
+        if ty.lifetime.as_ref().is_none_or(|x| x.ident == "_") {
+            ty.lifetime = Some(self.0.clone());
+        }
+    }
+}
+
+/// Turns `async fn f(&self, options: O<impl T>) -> R { body }` into
+/// `fn f<'a>(&'a self, options: O<impl T + 'a>) -> BoxFuture<'a, R> { Box::pin(async move { body }) }`.
+fn transform_method(mut method: ImplItemFn) -> syn::Result<(ImplItemFn, Type)> {
+    if method.sig.asyncness.take().is_none() {
+        return Err(syn::Error::new(
+            method.sig.fn_token.span(),
+            "expected an async fn",
+        ));
+    }
+    let lifetime: syn::Lifetime = parse_quote!('decthings_model);
+    method
+        .sig
+        .generics
+        .params
+        .insert(0, parse_quote!(#lifetime));
+
+    for input in &mut method.sig.inputs {
+        match input {
+            FnArg::Receiver(receiver) => {
+                if receiver.reference.is_none() || receiver.mutability.is_some() {
+                    return Err(syn::Error::new(
+                        receiver.span(),
+                        "expected `&self`, since the trait methods take `&self`",
+                    ));
+                }
+                receiver.reference = Some((parse_quote!(&), Some(lifetime.clone())));
+                receiver.ty = parse_quote!(&#lifetime Self);
+            }
+            FnArg::Typed(arg) => AddLifetime(lifetime.clone()).visit_type_mut(&mut arg.ty),
+        }
+    }
+
+    let output: Type = match &method.sig.output {
+        ReturnType::Default => parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    method.sig.output = parse_quote! {
+        -> ::decthings_model::futures::future::BoxFuture<#lifetime, #output>
+    };
+    let block = &method.block;
+    method.block = parse_quote! {
+        {
+            ::std::boxed::Box::pin(async move #block)
+        }
+    };
+    Ok((method, output))
+}
+
+pub fn expand(
+    kind: Kind,
+    options: Options,
+    input: ItemImpl,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if let Some((_, path, _)) = &input.trait_ {
+        return Err(syn::Error::new(
+            path.span(),
+            "expected an inherent impl block, the trait impl is generated",
+        ));
+    }
+
+    let mut trait_items = vec![];
+    let mut inherent_items = vec![];
+    let mut instantiated = None;
+    for item in input.items {
+        match item {
+            ImplItem::Fn(method)
+                if kind
+                    .methods()
+                    .contains(&method.sig.ident.to_string().as_str()) =>
+            {
+                let name = method.sig.ident.to_string();
+                let (method, output) = transform_method(method)?;
+                if name == "instantiate_model" {
+                    instantiated = Some(output);
+                }
+                trait_items.push(method);
+            }
+            ImplItem::Fn(method)
+                if kind
+                    .sync_methods()
+                    .contains(&method.sig.ident.to_string().as_str()) =>
+            {
+                trait_items.push(method);
+            }
+            item => inherent_items.push(item),
+        }
+    }
+
+    let self_ty = &input.self_ty;
+    let (impl_generics, _, where_clause) = input.generics.split_for_impl();
+    let attrs = &input.attrs;
+
+    let inherent = if inherent_items.is_empty() {
+        quote! {}
+    } else {
+        quote! {
+            #(#attrs)*
+            impl #impl_generics #self_ty #where_clause {
+                #(#inherent_items)*
+            }
+        }
+    };
+
+    let tokens = match kind {
+        Kind::Model => {
+            let Some(instantiated) = instantiated else {
+                return Err(syn::Error::new(
+                    self_ty.span(),
+                    "expected an `async fn instantiate_model`",
+                ));
+            };
+            let trait_path = if options.binary {
+                quote!(::decthings_model::ModelBinary)
+            } else {
+                quote!(::decthings_model::Model)
+            };
+            let entry = if options.main {
+                let Type::Path(ty) = &**self_ty else {
+                    return Err(syn::Error::new(
+                        self_ty.span(),
+                        "the main function can only be generated for a plain type name, use `main = false`",
+                    ));
+                };
+                let Some(ident) = ty.path.get_ident() else {
+                    return Err(syn::Error::new(
+                        self_ty.span(),
+                        "the main function can only be generated for a plain type name, use `main = false`",
+                    ));
+                };
+                let bindings = &options.bindings;
+                quote! {
+                    #[cfg(target_family = "unix")]
+                    fn main() {
+                        ::decthings_model::run_model_blocking::<#ident>();
+                    }
+
+                    #[cfg(target_family = "wasm")]
+                    ::decthings_model::wasm_bindings::export_decthings_model!(#ident with_types_in #bindings);
+                }
+            } else {
+                quote! {}
+            };
+            quote! {
+                #inherent
+
+                impl #impl_generics #trait_path for #self_ty #where_clause {
+                    type Instantiated = #instantiated;
+
+                    #(#trait_items)*
+                }
+
+                #entry
+            }
+        }
+        Kind::Instantiated => {
+            let trait_path = if options.binary {
+                quote!(::decthings_model::InstantiatedBinary)
+            } else {
+                quote!(::decthings_model::Instantiated)
+            };
+            quote! {
+                #inherent
+
+                impl #impl_generics #trait_path for #self_ty #where_clause {
+                    #(#trait_items)*
+                }
+            }
+        }
+    };
+    Ok(tokens)
+}
@@ -0,0 +1,270 @@
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, LitStr, spanned::Spanned};
+
+/// Which trait is derived.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    Inputs,
+    Outputs,
+}
+
+impl Kind {
+    fn trait_name(self) -> &'static str {
+        match self {
+            Self::Inputs => "ModelInputs",
+            Self::Outputs => "ModelOutputs",
+        }
+    }
+}
+
+struct Field {
+    ident: syn::Ident,
+    ty: syn::Type,
+    name: String,
+    shape: Option<Vec<i64>>,
+}
+
+fn parse_field(field: &syn::Field) -> syn::Result<Field> {
+    let ident = field.ident.clone().unwrap();
+    let mut name = ident.to_string().trim_start_matches("r#").to_owned();
+    let mut shape = None;
+    for attr in field.attrs.iter().filter(|x| x.path().is_ident("param")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else if meta.path.is_ident("shape") {
+                let array = meta.value()?.parse::<syn::ExprArray>()?;
+                shape = Some(
+                    array
+                        .elems
+                        .iter()
+                        .map(parse_dimension)
+                        .collect::<syn::Result<_>>()?,
+                );
+                Ok(())
+            } else {
+                Err(meta.error("expected `rename` or `shape`"))
+            }
+        })?;
+    }
+    Ok(Field {
+        ident,
+        ty: field.ty.clone(),
+        name,
+        shape,
+    })
+}
+
+/// Parses a dimension of `#[param(shape = [...])]`, which is a positive integer or -1.
+fn parse_dimension(expr: &syn::Expr) -> syn::Result<i64> {
+    let error = || syn::Error::new(expr.span(), "expected a positive integer or -1");
+    match expr {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Int(x),
+            ..
+        }) => match x.base10_parse::<u32>() {
+            Ok(x) if x > 0 => Ok(i64::from(x)),
+            _ => Err(error()),
+        },
+        syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => match &**expr {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Int(x),
+                ..
+            }) if x.base10_digits() == "1" => Ok(-1),
+            _ => Err(error()),
+        },
+        _ => Err(error()),
+    }
+}
+
+/// The dimensions of a shape as `Option<u32>` expressions, where -1 is None.
+fn dimensions(shape: &[i64]) -> impl Iterator<Item = proc_macro2::TokenStream> + '_ {
+    shape.iter().map(|&x| {
+        if x < 0 {
+            quote! { ::core::option::Option::None }
+        } else {
+            let x = x as u32;
+            quote! { ::core::option::Option::Some(#x) }
+        }
+    })
+}
+
+pub fn expand(kind: Kind, input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.span(),
+            format!("{} can only be derived for structs", kind.trait_name()),
+        ));
+    };
+    let fields = match &data.fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(parse_field)
+            .collect::<syn::Result<Vec<_>>>()?,
+        Fields::Unit => vec![],
+        Fields::Unnamed(_) => {
+            return Err(syn::Error::new(
+                data.fields.span(),
+                format!(
+                    "{} can only be derived for structs with named fields",
+                    kind.trait_name()
+                ),
+            ));
+        }
+    };
+    for (i, field) in fields.iter().enumerate() {
+        if fields[..i].iter().any(|x| x.name == field.name) {
+            return Err(syn::Error::new(
+                field.ident.span(),
+                format!(
+                    "the parameter name \"{}\" is used more than once",
+                    field.name
+                ),
+            ));
+        }
+    }
+
+    // The shape must be given for types without a fixed number of dimensions, and must otherwise
+    // have that number of dimensions. This is checked at compile time.
+    let assertions = fields.iter().map(|field| {
+        let ty = &field.ty;
+        let ndim = quote! { <#ty as ::decthings_model::ParamType>::NDIM };
+        match &field.shape {
+            None => {
+                let message = format!(
+                    "The parameter \"{}\" does not have a fixed number of dimensions, so its shape must be set with #[param(shape = [...])].",
+                    field.name
+                );
+                quote! { ::core::assert!(#ndim.is_some(), #message); }
+            }
+            Some(shape) => {
+                let len = shape.len();
+                let message = format!(
+                    "The shape of the parameter \"{}\" does not have the number of dimensions of its type.",
+                    field.name
+                );
+                quote! {
+                    ::core::assert!(
+                        match #ndim {
+                            ::core::option::Option::Some(ndim) => ndim == #len,
+                            ::core::option::Option::None => true,
+                        },
+                        #message
+                    );
+                }
+            }
+        }
+    });
+    // Constants outside of the impl cannot refer to generic parameters, so generic structs check
+    // within parameter_definitions instead, once it is instantiated.
+    let (assertions, inline_assertions) = if input.generics.params.is_empty() {
+        (quote! { const _: () = { #(#assertions)* }; }, quote! {})
+    } else {
+        (quote! {}, quote! { const { #(#assertions)* } })
+    };
+
+    let definitions = fields.iter().map(|field| {
+        let ty = &field.ty;
+        let name = &field.name;
+        let shape = match &field.shape {
+            Some(shape) => {
+                let dimensions = dimensions(shape);
+                quote! { ::core::option::Option::Some(::std::vec![#(#dimensions),*]) }
+            }
+            None => quote! { ::core::option::Option::None },
+        };
+        quote! {
+            ::decthings_model::param_definition::<#ty>(#name, #shape)
+        }
+    });
+    let parameter_definitions = quote! {
+        fn parameter_definitions() -> ::std::vec::Vec<
+            ::decthings_model::decthings_api::tensor::DecthingsParameterDefinition,
+        > {
+            #inline_assertions
+            ::std::vec![#(#definitions),*]
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let tokens = match kind {
+        Kind::Inputs => {
+            let names = fields.iter().map(|x| &x.name);
+            let vars = fields
+                .iter()
+                .map(|x| quote::format_ident!("param_{}", x.ident))
+                .collect::<Vec<_>>();
+            let values = fields.iter().zip(&vars).map(|(field, var)| {
+                let ident = &field.ident;
+                let ty = &field.ty;
+                let name = &field.name;
+                let shape = match &field.shape {
+                    Some(shape) => {
+                        let dimensions = dimensions(shape);
+                        quote! { ::core::option::Option::Some(&[#(#dimensions),*][..]) }
+                    }
+                    None => quote! { ::core::option::Option::None },
+                };
+                quote! {
+                    #ident: <#ty as ::decthings_model::InputParam>::read(#name, #shape, #var.as_mut()).await?
+                }
+            });
+            quote! {
+                #assertions
+
+                impl #impl_generics ::decthings_model::ModelInputs for #name #ty_generics #where_clause {
+                    #parameter_definitions
+
+                    #[allow(unused_mut)]
+                    fn from_params<'decthings_model, D: ::decthings_model::DataLoader + 'decthings_model>(
+                        mut params: ::std::collections::HashMap<::std::string::String, D>,
+                    ) -> ::decthings_model::futures::future::BoxFuture<
+                        'decthings_model,
+                        ::core::result::Result<Self, ::decthings_model::ParamsError>,
+                    > {
+                        ::std::boxed::Box::pin(async move {
+                            #(let mut #vars = params.remove(#names);)*
+                            if let ::core::option::Option::Some(name) = params.into_keys().next() {
+                                return ::core::result::Result::Err(
+                                    ::decthings_model::ParamsError::Unexpected { name },
+                                );
+                            }
+                            ::core::result::Result::Ok(Self { #(#values),* })
+                        })
+                    }
+                }
+            }
+        }
+        Kind::Outputs => {
+            let outputs = fields.iter().map(|field| {
+                let ident = &field.ident;
+                let name = &field.name;
+                quote! {
+                    outputs.extend(::decthings_model::param_output(#name, self.#ident));
+                }
+            });
+            quote! {
+                #assertions
+
+                impl #impl_generics ::decthings_model::ModelOutputs for #name #ty_generics #where_clause {
+                    #parameter_definitions
+
+                    fn into_outputs(self) -> ::std::vec::Vec<::decthings_model::EvaluateOutput> {
+                        let mut outputs = ::std::vec::Vec::new();
+                        #(#outputs)*
+                        outputs
+                    }
+                }
+            }
+        }
+    };
+    Ok(tokens)
+}
@@ -0,0 +1,855 @@
+use std::{collections::HashMap, marker::PhantomData, sync::Arc};
+
+use decthings_api::tensor::{DecthingsElementType, OwnedDecthingsTensor};
+use futures::future::BoxFuture;
+use ndarray::{Array0, ArrayD};
+
+use crate::{
+    DataLoader, DataLoaderView, EvaluateOptions, EvaluateOutput, GetWeightsOptions,
+    InMemoryDataLoader, InitializeWeightsOptions, InputParam, InstantiateModelOptions,
+    Instantiated, Metric, Model, OtherModelWithWeights, ParameterDefinitions, SharedContext,
+    TrainOptions, TrainTracker, WeightsElement, WeightsLoader, WeightsProvider, param_definition,
+    weights_key,
+};
+
+/// A weights provider that provides to *inner*, with every key placed within *prefix*, such as
+/// "encoder.weight" for the key "weight" and the prefix "encoder". Used to store the weights of
+/// several models in the weights of one.
+pub struct PrefixedWeightsProvider<'a, WP> {
+    inner: &'a mut WP,
+    prefix: String,
+}
+
+impl<'a, WP: WeightsProvider> PrefixedWeightsProvider<'a, WP> {
+    pub fn new(inner: &'a mut WP, prefix: impl Into<String>) -> Self {
+        Self {
+            inner,
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl<WP: WeightsProvider> WeightsProvider for PrefixedWeightsProvider<'_, WP> {
+    fn provide_all<'a>(
+        &'a mut self,
+        data: &'a [(impl AsRef<str> + Send + Sync + 'a, bytes::Bytes)],
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let data: Vec<_> = data
+                .iter()
+                .map(|(key, data)| (weights_key(&self.prefix, key.as_ref()), data.clone()))
+                .collect();
+            self.inner.provide_all(&data).await;
+        })
+    }
+
+    fn provide_stream<'a>(
+        &'a mut self,
+        key: &'a str,
+        data: impl futures::Stream<Item = bytes::Bytes> + Send + 'a,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let key = weights_key(&self.prefix, key);
+            self.inner.provide_stream(&key, data).await;
+        })
+    }
+
+    fn inherit<'a>(&'a mut self, keys: &'a [&'a str]) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let keys: Vec<_> = keys
+                .iter()
+                .map(|key| weights_key(&self.prefix, key))
+                .collect();
+            let keys: Vec<_> = keys.iter().map(|key| key.as_str()).collect();
+            self.inner.inherit(&keys).await;
+        })
+    }
+
+    fn provide_delta<'a>(&'a mut self, key: &'a str, delta: bytes::Bytes) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let key = weights_key(&self.prefix, key);
+            self.inner.provide_delta(&key, delta).await;
+        })
+    }
+}
+
+/// Removes the values of *weights* whose keys are within *prefix*, and returns them with the
+/// prefix removed from their keys.
+fn take_prefixed<T>(weights: &mut HashMap<String, T>, prefix: &str) -> HashMap<String, T> {
+    let keys: Vec<String> = weights
+        .keys()
+        .filter(|key| strip_key_prefix(key, prefix).is_some())
+        .cloned()
+        .collect();
+    keys.into_iter()
+        .map(|key| {
+            let value = weights.remove(&key).unwrap();
+            (strip_key_prefix(&key, prefix).unwrap().to_owned(), value)
+        })
+        .collect()
+}
+
+/// Returns the keys within *prefix*, with the prefix removed.
+fn keys_within(keys: &[String], prefix: &str) -> Vec<String> {
+    keys.iter()
+        .filter_map(|key| strip_key_prefix(key, prefix))
+        .map(|key| key.to_owned())
+        .collect()
+}
+
+/// The inverse of weights_key.
+fn strip_key_prefix<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    match key.strip_prefix(prefix)? {
+        "" => Some(""),
+        rest => rest.strip_prefix('.'),
+    }
+}
+
+/// Views of all data points of each param, so that several models can read the same params.
+fn param_views<D: DataLoader>(
+    params: &HashMap<String, D>,
+) -> HashMap<String, DataLoaderView<'_, D>> {
+    params
+        .iter()
+        .map(|(name, data_loader)| (name.clone(), data_loader.slice(0..data_loader.size())))
+        .collect()
+}
+
+/// A weights loader that can be given to several models. The weights are read from the inner
+/// loader as they are stored when one of the models first reads them, and later reads by any of the
+/// models reuse that data. On unix, the stored data is a memory map when the host has the weights
+/// in a local file, so it is not copied either.
+struct SharedWeightsLoader<WL> {
+    byte_size: u64,
+    inner: Arc<futures::lock::Mutex<(WL, Option<bytes::Bytes>)>>,
+}
+
+impl<WL> Clone for SharedWeightsLoader<WL> {
+    fn clone(&self) -> Self {
+        Self {
+            byte_size: self.byte_size,
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<WL: WeightsLoader> SharedWeightsLoader<WL> {
+    fn new(inner: WL) -> Self {
+        Self {
+            byte_size: inner.byte_size(),
+            inner: Arc::new(futures::lock::Mutex::new((inner, None))),
+        }
+    }
+}
+
+impl<WL: WeightsLoader> WeightsLoader for SharedWeightsLoader<WL> {
+    fn byte_size(&self) -> u64 {
+        self.byte_size
+    }
+
+    fn read(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        Box::pin(async {
+            self.try_read()
+                .await
+                .unwrap_or_else(|e| panic!("WeightsLoader: {e}"))
+        })
+    }
+
+    fn try_read(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async { crate::decompress_weights(self.try_read_raw().await?) })
+    }
+
+    fn mmap(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        self.read()
+    }
+
+    fn try_mmap(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        self.try_read()
+    }
+
+    fn read_raw(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        Box::pin(async {
+            self.try_read_raw()
+                .await
+                .unwrap_or_else(|e| panic!("WeightsLoader: {e}"))
+        })
+    }
+
+    fn try_read_raw(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async {
+            let mut inner = self.inner.lock().await;
+            let (loader, data) = &mut *inner;
+            if let Some(data) = data {
+                return Ok(data.clone());
+            }
+            let read = loader.try_read_raw().await?;
+            *data = Some(read.clone());
+            Ok(read)
+        })
+    }
+}
+
+/// Wraps the weights of the other models in SharedWeightsLoader, so that they can be given to
+/// several models without being read up front.
+fn share_other_models<WL: WeightsLoader>(
+    other_models: HashMap<String, OtherModelWithWeights<WL>>,
+) -> HashMap<String, OtherModelWithWeights<SharedWeightsLoader<WL>>> {
+    other_models
+        .into_iter()
+        .map(|(id, other_model)| {
+            let weights = other_model
+                .weights
+                .into_iter()
+                .map(|(key, loader)| (key, SharedWeightsLoader::new(loader)))
+                .collect();
+            (
+                id,
+                OtherModelWithWeights {
+                    mount_path: other_model.mount_path,
+                    weights,
+                },
+            )
+        })
+        .collect()
+}
+
+/// A model that evaluates *A*, and then evaluates *B* with the outputs of *A* as params. The
+/// weights of *A* are stored within the prefix "0" and the weights of *B* within "1".
+///
+/// The other models are given to both stages. Their weights are read once, when a stage first
+/// reads them.
+///
+/// # Limitations
+///
+/// - In initialize_weights, only *A* receives the params. *B* is initialized with no params,
+///   since its inputs are the outputs of *A*, which are not known before *A* has been
+///   instantiated. parameter_definitions therefore declares the initialize_weights params of *A*
+///   only.
+/// - Training is not supported, since the stages cannot be trained through each other.
+///   parameter_definitions declares no train params, and train panics.
+pub struct Pipeline<A, B>(PhantomData<fn() -> (A, B)>);
+
+pub struct PipelineInstantiated<A, B> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: Model, B: Model> Model for Pipeline<A, B> {
+    type Instantiated = PipelineInstantiated<A::Instantiated, B::Instantiated>;
+
+    fn parameter_definitions() -> ParameterDefinitions {
+        let first = A::parameter_definitions();
+        ParameterDefinitions {
+            initialize_weights: first.initialize_weights,
+            train: None,
+            evaluate: first.evaluate,
+            evaluate_output: B::parameter_definitions().evaluate_output,
+        }
+    }
+
+    fn setup<'a>(context: &'a mut SharedContext) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            A::setup(context).await;
+            B::setup(context).await;
+        })
+    }
+
+    fn initialize_weights<'a>(
+        options: InitializeWeightsOptions<
+            impl DataLoader + 'a,
+            impl WeightsProvider + 'a,
+            impl WeightsLoader + 'a,
+        >,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut weights_provider = options.weights_provider;
+            let other_models = share_other_models(options.other_models);
+            A::initialize_weights(InitializeWeightsOptions {
+                params: param_views(&options.params),
+                weights_provider: PrefixedWeightsProvider::new(&mut weights_provider, "0"),
+                other_models: other_models.clone(),
+                context: options.context.clone(),
+            })
+            .await;
+            B::initialize_weights(InitializeWeightsOptions {
+                params: HashMap::<String, InMemoryDataLoader>::new(),
+                weights_provider: PrefixedWeightsProvider::new(&mut weights_provider, "1"),
+                other_models,
+                context: options.context,
+            })
+            .await;
+        })
+    }
+
+    fn instantiate_model<'a>(
+        options: InstantiateModelOptions<impl WeightsLoader + 'a>,
+    ) -> BoxFuture<'a, Self::Instantiated> {
+        Box::pin(async move {
+            let mut weights = options.weights;
+            let (first, second) = futures::join!(
+                A::instantiate_model(InstantiateModelOptions {
+                    weights: take_prefixed(&mut weights, "0"),
+                    other_models: options.other_models.clone(),
+                    context: options.context.clone(),
+                }),
+                B::instantiate_model(InstantiateModelOptions {
+                    weights: take_prefixed(&mut weights, "1"),
+                    other_models: options.other_models,
+                    context: options.context,
+                }),
+            );
+            PipelineInstantiated { first, second }
+        })
+    }
+}
+
+impl<A: Instantiated, B: Instantiated> Instantiated for PipelineInstantiated<A, B> {
+    fn evaluate<'a>(
+        &'a self,
+        options: EvaluateOptions<impl DataLoader + 'a>,
+    ) -> BoxFuture<'a, Vec<EvaluateOutput>> {
+        Box::pin(async move {
+            let outputs = Instantiated::evaluate(
+                &self.first,
+                EvaluateOptions {
+                    params: options.params,
+                    expected_output_types: HashMap::new(),
+                },
+            )
+            .await;
+            Instantiated::evaluate(
+                &self.second,
+                EvaluateOptions {
+                    params: outputs
+                        .into_iter()
+                        .map(|output| (output.name, InMemoryDataLoader::from_tensors(output.data)))
+                        .collect(),
+                    expected_output_types: options.expected_output_types,
+                },
+            )
+            .await
+        })
+    }
+
+    fn get_weights<'a>(
+        &'a self,
+        options: GetWeightsOptions<impl WeightsProvider + 'a>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut weights_provider = options.weights_provider;
+            Instantiated::get_weights(
+                &self.first,
+                GetWeightsOptions {
+                    weights_provider: PrefixedWeightsProvider::new(&mut weights_provider, "0"),
+                    instantiated_weights: keys_within(&options.instantiated_weights, "0"),
+                },
+            )
+            .await;
+            Instantiated::get_weights(
+                &self.second,
+                GetWeightsOptions {
+                    weights_provider: PrefixedWeightsProvider::new(&mut weights_provider, "1"),
+                    instantiated_weights: keys_within(&options.instantiated_weights, "1"),
+                },
+            )
+            .await;
+        })
+    }
+
+    fn dispose(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            Instantiated::dispose(&self.second).await;
+            Instantiated::dispose(&self.first).await;
+        })
+    }
+}
+
+/// The initialize_weights param of Ensemble that sets the number of members, as a single scalar
+/// u32 data point.
+pub const ENSEMBLE_MEMBERS_PARAM: &str = "ensembleMembers";
+
+/// The weights key under which Ensemble stores its number of members.
+const ENSEMBLE_MEMBERS_KEY: &str = "members";
+
+/// A model that consists of a number of instances of *M*, written `Ensemble<Vec<M>>`, which are
+/// all evaluated with the same params. The outputs are averaged, which requires them to be
+/// floating point tensors of the same shape. The weights of each member are stored within its
+/// index as prefix, such as "0" and "1", and the number of members is stored under "members".
+///
+/// The number of members is chosen in initialize_weights, with the param ENSEMBLE_MEMBERS_PARAM.
+/// The other params and the other models are given to every member. The weights of the other
+/// models are read once, when a member first reads them.
+///
+/// Training trains the members one after another, each with all of the train params. The progress
+/// of member *i* out of *n* is reported as (i + progress) / n, and its metrics are reported within
+/// its index as prefix, such as "0.loss". The remaining members are not trained once the training
+/// has been cancelled.
+pub struct Ensemble<T>(PhantomData<fn() -> T>);
+
+pub struct EnsembleInstantiated<I> {
+    pub members: Vec<I>,
+}
+
+impl<M: Model> Model for Ensemble<Vec<M>> {
+    type Instantiated = EnsembleInstantiated<M::Instantiated>;
+
+    /// The initialize_weights params of *M* with ENSEMBLE_MEMBERS_PARAM added, and the train and
+    /// evaluate params and the outputs of *M*. If *M* does not define its initialize_weights params, the
+    /// params are not validated and ENSEMBLE_MEMBERS_PARAM is not declared either.
+    fn parameter_definitions() -> ParameterDefinitions {
+        let member = M::parameter_definitions();
+        ParameterDefinitions {
+            initialize_weights: member.initialize_weights.map(|mut definitions| {
+                definitions.push(param_definition::<Vec<Array0<u32>>>(
+                    ENSEMBLE_MEMBERS_PARAM,
+                    None,
+                ));
+                definitions
+            }),
+            ..member
+        }
+    }
+
+    fn setup<'a>(context: &'a mut SharedContext) -> BoxFuture<'a, ()> {
+        M::setup(context)
+    }
+
+    fn initialize_weights<'a>(
+        options: InitializeWeightsOptions<
+            impl DataLoader + 'a,
+            impl WeightsProvider + 'a,
+            impl WeightsLoader + 'a,
+        >,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut params = options.params;
+            let mut members_param = params.remove(ENSEMBLE_MEMBERS_PARAM);
+            let members = <Vec<Array0<u32>> as InputParam>::read(
+                ENSEMBLE_MEMBERS_PARAM,
+                None,
+                members_param.as_mut(),
+            )
+            .await
+            .unwrap_or_else(|e| panic!("Ensemble: {e}"));
+            let members = match members.as_slice() {
+                [members] if members[()] > 0 => members[()],
+                _ => panic!(
+                    r#"Ensemble: The param "{ENSEMBLE_MEMBERS_PARAM}" must be a single data point with a positive number of members."#
+                ),
+            };
+
+            let mut weights_provider = options.weights_provider;
+            weights_provider
+                .provide(
+                    ENSEMBLE_MEMBERS_KEY,
+                    bytes::Bytes::copy_from_slice(&members.to_le_bytes()),
+                )
+                .await;
+            let other_models = share_other_models(options.other_models);
+            for i in 0..members {
+                M::initialize_weights(InitializeWeightsOptions {
+                    params: param_views(&params),
+                    weights_provider: PrefixedWeightsProvider::new(
+                        &mut weights_provider,
+                        i.to_string(),
+                    ),
+                    other_models: other_models.clone(),
+                    context: options.context.clone(),
+                })
+                .await;
+            }
+        })
+    }
+
+    fn instantiate_model<'a>(
+        options: InstantiateModelOptions<impl WeightsLoader + 'a>,
+    ) -> BoxFuture<'a, Self::Instantiated> {
+        Box::pin(async move {
+            let mut weights = options.weights;
+            let members = match weights.remove(ENSEMBLE_MEMBERS_KEY) {
+                Some(mut loader) => loader.try_read().await.ok(),
+                None => None,
+            }
+            .and_then(|data| <[u8; 4]>::try_from(data.as_ref()).ok())
+            .map(u32::from_le_bytes)
+            .unwrap_or_else(|| {
+                panic!(
+                    r#"Ensemble: The weights do not contain the number of members under "{ENSEMBLE_MEMBERS_KEY}"."#
+                )
+            });
+            let members = futures::future::join_all((0..members).map(|i| {
+                M::instantiate_model(InstantiateModelOptions {
+                    weights: take_prefixed(&mut weights, &i.to_string()),
+                    other_models: options.other_models.clone(),
+                    context: options.context.clone(),
+                })
+            }))
+            .await;
+            EnsembleInstantiated { members }
+        })
+    }
+}
+
+impl<I: Instantiated> Instantiated for EnsembleInstantiated<I> {
+    fn evaluate<'a>(
+        &'a self,
+        options: EvaluateOptions<impl DataLoader + 'a>,
+    ) -> BoxFuture<'a, Vec<EvaluateOutput>> {
+        Box::pin(async move {
+            let results = futures::future::join_all(self.members.iter().map(|member| {
+                Instantiated::evaluate(
+                    member,
+                    EvaluateOptions {
+                        params: param_views(&options.params),
+                        expected_output_types: options.expected_output_types.clone(),
+                    },
+                )
+            }))
+            .await;
+            average_outputs(results)
+        })
+    }
+
+    fn train<'a>(
+        &'a self,
+        options: TrainOptions<impl DataLoader + 'a, impl TrainTracker + 'a>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let members = self.members.len();
+            for (index, member) in self.members.iter().enumerate() {
+                if futures::FutureExt::now_or_never(options.tracker.wait_for_cancelled()).is_some()
+                {
+                    return;
+                }
+                Instantiated::train(
+                    member,
+                    TrainOptions {
+                        params: param_views(&options.params),
+                        tracker: MemberTracker {
+                            inner: &options.tracker,
+                            index,
+                            members,
+                        },
+                    },
+                )
+                .await;
+            }
+        })
+    }
+
+    fn get_weights<'a>(
+        &'a self,
+        options: GetWeightsOptions<impl WeightsProvider + 'a>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut weights_provider = options.weights_provider;
+            weights_provider
+                .provide(
+                    ENSEMBLE_MEMBERS_KEY,
+                    bytes::Bytes::copy_from_slice(&(self.members.len() as u32).to_le_bytes()),
+                )
+                .await;
+            for (i, member) in self.members.iter().enumerate() {
+                let prefix = i.to_string();
+                Instantiated::get_weights(
+                    member,
+                    GetWeightsOptions {
+                        instantiated_weights: keys_within(&options.instantiated_weights, &prefix),
+                        weights_provider: PrefixedWeightsProvider::new(
+                            &mut weights_provider,
+                            prefix,
+                        ),
+                    },
+                )
+                .await;
+            }
+        })
+    }
+
+    fn dispose(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            futures::future::join_all(self.members.iter().map(Instantiated::dispose)).await;
+        })
+    }
+}
+
+/// The tracker given to member *index* of an ensemble while it trains, which reports to the
+/// tracker of the ensemble.
+struct MemberTracker<'t, T> {
+    inner: &'t T,
+    index: usize,
+    members: usize,
+}
+
+impl<T: TrainTracker> TrainTracker for MemberTracker<'_, T> {
+    fn wait_for_cancelled(&self) -> BoxFuture<'_, ()> {
+        self.inner.wait_for_cancelled()
+    }
+
+    fn progress(&self, progress: f32) -> BoxFuture<'_, ()> {
+        let progress = (self.index as f32 + progress) / self.members as f32;
+        self.inner.progress(progress)
+    }
+
+    fn metrics<'a, 'b>(
+        &'a self,
+        metrics: &'a [Metric<'b, impl AsRef<str> + Sync + 'a>],
+    ) -> BoxFuture<'a, ()>
+    where
+        'b: 'a,
+    {
+        Box::pin(async move {
+            let prefix = self.index.to_string();
+            let metrics: Vec<_> = metrics
+                .iter()
+                .map(|metric| Metric {
+                    name: weights_key(&prefix, metric.name.as_ref()),
+                    data: metric.data.clone(),
+                })
+                .collect();
+            self.inner.metrics(&metrics).await;
+        })
+    }
+}
+
+/// Averages the outputs of the members of an ensemble, matching outputs by name and data points by
+/// index.
+fn average_outputs(mut results: Vec<Vec<EvaluateOutput>>) -> Vec<EvaluateOutput> {
+    let Some(first) = results.pop() else {
+        return vec![];
+    };
+    first
+        .into_iter()
+        .map(|output| {
+            let name = output.name;
+            let mut data: Vec<Vec<OwnedDecthingsTensor>> =
+                output.data.into_iter().map(|tensor| vec![tensor]).collect();
+            for result in &mut results {
+                let other = result
+                    .iter_mut()
+                    .find(|other| other.name == name)
+                    .unwrap_or_else(|| {
+                        panic!(r#"Ensemble: Not all members returned the output "{name}"."#)
+                    });
+                if other.data.len() != data.len() {
+                    panic!(
+                        r#"Ensemble: The members returned different numbers of data points for output "{name}"."#
+                    );
+                }
+                for (tensors, tensor) in data.iter_mut().zip(other.data.drain(..)) {
+                    tensors.push(tensor);
+                }
+            }
+            EvaluateOutput {
+                data: data
+                    .into_iter()
+                    .map(|tensors| match tensors[0].tensor().typ() {
+                        DecthingsElementType::F32 => average_tensors::<f32>(&name, &tensors),
+                        DecthingsElementType::F64 => average_tensors::<f64>(&name, &tensors),
+                        typ => panic!(
+                            r#"Ensemble: Cannot average output "{name}" of type {typ}. Only floating point outputs can be averaged."#
+                        ),
+                    })
+                    .collect(),
+                name,
+            }
+        })
+        .collect()
+}
+
+trait AverageElement: WeightsElement + ndarray::NdFloat {
+    fn from_count(count: usize) -> Self;
+}
+
+impl AverageElement for f32 {
+    fn from_count(count: usize) -> Self {
+        count as f32
+    }
+}
+
+impl AverageElement for f64 {
+    fn from_count(count: usize) -> Self {
+        count as f64
+    }
+}
+
+fn average_tensors<T: AverageElement>(
+    name: &str,
+    tensors: &[OwnedDecthingsTensor],
+) -> OwnedDecthingsTensor {
+    let mut sum: Option<ArrayD<T>> = None;
+    for tensor in tensors {
+        let tensor = tensor.tensor();
+        let found = tensor.typ();
+        let array = T::from_tensor(tensor).unwrap_or_else(|| {
+            panic!(
+                r#"Ensemble: The members returned different types for output "{name}": {} and {found}."#,
+                T::TYPE
+            )
+        });
+        match &mut sum {
+            None => sum = Some(array.into_owned()),
+            Some(sum) => {
+                if sum.shape() != array.shape() {
+                    panic!(
+                        r#"Ensemble: The members returned different shapes for output "{name}": {:?} and {:?}."#,
+                        sum.shape(),
+                        array.shape()
+                    );
+                }
+                *sum += &array;
+            }
+        }
+    }
+    let count = T::from_count(tensors.len());
+    let average = sum.unwrap().mapv(|x| x / count);
+    T::to_tensor(average.into()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use decthings_api::tensor::DecthingsTensor;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::InMemoryWeightsLoader;
+
+    /// Counts the reads of the inner loader.
+    struct CountingLoader(InMemoryWeightsLoader, Arc<AtomicUsize>);
+
+    impl WeightsLoader for CountingLoader {
+        fn byte_size(&self) -> u64 {
+            self.0.byte_size()
+        }
+
+        fn read(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.read()
+        }
+    }
+
+    #[test]
+    fn shared_weights_loader_reads_once() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let data = bytes::Bytes::from_static(b"weights");
+        let loader = CountingLoader(InMemoryWeightsLoader::new(data.clone()), reads.clone());
+        let other_models = share_other_models(HashMap::from([(
+            "other".to_owned(),
+            OtherModelWithWeights {
+                mount_path: "/other".to_owned(),
+                weights: HashMap::from([("key".to_owned(), loader)]),
+            },
+        )]));
+        assert_eq!(reads.load(Ordering::Relaxed), 0);
+
+        let mut first = other_models["other"].weights["key"].clone();
+        let mut second = first.clone();
+        assert_eq!(first.byte_size(), data.len() as u64);
+        assert_eq!(block_on(first.read()), data);
+        assert_eq!(block_on(second.read()), data);
+        assert_eq!(block_on(second.read_raw()), data);
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+    }
+
+    #[derive(Clone, Default)]
+    struct Tracker {
+        cancelled: Arc<AtomicBool>,
+        progress: Arc<Mutex<Vec<f32>>>,
+        metrics: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TrainTracker for Tracker {
+        fn wait_for_cancelled(&self) -> BoxFuture<'_, ()> {
+            Box::pin(async {
+                if !self.cancelled.load(Ordering::Relaxed) {
+                    futures::future::pending::<()>().await;
+                }
+            })
+        }
+
+        fn progress(&self, progress: f32) -> BoxFuture<'_, ()> {
+            self.progress.lock().unwrap().push(progress);
+            Box::pin(async {})
+        }
+
+        fn metrics<'a, 'b>(
+            &'a self,
+            metrics: &'a [Metric<'b, impl AsRef<str> + Sync + 'a>],
+        ) -> BoxFuture<'a, ()>
+        where
+            'b: 'a,
+        {
+            let mut names = self.metrics.lock().unwrap();
+            names.extend(metrics.iter().map(|x| x.name.as_ref().to_owned()));
+            Box::pin(async {})
+        }
+    }
+
+    /// Records the number of data points it is trained with.
+    #[derive(Default)]
+    struct Member {
+        trained_with: Mutex<Option<u32>>,
+    }
+
+    impl Instantiated for Member {
+        fn train<'a>(
+            &'a self,
+            options: TrainOptions<impl DataLoader + 'a, impl TrainTracker + 'a>,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                let input = &options.params["input"];
+                *self.trained_with.lock().unwrap() = Some(input.remaining());
+                options.tracker.progress(0.5).await;
+                let loss = DecthingsTensor::F32(ndarray::arr0(1.0f32).into_dyn().into());
+                options
+                    .tracker
+                    .metrics(&[Metric {
+                        name: "loss",
+                        data: loss,
+                    }])
+                    .await;
+            })
+        }
+    }
+
+    fn train_ensemble(tracker: &Tracker) -> EnsembleInstantiated<Member> {
+        let ensemble = EnsembleInstantiated {
+            members: vec![Member::default(), Member::default()],
+        };
+        let input = InMemoryDataLoader::new(vec![bytes::Bytes::new(); 3]);
+        block_on(Instantiated::train(
+            &ensemble,
+            TrainOptions {
+                params: HashMap::from([("input".to_owned(), input)]),
+                tracker: tracker.clone(),
+            },
+        ));
+        ensemble
+    }
+
+    #[test]
+    fn ensemble_trains_each_member() {
+        let tracker = Tracker::default();
+        let ensemble = train_ensemble(&tracker);
+        for member in &ensemble.members {
+            assert_eq!(*member.trained_with.lock().unwrap(), Some(3));
+        }
+        assert_eq!(*tracker.progress.lock().unwrap(), [0.25, 0.75]);
+        assert_eq!(*tracker.metrics.lock().unwrap(), ["0.loss", "1.loss"]);
+    }
+
+    #[test]
+    fn ensemble_stops_training_when_cancelled() {
+        let tracker = Tracker::default();
+        tracker.cancelled.store(true, Ordering::Relaxed);
+        let ensemble = train_ensemble(&tracker);
+        for member in &ensemble.members {
+            assert_eq!(*member.trained_with.lock().unwrap(), None);
+        }
+    }
+}
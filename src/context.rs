@@ -0,0 +1,42 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::Arc,
+};
+
+/// Process-wide state that is created once by Model::setup and given to every call to
+/// initialize_weights and instantiate_model, such as thread pools or tokenizer vocabularies. Values
+/// are stored by type, so that a model and the models that it is composed of can each store their
+/// own. Cloning is cheap, and the clones share the values.
+#[derive(Clone, Default)]
+pub struct SharedContext {
+    values: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl SharedContext {
+    /// Stores *value*, replacing any value of the same type. Clones that were made before are not
+    /// affected.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        Arc::make_mut(&mut self.values).insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// Returns the value of type T, if one has been stored.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .map(|value| Arc::clone(value).downcast().unwrap())
+    }
+
+    /// Returns true if a value of type T has been stored.
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl std::fmt::Debug for SharedContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedContext")
+            .field("values", &self.values.len())
+            .finish()
+    }
+}
@@ -4,12 +4,34 @@ pub mod wasm_bindings;
 #[cfg(target_family = "unix")]
 mod unix;
 
+mod combinators;
+mod context;
+mod memory;
+mod params;
+mod sampler;
+mod shuffle;
 mod trait_def;
+mod transform;
+mod view;
+mod weights;
 
 #[cfg(target_family = "unix")]
 pub use unix::*;
 
+pub use combinators::*;
+pub use context::*;
+pub use memory::*;
+pub use params::*;
+pub use sampler::*;
+pub use shuffle::*;
 pub use trait_def::*;
+pub use transform::*;
+pub use view::*;
+pub use weights::*;
+
+#[cfg(feature = "derive")]
+pub use decthings_model_derive::{instantiated, model};
 
 pub use bytes;
 pub use decthings_api;
+pub use futures;
@@ -0,0 +1,164 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use decthings_api::tensor::OwnedDecthingsTensor;
+use futures::future::BoxFuture;
+
+use crate::{
+    DataLoaderBinary, DataLoaderError, InstantiateModelOptions, ModelBinary, OtherModel,
+    SharedContext, ShuffleState, WeightsLoader, shuffle_permutation,
+};
+
+/// A data loader that reads from data that is already in memory. Shuffling uses the same
+/// permutation as the host, so a seed gives the same order here as for a host data loader.
+pub struct InMemoryDataLoader {
+    data: Vec<bytes::Bytes>,
+    total_byte_size: u64,
+    position: u32,
+    shuffled: Mutex<Option<(u64, Vec<u32>)>>,
+}
+
+impl InMemoryDataLoader {
+    pub fn new(data: Vec<bytes::Bytes>) -> Self {
+        if u32::try_from(data.len()).is_err() {
+            panic!(
+                "InMemoryDataLoader: Cannot contain more than {} data points.",
+                u32::MAX
+            );
+        }
+        Self {
+            total_byte_size: data.iter().map(|x| x.len() as u64).sum(),
+            data,
+            position: 0,
+            shuffled: Mutex::new(None),
+        }
+    }
+
+    pub fn from_tensors(tensors: impl IntoIterator<Item = OwnedDecthingsTensor>) -> Self {
+        Self::new(tensors.into_iter().map(|x| x.serialize()).collect())
+    }
+}
+
+impl DataLoaderBinary for InMemoryDataLoader {
+    fn total_byte_size(&self) -> u64 {
+        self.total_byte_size
+    }
+
+    fn try_shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+        for data_loader in [self].iter().chain(others) {
+            let permutation = shuffle_permutation(data_loader.data.len() as u32, seed);
+            *data_loader.shuffled.lock().unwrap() = Some((seed, permutation));
+        }
+        Box::pin(async { Ok(()) })
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        ShuffleState {
+            seed: self.shuffled.lock().unwrap().as_ref().map(|x| x.0),
+            position: self.position,
+        }
+    }
+
+    fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn position(&self) -> u32 {
+        self.position
+    }
+
+    fn set_position(&mut self, position: u32) {
+        if position > self.data.len() as u32 {
+            panic!(
+                "DataLoader: Cannot set the the position to a value greater than the data size. The data size was {}, and position {} was attempted to be set.",
+                self.data.len(),
+                position
+            );
+        }
+        self.position = position;
+    }
+
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        let size = self.data.len() as u32;
+        let res = if start_index > size {
+            Err(DataLoaderError::IndexOutOfRange {
+                index: start_index,
+                size,
+            })
+        } else {
+            let range = start_index..start_index + amount.min(size - start_index);
+            let shuffled = self.shuffled.lock().unwrap();
+            Ok(match &*shuffled {
+                Some((_, permutation)) => permutation[range.start as usize..range.end as usize]
+                    .iter()
+                    .map(|&i| self.data[i as usize].clone())
+                    .collect(),
+                None => self.data[range.start as usize..range.end as usize].to_vec(),
+            })
+        };
+        Box::pin(async { res })
+    }
+}
+
+/// A weights loader that reads from data that is already in memory.
+#[derive(Clone, Debug)]
+pub struct InMemoryWeightsLoader {
+    data: bytes::Bytes,
+}
+
+impl InMemoryWeightsLoader {
+    pub fn new(data: bytes::Bytes) -> Self {
+        Self { data }
+    }
+}
+
+impl WeightsLoader for InMemoryWeightsLoader {
+    fn byte_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    fn read(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        Box::pin(async {
+            crate::decompress_weights(self.data.clone())
+                .unwrap_or_else(|e| panic!("WeightsLoader: {e}"))
+        })
+    }
+
+    fn try_read(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async { crate::decompress_weights(self.data.clone()) })
+    }
+
+    fn read_raw(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        Box::pin(async { self.data.clone() })
+    }
+}
+
+/// Instantiates the model *M* in this process, with *weights* that are already in memory. This
+/// allows a model whose crate is a dependency to be used as a component, such as a feature
+/// extractor, by calling the methods of the returned instance directly. The weights can for
+/// example be copied into the weights of this model in initialize_weights, using
+/// OtherModelWithWeights::copy_to, and read with crate::read_weights in instantiate_model.
+///
+/// *context* is given to M::instantiate_model. M::setup is not called, so call it with the context
+/// first if M needs it, such as from the Model::setup of this model.
+pub fn instantiate_in_memory<'a, M: ModelBinary>(
+    weights: HashMap<String, bytes::Bytes>,
+    other_models: HashMap<String, OtherModel>,
+    context: SharedContext,
+) -> BoxFuture<'a, M::Instantiated> {
+    M::instantiate_model(InstantiateModelOptions {
+        weights: weights
+            .into_iter()
+            .map(|(key, data)| (key, InMemoryWeightsLoader::new(data)))
+            .collect(),
+        other_models,
+        context,
+    })
+}
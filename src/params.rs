@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+
+use decthings_api::tensor::{
+    DecthingsElementType, DecthingsParameterDefinition, DecthingsTensorRules, OwnedDecthingsTensor,
+};
+use futures::future::BoxFuture;
+use ndarray::{Array, Dimension};
+
+use crate::{DataLoader, DataLoaderError, EvaluateOutput, WeightsElement};
+
+#[cfg(feature = "derive")]
+pub use decthings_model_derive::{ModelInputs, ModelOutputs};
+
+/// The named input parameters of a model, read from the params of EvaluateOptions or TrainOptions.
+///
+/// Implemented for structs using `#[derive(ModelInputs)]`, where each field is a parameter named
+/// after the field. Fields can be annotated with `#[param(rename = "name")]` to use another name,
+/// and with `#[param(shape = [-1, 28, 28])]` to set the shape of each data point, where -1 is a
+/// dimension of any size. The shape is required for fields that do not have a fixed number of
+/// dimensions, such as `Vec<ArrayD<f32>>`, and its length must match the number of dimensions of
+/// the field otherwise. Both are checked at compile time.
+pub trait ModelInputs: Sized + Send {
+    /// Returns the definitions of the parameters, in the order of the fields.
+    fn parameter_definitions() -> Vec<DecthingsParameterDefinition>;
+
+    /// Reads all remaining data of each parameter. Fails if a required parameter is missing, if
+    /// *params* contains a parameter that is not defined, or if the data does not fit the field.
+    fn from_params<'a, D: DataLoader + 'a>(
+        params: HashMap<String, D>,
+    ) -> BoxFuture<'a, Result<Self, ParamsError>>;
+}
+
+/// The named output parameters of a model, returned from Instantiated::evaluate.
+///
+/// Implemented for structs using `#[derive(ModelOutputs)]`, which accepts the same attributes as
+/// `#[derive(ModelInputs)]`.
+pub trait ModelOutputs {
+    /// Returns the definitions of the parameters, in the order of the fields.
+    fn parameter_definitions() -> Vec<DecthingsParameterDefinition>;
+
+    /// Converts self to one EvaluateOutput per parameter. Optional parameters that are None are
+    /// left out.
+    fn into_outputs(self) -> Vec<EvaluateOutput>;
+}
+
+/// The type of a field of ModelInputs or ModelOutputs. Each element of the Vec is one data point.
+pub trait ParamType {
+    /// Whether the parameter has to be provided.
+    const REQUIRED: bool = true;
+
+    /// The element types that the data points may have.
+    fn allowed_types() -> Vec<DecthingsElementType>;
+
+    /// The number of dimensions of each data point, or None if it is not fixed.
+    const NDIM: Option<usize>;
+}
+
+/// A ParamType that can be read from a DataLoader.
+pub trait InputParam: ParamType + Sized + Send {
+    /// Reads all remaining data of *data_loader*, which is None if the parameter *name* was not
+    /// provided. If *shape* is given, each data point must have that shape, where None is a
+    /// dimension of any size.
+    fn read<'a, D: DataLoader>(
+        name: &'a str,
+        shape: Option<&'a [Option<u32>]>,
+        data_loader: Option<&'a mut D>,
+    ) -> BoxFuture<'a, Result<Self, ParamsError>>;
+}
+
+/// A ParamType that can be converted to tensors.
+pub trait OutputParam: ParamType {
+    /// Returns the data points, or None if the parameter should be left out of the outputs.
+    fn into_tensors(self) -> Option<Vec<OwnedDecthingsTensor>>;
+}
+
+#[derive(Debug)]
+pub enum ParamsError {
+    /// The required parameter *name* was not provided.
+    Missing { name: String },
+    /// The parameter *name* was provided, but is not defined.
+    Unexpected { name: String },
+    /// Data point *index* of the parameter *name* has element type *found*, but *expected* was
+    /// required.
+    WrongType {
+        name: String,
+        index: usize,
+        expected: DecthingsElementType,
+        found: DecthingsElementType,
+    },
+    /// Data point *index* of the parameter *name* has a shape that does not fit the field.
+    WrongShape {
+        name: String,
+        index: usize,
+        shape: Vec<usize>,
+    },
+    /// The data of the parameter *name* could not be read.
+    DataLoader {
+        name: String,
+        error: DataLoaderError,
+    },
+}
+
+impl std::fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing { name } => {
+                write!(f, "The required parameter \"{name}\" was not provided.")
+            }
+            Self::Unexpected { name } => write!(
+                f,
+                "The parameter \"{name}\" was provided, but the model does not define it."
+            ),
+            Self::WrongType {
+                name,
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Data point {index} of the parameter \"{name}\" has element type {found}, but {expected} was expected."
+            ),
+            Self::WrongShape { name, index, shape } => write!(
+                f,
+                "Data point {index} of the parameter \"{name}\" has shape {shape:?}, which does not fit the parameter."
+            ),
+            Self::DataLoader { name, error } => {
+                write!(f, "Failed to read the parameter \"{name}\": {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParamsError {}
+
+/// Checks that each of *names* is defined in *definitions*, and that all required parameters are
+/// among *names*.
+pub fn validate_param_names<'a>(
+    definitions: &[DecthingsParameterDefinition],
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<(), ParamsError> {
+    let mut missing: Vec<&str> = definitions
+        .iter()
+        .filter(|x| x.required)
+        .map(|x| x.name.as_str())
+        .collect();
+    for name in names {
+        if !definitions.iter().any(|x| x.name == name) {
+            return Err(ParamsError::Unexpected {
+                name: name.to_owned(),
+            });
+        }
+        missing.retain(|&x| x != name);
+    }
+    match missing.first() {
+        Some(name) => Err(ParamsError::Missing {
+            name: (*name).to_owned(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Returns the definition of a parameter of type P. Without *shape*, each of the P::NDIM
+/// dimensions may have any size. The derive macros check at compile time that *shape* is given if
+/// P::NDIM is None.
+#[doc(hidden)]
+pub fn param_definition<P: ParamType>(
+    name: &str,
+    shape: Option<Vec<Option<u32>>>,
+) -> DecthingsParameterDefinition {
+    let shape = shape
+        .or_else(|| P::NDIM.map(|ndim| vec![None; ndim]))
+        .unwrap_or_else(|| {
+            panic!(
+                "ParamType: The parameter \"{name}\" does not have a fixed number of dimensions, so its shape must be set with #[param(shape = [...])]."
+            )
+        });
+    DecthingsParameterDefinition {
+        name: name.to_owned(),
+        required: P::REQUIRED,
+        rules: DecthingsTensorRules {
+            shape,
+            allowed_types: P::allowed_types(),
+            annotations: vec![],
+        },
+    }
+}
+
+/// Checks that data point *index* of the parameter *name* has the *expected* shape, if given.
+fn check_shape(
+    name: &str,
+    index: usize,
+    expected: Option<&[Option<u32>]>,
+    shape: &[usize],
+) -> Result<(), ParamsError> {
+    let fits = expected.is_none_or(|expected| {
+        expected.len() == shape.len()
+            && expected
+                .iter()
+                .zip(shape)
+                .all(|(expected, &size)| expected.is_none_or(|expected| expected as usize == size))
+    });
+    if fits {
+        Ok(())
+    } else {
+        Err(ParamsError::WrongShape {
+            name: name.to_owned(),
+            index,
+            shape: shape.to_vec(),
+        })
+    }
+}
+
+async fn read_all<D: DataLoader>(
+    name: &str,
+    data_loader: &mut D,
+) -> Result<Vec<OwnedDecthingsTensor>, ParamsError> {
+    let remaining = data_loader.remaining();
+    data_loader
+        .try_next(remaining)
+        .await
+        .map_err(|error| ParamsError::DataLoader {
+            name: name.to_owned(),
+            error,
+        })
+}
+
+impl<T: WeightsElement, Dim: Dimension> ParamType for Vec<Array<T, Dim>> {
+    fn allowed_types() -> Vec<DecthingsElementType> {
+        vec![T::TYPE]
+    }
+
+    const NDIM: Option<usize> = Dim::NDIM;
+}
+
+impl<T: WeightsElement, Dim: Dimension> InputParam for Vec<Array<T, Dim>> {
+    fn read<'a, D: DataLoader>(
+        name: &'a str,
+        shape: Option<&'a [Option<u32>]>,
+        data_loader: Option<&'a mut D>,
+    ) -> BoxFuture<'a, Result<Self, ParamsError>> {
+        Box::pin(async move {
+            let Some(data_loader) = data_loader else {
+                return Err(ParamsError::Missing {
+                    name: name.to_owned(),
+                });
+            };
+            read_all(name, data_loader)
+                .await?
+                .iter()
+                .enumerate()
+                .map(|(index, tensor)| {
+                    let tensor = tensor.tensor();
+                    let found = tensor.typ();
+                    let array = T::from_tensor(tensor).ok_or_else(|| ParamsError::WrongType {
+                        name: name.to_owned(),
+                        index,
+                        expected: T::TYPE,
+                        found,
+                    })?;
+                    check_shape(name, index, shape, array.shape())?;
+                    let found_shape = array.shape().to_vec();
+                    array
+                        .into_owned()
+                        .into_dimensionality()
+                        .map_err(|_| ParamsError::WrongShape {
+                            name: name.to_owned(),
+                            index,
+                            shape: found_shape,
+                        })
+                })
+                .collect()
+        })
+    }
+}
+
+impl<T: WeightsElement, Dim: Dimension> OutputParam for Vec<Array<T, Dim>> {
+    fn into_tensors(self) -> Option<Vec<OwnedDecthingsTensor>> {
+        Some(
+            self.into_iter()
+                .map(|array| T::to_tensor(array.into_dyn().into()).into())
+                .collect(),
+        )
+    }
+}
+
+impl ParamType for Vec<OwnedDecthingsTensor> {
+    fn allowed_types() -> Vec<DecthingsElementType> {
+        use DecthingsElementType::*;
+        vec![
+            F32, F64, I8, I16, I32, I64, U8, U16, U32, U64, String, Boolean, Binary, Image, Audio,
+            Video,
+        ]
+    }
+
+    const NDIM: Option<usize> = None;
+}
+
+impl InputParam for Vec<OwnedDecthingsTensor> {
+    fn read<'a, D: DataLoader>(
+        name: &'a str,
+        shape: Option<&'a [Option<u32>]>,
+        data_loader: Option<&'a mut D>,
+    ) -> BoxFuture<'a, Result<Self, ParamsError>> {
+        Box::pin(async move {
+            let Some(data_loader) = data_loader else {
+                return Err(ParamsError::Missing {
+                    name: name.to_owned(),
+                });
+            };
+            let data = read_all(name, data_loader).await?;
+            for (index, tensor) in data.iter().enumerate() {
+                check_shape(name, index, shape, tensor.tensor().shape())?;
+            }
+            Ok(data)
+        })
+    }
+}
+
+impl OutputParam for Vec<OwnedDecthingsTensor> {
+    fn into_tensors(self) -> Option<Vec<OwnedDecthingsTensor>> {
+        Some(self)
+    }
+}
+
+impl<P: ParamType> ParamType for Option<P> {
+    const REQUIRED: bool = false;
+
+    fn allowed_types() -> Vec<DecthingsElementType> {
+        P::allowed_types()
+    }
+
+    const NDIM: Option<usize> = P::NDIM;
+}
+
+impl<P: InputParam> InputParam for Option<P> {
+    fn read<'a, D: DataLoader>(
+        name: &'a str,
+        shape: Option<&'a [Option<u32>]>,
+        data_loader: Option<&'a mut D>,
+    ) -> BoxFuture<'a, Result<Self, ParamsError>> {
+        Box::pin(async move {
+            match data_loader {
+                Some(data_loader) => P::read(name, shape, Some(data_loader)).await.map(Some),
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+impl<P: OutputParam> OutputParam for Option<P> {
+    fn into_tensors(self) -> Option<Vec<OwnedDecthingsTensor>> {
+        self.and_then(P::into_tensors)
+    }
+}
+
+/// Returns the output *name*, or None if *param* should be left out.
+#[doc(hidden)]
+pub fn param_output<P: OutputParam>(name: &str, param: P) -> Option<EvaluateOutput> {
+    param.into_tensors().map(|data| EvaluateOutput {
+        name: name.to_owned(),
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_shape_fixed_dimensions() {
+        let expected = [None, Some(3)];
+        assert!(check_shape("x", 0, Some(&expected), &[5, 3]).is_ok());
+        assert!(check_shape("x", 0, Some(&expected), &[0, 3]).is_ok());
+        assert!(matches!(
+            check_shape("x", 2, Some(&expected), &[5, 4]),
+            Err(ParamsError::WrongShape { index: 2, .. })
+        ));
+        assert!(check_shape("x", 0, Some(&expected), &[3]).is_err());
+        assert!(check_shape("x", 0, Some(&expected), &[1, 3, 1]).is_err());
+        assert!(check_shape("x", 0, None, &[1, 2, 3]).is_ok());
+    }
+}
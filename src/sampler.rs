@@ -0,0 +1,321 @@
+use crate::shuffle::{Rng, shuffle_permutation};
+
+/// Decides which data points are read, and how they are grouped into batches. Pass a sampler to
+/// DataLoader::read_batch to read the batches.
+pub trait Sampler: Send {
+    /// Returns the indices of the next batch, or None when all batches of the current epoch have
+    /// been returned.
+    fn next_batch(&mut self) -> Option<Vec<u32>>;
+
+    /// Starts a new epoch.
+    fn reset(&mut self);
+}
+
+/// Returns the data points in order.
+#[derive(Clone, Debug)]
+pub struct SequentialSampler {
+    size: u32,
+    batch_size: u32,
+    position: u32,
+}
+
+impl SequentialSampler {
+    pub fn new(size: u32, batch_size: u32) -> Self {
+        assert!(batch_size > 0, "Sampler: The batch size must be positive.");
+        Self {
+            size,
+            batch_size,
+            position: 0,
+        }
+    }
+}
+
+impl Sampler for SequentialSampler {
+    fn next_batch(&mut self) -> Option<Vec<u32>> {
+        if self.position >= self.size {
+            return None;
+        }
+        let end = self.position.saturating_add(self.batch_size).min(self.size);
+        let res = (self.position..end).collect();
+        self.position = end;
+        Some(res)
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+    }
+}
+
+/// Returns every data point once per epoch, in a new random order each epoch. The orders are
+/// determined by the seed.
+#[derive(Clone, Debug)]
+pub struct RandomSampler {
+    size: u32,
+    batch_size: u32,
+    rng: Rng,
+    order: Vec<u32>,
+    position: usize,
+}
+
+impl RandomSampler {
+    pub fn new(size: u32, batch_size: u32, seed: u64) -> Self {
+        assert!(batch_size > 0, "Sampler: The batch size must be positive.");
+        let mut rng = Rng::new(seed);
+        Self {
+            size,
+            batch_size,
+            order: shuffle_permutation(size, rng.next_u64()),
+            rng,
+            position: 0,
+        }
+    }
+}
+
+impl Sampler for RandomSampler {
+    fn next_batch(&mut self) -> Option<Vec<u32>> {
+        if self.position >= self.order.len() {
+            return None;
+        }
+        let end = (self.position + self.batch_size as usize).min(self.order.len());
+        let res = self.order[self.position..end].to_vec();
+        self.position = end;
+        Some(res)
+    }
+
+    fn reset(&mut self) {
+        self.order = shuffle_permutation(self.size, self.rng.next_u64());
+        self.position = 0;
+    }
+}
+
+/// Draws *num_samples* data points per epoch with replacement, where each data point is drawn
+/// with a probability proportional to its weight.
+#[derive(Clone, Debug)]
+pub struct WeightedRandomSampler {
+    cumulative_weights: Vec<f64>,
+    num_samples: u32,
+    batch_size: u32,
+    rng: Rng,
+    drawn: u32,
+}
+
+impl WeightedRandomSampler {
+    pub fn new(weights: &[f64], num_samples: u32, batch_size: u32, seed: u64) -> Self {
+        assert!(batch_size > 0, "Sampler: The batch size must be positive.");
+        if weights.iter().any(|&x| !x.is_finite() || x < 0.0) {
+            panic!("WeightedRandomSampler: Weights must be finite and non-negative.");
+        }
+        let cumulative_weights: Vec<f64> = weights
+            .iter()
+            .scan(0.0, |sum, x| {
+                *sum += x;
+                Some(*sum)
+            })
+            .collect();
+        if cumulative_weights.last().is_none_or(|&x| x <= 0.0) {
+            panic!("WeightedRandomSampler: At least one weight must be positive.");
+        }
+        Self {
+            cumulative_weights,
+            num_samples,
+            batch_size,
+            rng: Rng::new(seed),
+            drawn: 0,
+        }
+    }
+
+    /// Weights each data point by the inverse frequency of its label, so that every class is drawn
+    /// equally often. *labels* contains the class of each data point.
+    pub fn class_balanced(labels: &[u32], num_samples: u32, batch_size: u32, seed: u64) -> Self {
+        let mut counts = std::collections::HashMap::new();
+        for label in labels {
+            *counts.entry(label).or_insert(0u32) += 1;
+        }
+        let weights: Vec<f64> = labels.iter().map(|x| 1.0 / counts[x] as f64).collect();
+        Self::new(&weights, num_samples, batch_size, seed)
+    }
+}
+
+impl Sampler for WeightedRandomSampler {
+    fn next_batch(&mut self) -> Option<Vec<u32>> {
+        if self.drawn >= self.num_samples {
+            return None;
+        }
+        let amount = self.batch_size.min(self.num_samples - self.drawn);
+        self.drawn += amount;
+        let total = *self.cumulative_weights.last().unwrap();
+        Some(
+            (0..amount)
+                .map(|_| {
+                    let target = self.rng.next_f64() * total;
+                    let index = self.cumulative_weights.partition_point(|&x| x <= target);
+                    index.min(self.cumulative_weights.len() - 1) as u32
+                })
+                .collect(),
+        )
+    }
+
+    fn reset(&mut self) {
+        self.drawn = 0;
+    }
+}
+
+/// Groups data points of similar size into the same batch, which reduces padding for variable
+/// sized inputs such as strings and audio. The batches are returned in a random order.
+#[derive(Clone, Debug)]
+pub struct BucketBySizeSampler {
+    sizes: Vec<u64>,
+    batch_size: u32,
+    rng: Rng,
+    batches: Vec<Vec<u32>>,
+}
+
+impl BucketBySizeSampler {
+    /// *sizes* contains the size of each data point, for example its length or byte size.
+    pub fn new(sizes: Vec<u64>, batch_size: u32, seed: u64) -> Self {
+        assert!(batch_size > 0, "Sampler: The batch size must be positive.");
+        if u32::try_from(sizes.len()).is_err() {
+            panic!(
+                "BucketBySizeSampler: Cannot contain more than {} data points.",
+                u32::MAX
+            );
+        }
+        let mut res = Self {
+            sizes,
+            batch_size,
+            rng: Rng::new(seed),
+            batches: vec![],
+        };
+        res.reset();
+        res
+    }
+}
+
+impl Sampler for BucketBySizeSampler {
+    fn next_batch(&mut self) -> Option<Vec<u32>> {
+        self.batches.pop()
+    }
+
+    fn reset(&mut self) {
+        // Shuffle first so that data points of equal size end up in different batches each epoch.
+        let mut order = shuffle_permutation(self.sizes.len() as u32, self.rng.next_u64());
+        order.sort_by_key(|&i| self.sizes[i as usize]);
+        let batches: Vec<Vec<u32>> = order
+            .chunks(self.batch_size as usize)
+            .map(|x| x.to_vec())
+            .collect();
+        let batch_order = shuffle_permutation(batches.len() as u32, self.rng.next_u64());
+        let mut batches: Vec<Option<Vec<u32>>> = batches.into_iter().map(Some).collect();
+        self.batches = batch_order
+            .into_iter()
+            .map(|i| batches[i as usize].take().unwrap())
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch(sampler: &mut impl Sampler) -> Vec<Vec<u32>> {
+        std::iter::from_fn(|| sampler.next_batch()).collect()
+    }
+
+    /// Checks that every index below *size* appears exactly once in *batches*.
+    fn assert_each_once(batches: &[Vec<u32>], size: u32) {
+        let mut indices: Vec<u32> = batches.concat();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..size).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn sequential() {
+        let mut sampler = SequentialSampler::new(5, 2);
+        assert_eq!(epoch(&mut sampler), vec![vec![0, 1], vec![2, 3], vec![4]]);
+        assert_eq!(sampler.next_batch(), None);
+        sampler.reset();
+        assert_eq!(epoch(&mut sampler), vec![vec![0, 1], vec![2, 3], vec![4]]);
+    }
+
+    #[test]
+    fn random_returns_each_index_once_per_epoch() {
+        let mut sampler = RandomSampler::new(103, 10, 1);
+        let first = epoch(&mut sampler);
+        assert_eq!(first.len(), 11);
+        assert!(first[..10].iter().all(|x| x.len() == 10));
+        assert_each_once(&first, 103);
+        sampler.reset();
+        let second = epoch(&mut sampler);
+        assert_each_once(&second, 103);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn random_is_deterministic() {
+        let mut a = RandomSampler::new(50, 7, 9);
+        let mut b = RandomSampler::new(50, 7, 9);
+        for _ in 0..3 {
+            assert_eq!(epoch(&mut a), epoch(&mut b));
+            a.reset();
+            b.reset();
+        }
+        assert_ne!(
+            epoch(&mut RandomSampler::new(50, 7, 9)),
+            epoch(&mut RandomSampler::new(50, 7, 10))
+        );
+    }
+
+    #[test]
+    fn weighted_random_follows_weights() {
+        let mut sampler = WeightedRandomSampler::new(&[1.0, 0.0, 3.0], 40000, 64, 3);
+        let batches = epoch(&mut sampler);
+        assert_eq!(batches.iter().map(Vec::len).sum::<usize>(), 40000);
+        let mut counts = [0u32; 3];
+        for index in batches.concat() {
+            counts[index as usize] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        let fraction = counts[2] as f64 / 40000.0;
+        assert!((fraction - 0.75).abs() < 0.02, "{fraction}");
+
+        let mut other = WeightedRandomSampler::new(&[1.0, 0.0, 3.0], 40000, 64, 3);
+        assert_eq!(epoch(&mut other), batches);
+    }
+
+    #[test]
+    fn class_balanced_draws_classes_equally() {
+        let labels = [0, 0, 0, 0, 0, 0, 0, 0, 0, 7];
+        let mut sampler = WeightedRandomSampler::class_balanced(&labels, 20000, 100, 4);
+        let drawn = epoch(&mut sampler).concat();
+        let rare = drawn.iter().filter(|&&x| labels[x as usize] == 7).count();
+        let fraction = rare as f64 / drawn.len() as f64;
+        assert!((fraction - 0.5).abs() < 0.02, "{fraction}");
+    }
+
+    #[test]
+    #[should_panic(expected = "At least one weight must be positive")]
+    fn weighted_random_rejects_zero_weights() {
+        WeightedRandomSampler::new(&[0.0, 0.0], 1, 1, 0);
+    }
+
+    #[test]
+    fn bucket_by_size_groups_similar_sizes() {
+        let sizes: Vec<u64> = (0..40).map(|x| (x * 7919) % 40).collect();
+        let mut sampler = BucketBySizeSampler::new(sizes.clone(), 4, 5);
+        let first = epoch(&mut sampler);
+        assert_each_once(&first, 40);
+        for batch in &first {
+            let batch_sizes: Vec<u64> = batch.iter().map(|&x| sizes[x as usize]).collect();
+            let min = *batch_sizes.iter().min().unwrap();
+            let max = *batch_sizes.iter().max().unwrap();
+            assert_eq!(max - min, 3, "{batch_sizes:?}");
+        }
+        sampler.reset();
+        let second = epoch(&mut sampler);
+        assert_each_once(&second, 40);
+        assert_ne!(first, second);
+
+        let mut other = BucketBySizeSampler::new(sizes, 4, 5);
+        assert_eq!(epoch(&mut other), first);
+    }
+}
@@ -0,0 +1,120 @@
+/// The shuffle state of a data loader, which can be saved together with a checkpoint. Restoring
+/// it with restore_shuffle_state on a newly created data loader for the same dataset makes future
+/// reads return the data points in the same order as the data loader it was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ShuffleState {
+    /// The seed of the last shuffle, or None if the data loader has not been shuffled.
+    pub seed: Option<u64>,
+    pub position: u32,
+}
+
+/// A SplitMix64 pseudo random number generator. This is the generator used for all shuffles, so
+/// hosts and data loaders that share a seed produce the same order.
+#[derive(Clone, Debug)]
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in the range 0..bound. Numbers from next_u64 below 2^64 modulo *bound*
+    /// are skipped, so that every result is equally likely.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        let threshold = bound.wrapping_neg() % bound;
+        loop {
+            let x = self.next_u64();
+            if x >= threshold {
+                return x % bound;
+            }
+        }
+    }
+
+    /// Returns a number in the range [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Returns a seed which is different every time this is called.
+pub(crate) fn random_seed() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|x| x.as_nanos())
+            .unwrap_or(0),
+    );
+    hasher.finish()
+}
+
+/// Returns the order in which a data loader with *size* data points returns its data after being
+/// shuffled with *seed*. Element i of the result is the index in the unshuffled data of the data
+/// point that is read at position i.
+///
+/// The permutation is created by starting from the identity and then, for each i from size - 1
+/// down to 1, swapping element i with element j, where j is the next number from a SplitMix64
+/// generator seeded with *seed*, modulo i + 1. Numbers below 2^64 modulo i + 1 are skipped, so
+/// that j is not biased. A shuffle always starts from the unshuffled order, so shuffling twice
+/// with the same seed gives the same order as shuffling once.
+pub fn shuffle_permutation(size: u32, seed: u64) -> Vec<u32> {
+    let mut res: Vec<u32> = (0..size).collect();
+    let mut rng = Rng::new(seed);
+    for i in (1..res.len()).rev() {
+        let j = rng.below(i as u64 + 1) as usize;
+        res.swap(i, j);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitmix64_known_values() {
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xe220a8397b1dcdaf);
+        assert_eq!(rng.next_u64(), 0x6e789e6aa1b965f4);
+        assert_eq!(rng.next_u64(), 0x06c45d188009454f);
+    }
+
+    #[test]
+    fn below_is_in_range() {
+        let mut rng = Rng::new(1);
+        for bound in [1, 2, 3, 10, u64::MAX / 2 + 2, u64::MAX] {
+            for _ in 0..100 {
+                assert!(rng.below(bound) < bound);
+            }
+        }
+    }
+
+    #[test]
+    fn shuffle_permutation_is_deterministic() {
+        let a = shuffle_permutation(1000, 42);
+        assert_eq!(a, shuffle_permutation(1000, 42));
+        assert_ne!(a, shuffle_permutation(1000, 43));
+        let mut sorted = a.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shuffle_permutation_known_order() {
+        assert_eq!(shuffle_permutation(0, 7), Vec::<u32>::new());
+        assert_eq!(shuffle_permutation(1, 7), vec![0]);
+        assert_eq!(shuffle_permutation(8, 7), vec![1, 4, 5, 2, 6, 0, 3, 7]);
+    }
+}
@@ -2,7 +2,18 @@ use std::collections::HashMap;
 
 use futures::future::BoxFuture;
 
-use decthings_api::tensor::{DecthingsTensor, OwnedDecthingsTensor};
+use crate::{
+    context::SharedContext,
+    sampler::Sampler,
+    shuffle::ShuffleState,
+    transform::{Filter, Map},
+    view::{DataLoaderBinaryView, DataLoaderView},
+};
+
+use decthings_api::tensor::{
+    DecthingsParameterDefinition, DecthingsTensor, DeserializeDecthingsTensorError,
+    OwnedDecthingsTensor,
+};
 
 #[derive(Clone, Debug)]
 pub struct EvaluateOutput {
@@ -30,7 +41,54 @@ impl From<EvaluateOutput> for EvaluateOutputBinary {
 pub trait WeightsLoader: Send + Sync {
     fn byte_size(&self) -> u64;
 
+    /// Reads the weights. Panics if they cannot be read, for example if compressed weights are
+    /// corrupt. See try_read.
     fn read(&mut self) -> BoxFuture<'_, bytes::Bytes>;
+
+    /// Same as read, but returns an error instead of panicking.
+    fn try_read(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async { Ok(self.read().await) })
+    }
+
+    /// Same as read, but when the host has the weights in a local file, the returned data is a
+    /// read-only memory map of that file instead of a copy. The pages are loaded lazily by the
+    /// operating system and are shared with other instances that map the same file. Falls back to
+    /// read when no file is available or the file cannot be mapped. Compressed weights are
+    /// decompressed into memory, so they are not shared.
+    fn mmap(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        self.read()
+    }
+
+    /// Same as mmap, but returns an error instead of panicking.
+    fn try_mmap(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async { Ok(self.mmap().await) })
+    }
+
+    /// Reads the weights as they are stored, without decompressing them, so the data may start
+    /// with the header of crate::compress_weights. Like mmap, the data is a memory map when the
+    /// host has the weights in a local file. Loaders that do not decompress the weights can use
+    /// the default implementation, which calls read.
+    fn read_raw(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        self.read()
+    }
+
+    /// Same as read_raw, but returns an error instead of panicking.
+    fn try_read_raw(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async { Ok(self.read_raw().await) })
+    }
+
+    /// Reads the weights and verifies the header written by crate::encode_weights_blob, which
+    /// ModelWeights::to_weights adds to each value. Returns the schema version and the data after
+    /// the header. *key* is only used in errors.
+    fn read_checked<'a>(
+        &'a mut self,
+        key: &'a str,
+    ) -> BoxFuture<'a, Result<(u32, bytes::Bytes), crate::WeightsError>> {
+        Box::pin(async move {
+            let blob = self.try_read().await?;
+            crate::decode_weights_blob(key, &blob)
+        })
+    }
 }
 
 pub trait WeightsProvider: Send + Sync {
@@ -44,6 +102,134 @@ pub trait WeightsProvider: Send + Sync {
             self.provide_all(&[(key, data)]).await;
         })
     }
+
+    /// Provides the weights under *key* as a stream of chunks, which are concatenated by the host.
+    /// Unlike provide, the data does not need to fit in memory at once, and there is no limit on
+    /// the total size, but each chunk must be at most 1 GiB. Empty chunks are skipped. Streamed
+    /// weights are not compressed, on any platform.
+    ///
+    /// The default implementation collects the stream and calls provide.
+    fn provide_stream<'a>(
+        &'a mut self,
+        key: &'a str,
+        data: impl futures::Stream<Item = bytes::Bytes> + Send + 'a,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let data = futures::StreamExt::collect::<Vec<_>>(data).await.concat();
+            self.provide(key, data.into()).await;
+        })
+    }
+
+    /// Marks *keys* as unchanged from the weights that the model was instantiated with, so that
+    /// the host keeps them without them being provided again. Only available in get_weights, for
+    /// keys in GetWeightsOptions::instantiated_weights.
+    fn inherit<'a>(&'a mut self, keys: &'a [&'a str]) -> BoxFuture<'a, ()> {
+        let _ = keys;
+        Box::pin(async {
+            panic!("WeightsProvider: This weights provider does not support inherit.")
+        })
+    }
+
+    /// Provides the weights under *key* as a patch created by crate::create_weights_delta, which
+    /// the host applies to the weights under the same key that the model was instantiated with.
+    /// The patch applies to the weights as stored, as returned by WeightsLoader::read_raw. Only
+    /// available in get_weights, for keys in GetWeightsOptions::instantiated_weights.
+    fn provide_delta<'a>(&'a mut self, key: &'a str, delta: bytes::Bytes) -> BoxFuture<'a, ()> {
+        let _ = (key, delta);
+        Box::pin(async {
+            panic!("WeightsProvider: This weights provider does not support provide_delta.")
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum DataLoaderError {
+    /// The data point at *index* could not be decoded as a tensor.
+    Decode {
+        index: u32,
+        error: DeserializeDecthingsTensorError,
+    },
+    /// The connection to the host was lost.
+    Disconnected,
+    /// The request was dropped before the host provided the data.
+    Cancelled,
+    /// The host did not provide the data within the timeout set by set_timeout.
+    Timeout,
+    /// A read referred to *index*, which is outside of a data loader with *size* data points.
+    IndexOutOfRange { index: u32, size: u32 },
+    /// The host provided a data point of *size* bytes, which is more than the *limit* configured
+    /// for the model.
+    ElementTooLarge { size: u64, limit: u64 },
+    /// The data loader does not support *operation*, such as a range read on a data loader
+    /// returned by filter.
+    Unsupported { operation: &'static str },
+}
+
+impl std::fmt::Display for DataLoaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Decode { index, error } => {
+                write!(
+                    f,
+                    "Failed to decode data point {index} as a tensor: {error:?}"
+                )
+            }
+            Self::Disconnected => write!(f, "The connection to the host was lost."),
+            Self::Cancelled => write!(f, "The request was cancelled before data was provided."),
+            Self::Timeout => write!(f, "Timed out while waiting for data from the host."),
+            Self::IndexOutOfRange { index, size } => write!(
+                f,
+                "Index {index} is out of range for a data loader with {size} data points."
+            ),
+            Self::ElementTooLarge { size, limit } => write!(
+                f,
+                "A data point of {size} bytes was provided, which exceeds the limit of {limit} bytes."
+            ),
+            Self::Unsupported { operation } => {
+                write!(f, "This data loader does not support {operation}.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DataLoaderError {}
+
+/// Reads the data points at *indices* by fetching each run of consecutive indices with a single
+/// call to *read_range*. Indices that occur multiple times are only fetched once.
+pub(crate) async fn read_indices_coalesced<'a, T: Clone>(
+    indices: &[u32],
+    size: u32,
+    read_range: impl Fn(u32, u32) -> BoxFuture<'a, Result<Vec<T>, DataLoaderError>>,
+) -> Result<Vec<T>, DataLoaderError> {
+    if let Some(&index) = indices.iter().find(|&&index| index >= size) {
+        return Err(DataLoaderError::IndexOutOfRange { index, size });
+    }
+
+    let mut sorted = indices.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut runs: Vec<(u32, u32)> = vec![];
+    for index in sorted {
+        match runs.last_mut() {
+            Some((start, amount)) if *start + *amount == index => *amount += 1,
+            _ => runs.push((index, 1)),
+        }
+    }
+
+    let fetched = futures::future::try_join_all(
+        runs.iter()
+            .map(|&(start_index, amount)| read_range(start_index, amount)),
+    )
+    .await?;
+
+    Ok(indices
+        .iter()
+        .map(|&index| {
+            let run = runs.partition_point(|&(start_index, _)| start_index <= index) - 1;
+            fetched[run][(index - runs[run].0) as usize].clone()
+        })
+        .collect())
 }
 
 pub trait DataLoaderBinary: Send + Sync {
@@ -55,9 +241,98 @@ pub trait DataLoaderBinary: Send + Sync {
         self.shuffle_in_group(&[])
     }
 
+    /// Same as shuffle, but returns an error instead of panicking.
+    fn try_shuffle(&self) -> BoxFuture<'_, Result<(), DataLoaderError>> {
+        self.try_shuffle_in_group(&[])
+    }
+
     /// After this has been called, the data points will be returned in a random order from future
     /// reads. The data loaders in *others* will be shuffled in the same order.
-    fn shuffle_in_group<'a>(&'a self, others: &'a [&'a Self]) -> BoxFuture<'a, ()>;
+    fn shuffle_in_group<'a>(&'a self, others: &'a [&'a Self]) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            DataLoaderBinary::try_shuffle_in_group(self, others)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as shuffle_in_group, but returns an error instead of panicking.
+    fn try_shuffle_in_group<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+        DataLoaderBinary::try_shuffle_in_group_with_seed(
+            self,
+            others,
+            crate::shuffle::random_seed(),
+        )
+    }
+
+    /// Same as shuffle, but the order is determined by *seed*. Data loaders of the same size that
+    /// are shuffled with the same seed return their data in the same order, see
+    /// shuffle_permutation.
+    fn shuffle_with_seed(&self, seed: u64) -> BoxFuture<'_, ()> {
+        self.shuffle_in_group_with_seed(&[], seed)
+    }
+
+    /// Same as shuffle_with_seed, but returns an error instead of panicking.
+    fn try_shuffle_with_seed(&self, seed: u64) -> BoxFuture<'_, Result<(), DataLoaderError>> {
+        self.try_shuffle_in_group_with_seed(&[], seed)
+    }
+
+    /// Same as shuffle_in_group, but the order is determined by *seed*.
+    fn shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            DataLoaderBinary::try_shuffle_in_group_with_seed(self, others, seed)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as shuffle_in_group_with_seed, but returns an error instead of panicking.
+    fn try_shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>>;
+
+    /// Returns the current shuffle state, which can be saved together with a checkpoint. By
+    /// default, this reports the position and no seed, as for a data loader that has not been
+    /// shuffled with a seed.
+    fn shuffle_state(&self) -> ShuffleState {
+        ShuffleState {
+            seed: None,
+            position: self.position(),
+        }
+    }
+
+    /// Shuffles with the seed in *state* and moves to its position. This should be called on a
+    /// data loader which has not been shuffled before.
+    fn restore_shuffle_state(&mut self, state: ShuffleState) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            DataLoaderBinary::try_restore_shuffle_state(self, state)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as restore_shuffle_state, but returns an error instead of panicking.
+    fn try_restore_shuffle_state(
+        &mut self,
+        state: ShuffleState,
+    ) -> BoxFuture<'_, Result<(), DataLoaderError>> {
+        Box::pin(async move {
+            if let Some(seed) = state.seed {
+                DataLoaderBinary::try_shuffle_with_seed(self, seed).await?;
+            }
+            DataLoaderBinary::set_position(self, state.position);
+            Ok(())
+        })
+    }
 
     fn size(&self) -> u32;
 
@@ -66,6 +341,12 @@ pub trait DataLoaderBinary: Send + Sync {
     /// After called, future reads will read from this position instead.
     fn set_position(&mut self, position: u32);
 
+    /// Limits how long a single read may wait for the host. Reads that take longer fail with
+    /// DataLoaderError::Timeout. Data loaders that do not wait for a host ignore this.
+    fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        let _ = timeout;
+    }
+
     /// Returns the number of remaining data points, i.e self.size() - self.position().
     fn remaining(&self) -> u32 {
         DataLoaderBinary::size(self) - DataLoaderBinary::position(self)
@@ -78,7 +359,169 @@ pub trait DataLoaderBinary: Send + Sync {
 
     /// Fetches data points and advance the position by *amount*. If self.remaining() is less
     /// than *amount*, self.remaining() data points are fetched instead.
-    fn next(&mut self, amount: u32) -> BoxFuture<'_, Vec<bytes::Bytes>>;
+    fn next(&mut self, amount: u32) -> BoxFuture<'_, Vec<bytes::Bytes>> {
+        Box::pin(async move {
+            DataLoaderBinary::try_next(self, amount)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as next, but returns an error instead of panicking. The position is only advanced if
+    /// the read succeeds.
+    fn try_next(
+        &mut self,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(async move {
+            let position = DataLoaderBinary::position(self);
+            let res = DataLoaderBinary::try_read_range(self, position, amount).await?;
+            DataLoaderBinary::set_position(self, position + res.len() as u32);
+            Ok(res)
+        })
+    }
+
+    /// Fetches *amount* data points starting at *start_index*, without changing the position. If
+    /// fewer than *amount* data points exist after *start_index*, only those are fetched.
+    fn read_range(&self, start_index: u32, amount: u32) -> BoxFuture<'_, Vec<bytes::Bytes>> {
+        Box::pin(async move {
+            DataLoaderBinary::try_read_range(self, start_index, amount)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as read_range, but returns an error instead of panicking.
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<bytes::Bytes>, DataLoaderError>>;
+
+    /// Same as next, but the data points are placed in *buffer*. See try_read_range_into.
+    fn next_into<'a>(
+        &'a mut self,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Vec<bytes::Bytes>> {
+        Box::pin(async move {
+            DataLoaderBinary::try_next_into(self, amount, buffer)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as next_into, but returns an error instead of panicking.
+    fn try_next_into<'a>(
+        &'a mut self,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(async move {
+            let position = DataLoaderBinary::position(self);
+            let res = DataLoaderBinary::try_read_range_into(self, position, amount, buffer).await?;
+            DataLoaderBinary::set_position(self, position + res.len() as u32);
+            Ok(res)
+        })
+    }
+
+    /// Same as read_range, but the data points are placed in *buffer*. See try_read_range_into.
+    fn read_range_into<'a>(
+        &'a self,
+        start_index: u32,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Vec<bytes::Bytes>> {
+        Box::pin(async move {
+            DataLoaderBinary::try_read_range_into(self, start_index, amount, buffer)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as try_read_range, but the data provided by the host is read directly into the
+    /// allocation of *buffer*, and any previous content of *buffer* is discarded. When reading in
+    /// a loop with the same buffer, its allocation is reused once the data points from earlier
+    /// reads have been dropped, so that no allocation is needed per read. Data loaders that do not
+    /// receive data from the host, such as in-memory or cached ones, ignore the buffer.
+    fn try_read_range_into<'a>(
+        &'a self,
+        start_index: u32,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        let _ = buffer;
+        DataLoaderBinary::try_read_range(self, start_index, amount)
+    }
+
+    /// Fetches the data points at *indices*, in the given order, without changing the position.
+    /// Consecutive indices are fetched together.
+    fn read_indices<'a>(&'a self, indices: &'a [u32]) -> BoxFuture<'a, Vec<bytes::Bytes>> {
+        Box::pin(async move {
+            DataLoaderBinary::try_read_indices(self, indices)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as read_indices, but returns an error instead of panicking.
+    fn try_read_indices<'a>(
+        &'a self,
+        indices: &'a [u32],
+    ) -> BoxFuture<'a, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(read_indices_coalesced(
+            indices,
+            DataLoaderBinary::size(self),
+            |start_index, amount| DataLoaderBinary::try_read_range(self, start_index, amount),
+        ))
+    }
+
+    /// Reads the next batch chosen by *sampler*, without changing the position. Returns None when
+    /// the sampler has no more batches in the current epoch.
+    fn read_batch<'a>(
+        &'a self,
+        sampler: &'a mut (impl Sampler + ?Sized),
+    ) -> BoxFuture<'a, Option<Vec<bytes::Bytes>>> {
+        Box::pin(async move {
+            DataLoaderBinary::try_read_batch(self, sampler)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as read_batch, but returns an error instead of panicking.
+    fn try_read_batch<'a>(
+        &'a self,
+        sampler: &'a mut (impl Sampler + ?Sized),
+    ) -> BoxFuture<'a, Result<Option<Vec<bytes::Bytes>>, DataLoaderError>> {
+        Box::pin(async move {
+            let Some(indices) = sampler.next_batch() else {
+                return Ok(None);
+            };
+            DataLoaderBinary::try_read_indices(self, &indices)
+                .await
+                .map(Some)
+        })
+    }
+
+    /// Randomly splits the data points into two views, where the first contains *fraction* of the
+    /// data points and the second contains the rest. The same *seed* always gives the same split,
+    /// which makes it suitable for holding out a validation set.
+    fn split(
+        &self,
+        fraction: f64,
+        seed: u64,
+    ) -> (
+        DataLoaderBinaryView<'_, Self>,
+        DataLoaderBinaryView<'_, Self>,
+    ) {
+        DataLoaderBinaryView::split(self, fraction, seed)
+    }
+
+    /// Returns a view of the data points in *range*.
+    fn slice(&self, range: std::ops::Range<u32>) -> DataLoaderBinaryView<'_, Self> {
+        DataLoaderBinaryView::slice(self, range)
+    }
 }
 
 pub trait DataLoader: Send + Sync {
@@ -90,9 +533,94 @@ pub trait DataLoader: Send + Sync {
         self.shuffle_in_group(&[])
     }
 
+    /// Same as shuffle, but returns an error instead of panicking.
+    fn try_shuffle(&self) -> BoxFuture<'_, Result<(), DataLoaderError>> {
+        self.try_shuffle_in_group(&[])
+    }
+
     /// After this has been called, the data points will be returned in a random order from future
     /// reads. The data loaders in *others* will be shuffled in the same order.
-    fn shuffle_in_group<'a>(&'a self, others: &'a [&'a Self]) -> BoxFuture<'a, ()>;
+    fn shuffle_in_group<'a>(&'a self, others: &'a [&'a Self]) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            DataLoader::try_shuffle_in_group(self, others)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as shuffle_in_group, but returns an error instead of panicking.
+    fn try_shuffle_in_group<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+        DataLoader::try_shuffle_in_group_with_seed(self, others, crate::shuffle::random_seed())
+    }
+
+    /// Same as shuffle, but the order is determined by *seed*. Data loaders of the same size that
+    /// are shuffled with the same seed return their data in the same order, see
+    /// shuffle_permutation.
+    fn shuffle_with_seed(&self, seed: u64) -> BoxFuture<'_, ()> {
+        self.shuffle_in_group_with_seed(&[], seed)
+    }
+
+    /// Same as shuffle_with_seed, but returns an error instead of panicking.
+    fn try_shuffle_with_seed(&self, seed: u64) -> BoxFuture<'_, Result<(), DataLoaderError>> {
+        self.try_shuffle_in_group_with_seed(&[], seed)
+    }
+
+    /// Same as shuffle_in_group, but the order is determined by *seed*.
+    fn shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            DataLoader::try_shuffle_in_group_with_seed(self, others, seed)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as shuffle_in_group_with_seed, but returns an error instead of panicking.
+    fn try_shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>>;
+
+    /// Returns the current shuffle state, which can be saved together with a checkpoint. By
+    /// default, this reports the position and no seed, as for a data loader that has not been
+    /// shuffled with a seed.
+    fn shuffle_state(&self) -> ShuffleState {
+        ShuffleState {
+            seed: None,
+            position: self.position(),
+        }
+    }
+
+    /// Shuffles with the seed in *state* and moves to its position. This should be called on a
+    /// data loader which has not been shuffled before.
+    fn restore_shuffle_state(&mut self, state: ShuffleState) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            DataLoader::try_restore_shuffle_state(self, state)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as restore_shuffle_state, but returns an error instead of panicking.
+    fn try_restore_shuffle_state(
+        &mut self,
+        state: ShuffleState,
+    ) -> BoxFuture<'_, Result<(), DataLoaderError>> {
+        Box::pin(async move {
+            if let Some(seed) = state.seed {
+                DataLoader::try_shuffle_with_seed(self, seed).await?;
+            }
+            DataLoader::set_position(self, state.position);
+            Ok(())
+        })
+    }
 
     fn size(&self) -> u32;
 
@@ -101,6 +629,12 @@ pub trait DataLoader: Send + Sync {
     /// After called, future reads will read from this position instead.
     fn set_position(&mut self, position: u32);
 
+    /// Limits how long a single read may wait for the host. Reads that take longer fail with
+    /// DataLoaderError::Timeout. Data loaders that do not wait for a host ignore this.
+    fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        let _ = timeout;
+    }
+
     /// Returns the number of remaining data points, i.e self.size() - self.position().
     fn remaining(&self) -> u32 {
         DataLoader::size(self) - DataLoader::position(self)
@@ -113,7 +647,213 @@ pub trait DataLoader: Send + Sync {
 
     /// Fetches data and advances the position by *amount*. If self.remaining() is less than
     /// *amount*, self.remaining() data points are fetched instead.
-    fn next(&mut self, amount: u32) -> BoxFuture<'_, Vec<OwnedDecthingsTensor>>;
+    fn next(&mut self, amount: u32) -> BoxFuture<'_, Vec<OwnedDecthingsTensor>> {
+        Box::pin(async move {
+            DataLoader::try_next(self, amount)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as next, but returns an error instead of panicking. The position is only advanced if
+    /// the read succeeds.
+    fn try_next(
+        &mut self,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(async move {
+            let position = DataLoader::position(self);
+            let res = DataLoader::try_read_range(self, position, amount).await?;
+            DataLoader::set_position(self, position + res.len() as u32);
+            Ok(res)
+        })
+    }
+
+    /// Fetches *amount* data points starting at *start_index*, without changing the position. If
+    /// fewer than *amount* data points exist after *start_index*, only those are fetched.
+    fn read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Vec<OwnedDecthingsTensor>> {
+        Box::pin(async move {
+            DataLoader::try_read_range(self, start_index, amount)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as read_range, but returns an error instead of panicking.
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>>;
+
+    /// Same as next, but the data is decoded from *buffer*. See try_read_range_into.
+    fn next_into<'a>(
+        &'a mut self,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Vec<OwnedDecthingsTensor>> {
+        Box::pin(async move {
+            DataLoader::try_next_into(self, amount, buffer)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as next_into, but returns an error instead of panicking.
+    fn try_next_into<'a>(
+        &'a mut self,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(async move {
+            let position = DataLoader::position(self);
+            let res = DataLoader::try_read_range_into(self, position, amount, buffer).await?;
+            DataLoader::set_position(self, position + res.len() as u32);
+            Ok(res)
+        })
+    }
+
+    /// Same as read_range, but the data is decoded from *buffer*. See try_read_range_into.
+    fn read_range_into<'a>(
+        &'a self,
+        start_index: u32,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Vec<OwnedDecthingsTensor>> {
+        Box::pin(async move {
+            DataLoader::try_read_range_into(self, start_index, amount, buffer)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as try_read_range, but the data is read into the allocation of *buffer* and the
+    /// tensors are decoded from there, see DataLoaderBinary::try_read_range_into. Data loaders
+    /// that create new tensors, such as those returned by map, ignore the buffer.
+    fn try_read_range_into<'a>(
+        &'a self,
+        start_index: u32,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        let _ = buffer;
+        DataLoader::try_read_range(self, start_index, amount)
+    }
+
+    /// Fetches the data points at *indices*, in the given order, without changing the position.
+    /// Consecutive indices are fetched together.
+    fn read_indices<'a>(&'a self, indices: &'a [u32]) -> BoxFuture<'a, Vec<OwnedDecthingsTensor>> {
+        Box::pin(async move {
+            DataLoader::try_read_indices(self, indices)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as read_indices, but returns an error instead of panicking.
+    fn try_read_indices<'a>(
+        &'a self,
+        indices: &'a [u32],
+    ) -> BoxFuture<'a, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(read_indices_coalesced(
+            indices,
+            DataLoader::size(self),
+            |start_index, amount| DataLoader::try_read_range(self, start_index, amount),
+        ))
+    }
+
+    /// Reads the next batch chosen by *sampler*, without changing the position. Returns None when
+    /// the sampler has no more batches in the current epoch.
+    fn read_batch<'a>(
+        &'a self,
+        sampler: &'a mut (impl Sampler + ?Sized),
+    ) -> BoxFuture<'a, Option<Vec<OwnedDecthingsTensor>>> {
+        Box::pin(async move {
+            DataLoader::try_read_batch(self, sampler)
+                .await
+                .unwrap_or_else(|e| panic!("DataLoader: {e}"))
+        })
+    }
+
+    /// Same as read_batch, but returns an error instead of panicking.
+    fn try_read_batch<'a>(
+        &'a self,
+        sampler: &'a mut (impl Sampler + ?Sized),
+    ) -> BoxFuture<'a, Result<Option<Vec<OwnedDecthingsTensor>>, DataLoaderError>> {
+        Box::pin(async move {
+            let Some(indices) = sampler.next_batch() else {
+                return Ok(None);
+            };
+            DataLoader::try_read_indices(self, &indices).await.map(Some)
+        })
+    }
+
+    /// Randomly splits the data points into two views, where the first contains *fraction* of the
+    /// data points and the second contains the rest. The same *seed* always gives the same split,
+    /// which makes it suitable for holding out a validation set.
+    fn split(
+        &self,
+        fraction: f64,
+        seed: u64,
+    ) -> (DataLoaderView<'_, Self>, DataLoaderView<'_, Self>) {
+        DataLoaderView::split(self, fraction, seed)
+    }
+
+    /// Returns a view of the data points in *range*.
+    fn slice(&self, range: std::ops::Range<u32>) -> DataLoaderView<'_, Self> {
+        DataLoaderView::slice(self, range)
+    }
+
+    /// Returns a data loader which applies *f* to each data point that is read.
+    fn map(
+        self,
+        f: impl Fn(OwnedDecthingsTensor) -> OwnedDecthingsTensor + Send + Sync + 'static,
+    ) -> Map<Self>
+    where
+        Self: Sized,
+    {
+        Map::each(self, f)
+    }
+
+    /// Same as map, but *f* is asynchronous. The data points of a read are transformed
+    /// concurrently.
+    fn map_async<Fut: std::future::Future<Output = OwnedDecthingsTensor> + Send + 'static>(
+        self,
+        f: impl Fn(OwnedDecthingsTensor) -> Fut + Send + Sync + 'static,
+    ) -> Map<Self>
+    where
+        Self: Sized,
+    {
+        Map::each_async(self, f)
+    }
+
+    /// Returns a data loader which applies *f* to all data points of each read together. *f* must
+    /// return as many data points as it was given.
+    fn map_batch(
+        self,
+        f: impl Fn(Vec<OwnedDecthingsTensor>) -> Vec<OwnedDecthingsTensor> + Send + Sync + 'static,
+    ) -> Map<Self>
+    where
+        Self: Sized,
+    {
+        Map::batch(self, f)
+    }
+
+    /// Returns a data loader which skips the data points for which *predicate* returns false. The
+    /// returned data loader can only be read sequentially, see Filter.
+    fn filter(
+        self,
+        predicate: impl Fn(&OwnedDecthingsTensor) -> bool + Send + Sync + 'static,
+    ) -> Filter<Self>
+    where
+        Self: Sized,
+    {
+        Filter::new(self, predicate)
+    }
 }
 
 impl<T: DataLoaderBinary + Send> DataLoader for T {
@@ -121,8 +861,16 @@ impl<T: DataLoaderBinary + Send> DataLoader for T {
         DataLoaderBinary::total_byte_size(self)
     }
 
-    fn shuffle_in_group<'a>(&'a self, others: &'a [&'a Self]) -> BoxFuture<'a, ()> {
-        DataLoaderBinary::shuffle_in_group(self, others)
+    fn try_shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+        DataLoaderBinary::try_shuffle_in_group_with_seed(self, others, seed)
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        DataLoaderBinary::shuffle_state(self)
     }
 
     fn size(&self) -> u32 {
@@ -137,6 +885,10 @@ impl<T: DataLoaderBinary + Send> DataLoader for T {
         DataLoaderBinary::set_position(self, position)
     }
 
+    fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        DataLoaderBinary::set_timeout(self, timeout)
+    }
+
     fn remaining(&self) -> u32 {
         DataLoaderBinary::remaining(self)
     }
@@ -145,17 +897,54 @@ impl<T: DataLoaderBinary + Send> DataLoader for T {
         DataLoaderBinary::has_next(self, amount)
     }
 
-    fn next(&mut self, amount: u32) -> BoxFuture<'_, Vec<OwnedDecthingsTensor>> {
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
         Box::pin(async move {
-            DataLoaderBinary::next(self, amount)
-                .await
-                .into_iter()
-                .map(|x| OwnedDecthingsTensor::from_bytes(x).unwrap())
-                .collect()
+            let data = DataLoaderBinary::try_read_range(self, start_index, amount).await?;
+            decode_all(data, start_index..)
+        })
+    }
+
+    fn try_read_range_into<'a>(
+        &'a self,
+        start_index: u32,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(async move {
+            let data =
+                DataLoaderBinary::try_read_range_into(self, start_index, amount, buffer).await?;
+            decode_all(data, start_index..)
+        })
+    }
+
+    fn try_read_indices<'a>(
+        &'a self,
+        indices: &'a [u32],
+    ) -> BoxFuture<'a, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(async move {
+            let data = DataLoaderBinary::try_read_indices(self, indices).await?;
+            decode_all(data, indices.iter().copied())
         })
     }
 }
 
+fn decode_all(
+    data: Vec<bytes::Bytes>,
+    indices: impl Iterator<Item = u32>,
+) -> Result<Vec<OwnedDecthingsTensor>, DataLoaderError> {
+    data.into_iter()
+        .zip(indices)
+        .map(|(x, index)| {
+            OwnedDecthingsTensor::from_bytes(x)
+                .map_err(|error| DataLoaderError::Decode { index, error })
+        })
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 pub struct MetricBinary<S: AsRef<str>> {
     pub name: S,
@@ -245,6 +1034,9 @@ pub struct TrainOptions<D, T> {
 #[derive(Clone, Debug)]
 pub struct GetWeightsOptions<WP: WeightsProvider> {
     pub weights_provider: WP,
+    /// The keys of the weights that the model was instantiated with. These can be passed to
+    /// WeightsProvider::inherit or WeightsProvider::provide_delta.
+    pub instantiated_weights: Vec<String>,
 }
 
 pub trait InstantiatedBinary: Send + Sync {
@@ -271,6 +1063,14 @@ pub trait InstantiatedBinary: Send + Sync {
         let _ = options;
         panic!("GetWeights was called but was not implemented.");
     }
+
+    /// Releases resources held by the instantiated model, such as GPU memory, open files or
+    /// threads. Called once when the host disposes the instantiated model, after all of its calls
+    /// have finished or been cancelled, and the value is dropped afterwards. Does nothing by
+    /// default.
+    fn dispose(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -284,6 +1084,8 @@ pub struct InitializeWeightsOptions<D, WP: WeightsProvider, WL: WeightsLoader> {
     pub params: HashMap<String, D>,
     pub weights_provider: WP,
     pub other_models: HashMap<String, OtherModelWithWeights<WL>>,
+    /// The values stored by Model::setup.
+    pub context: SharedContext,
 }
 
 #[derive(Clone, Debug)]
@@ -295,11 +1097,59 @@ pub struct OtherModel {
 pub struct InstantiateModelOptions<WL: WeightsLoader> {
     pub weights: HashMap<String, WL>,
     pub other_models: HashMap<String, OtherModel>,
+    /// The values stored by Model::setup.
+    pub context: SharedContext,
+}
+
+/// The parameters that a model expects, as returned by Model::parameter_definitions. The
+/// definitions are sent to the host when the model starts, or exported as get-parameter-definitions
+/// on wasm. The params of each call are checked against its definitions before the model is
+/// called, so that params with other names, or missing required params, are rejected. A None list
+/// is not checked.
+#[cfg_attr(target_family = "unix", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(target_family = "unix", serde(rename_all = "camelCase"))]
+#[derive(Clone, Debug, Default)]
+pub struct ParameterDefinitions {
+    #[cfg_attr(
+        target_family = "unix",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub initialize_weights: Option<Vec<DecthingsParameterDefinition>>,
+    #[cfg_attr(
+        target_family = "unix",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub train: Option<Vec<DecthingsParameterDefinition>>,
+    #[cfg_attr(
+        target_family = "unix",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub evaluate: Option<Vec<DecthingsParameterDefinition>>,
+    /// The outputs of evaluate. These are not checked.
+    #[cfg_attr(
+        target_family = "unix",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub evaluate_output: Option<Vec<DecthingsParameterDefinition>>,
 }
 
 pub trait ModelBinary: Send + Sync {
     type Instantiated: InstantiatedBinary;
 
+    /// Returns the parameters that the model expects. By default, nothing is declared and params
+    /// are not checked.
+    fn parameter_definitions() -> ParameterDefinitions {
+        ParameterDefinitions::default()
+    }
+
+    /// Called once when the model process starts, before any other call, to store expensive
+    /// process-wide state in *context*. The context is then given to every call to
+    /// initialize_weights and instantiate_model. Does nothing by default.
+    fn setup<'a>(context: &'a mut SharedContext) -> BoxFuture<'a, ()> {
+        let _ = context;
+        Box::pin(async {})
+    }
+
     fn initialize_weights<'a>(
         options: InitializeWeightsOptions<
             impl DataLoaderBinary + 'a,
@@ -343,6 +1193,14 @@ pub trait Instantiated: Send + Sync {
         let _ = options;
         panic!("GetWeights was called but was not implemented.");
     }
+
+    /// Releases resources held by the instantiated model, such as GPU memory, open files or
+    /// threads. Called once when the host disposes the instantiated model, after all of its calls
+    /// have finished or been cancelled, and the value is dropped afterwards. Does nothing by
+    /// default.
+    fn dispose(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
 }
 
 impl<T: Instantiated + Sync> InstantiatedBinary for T {
@@ -369,11 +1227,31 @@ impl<T: Instantiated + Sync> InstantiatedBinary for T {
     ) -> BoxFuture<'a, ()> {
         T::get_weights(self, options)
     }
+
+    fn dispose(&self) -> BoxFuture<'_, ()> {
+        T::dispose(self)
+    }
 }
 
 pub trait Model: Send + Sync {
     type Instantiated: Instantiated;
 
+    /// Returns the parameters that the model expects. By default, nothing is declared and params
+    /// are not checked. The definitions of structs using `#[derive(ModelInputs)]` and
+    /// `#[derive(ModelOutputs)]` can be used here, such as
+    /// `evaluate: Some(MyInputs::parameter_definitions())`.
+    fn parameter_definitions() -> ParameterDefinitions {
+        ParameterDefinitions::default()
+    }
+
+    /// Called once when the model process starts, before any other call, to store expensive
+    /// process-wide state in *context*. The context is then given to every call to
+    /// initialize_weights and instantiate_model. Does nothing by default.
+    fn setup<'a>(context: &'a mut SharedContext) -> BoxFuture<'a, ()> {
+        let _ = context;
+        Box::pin(async {})
+    }
+
     fn initialize_weights<'a>(
         options: InitializeWeightsOptions<
             impl DataLoader + 'a,
@@ -399,6 +1277,14 @@ where
 {
     type Instantiated = T::Instantiated;
 
+    fn parameter_definitions() -> ParameterDefinitions {
+        T::parameter_definitions()
+    }
+
+    fn setup<'a>(context: &'a mut SharedContext) -> BoxFuture<'a, ()> {
+        T::setup(context)
+    }
+
     fn initialize_weights<'a>(
         options: InitializeWeightsOptions<
             impl DataLoaderBinary + 'a,
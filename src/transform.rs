@@ -0,0 +1,632 @@
+use std::{future::Future, sync::Arc};
+
+use decthings_api::tensor::OwnedDecthingsTensor;
+use futures::future::BoxFuture;
+
+use crate::{DataLoader, DataLoaderError, ShuffleState};
+
+type Tensors = Vec<OwnedDecthingsTensor>;
+
+#[derive(Clone)]
+enum Transform {
+    Each(Arc<dyn Fn(OwnedDecthingsTensor) -> OwnedDecthingsTensor + Send + Sync>),
+    EachAsync(
+        Arc<dyn Fn(OwnedDecthingsTensor) -> BoxFuture<'static, OwnedDecthingsTensor> + Send + Sync>,
+    ),
+    Batch(Arc<dyn Fn(Tensors) -> Tensors + Send + Sync>),
+}
+
+/// Data points that were read ahead by Map::try_next, and are being transformed on the pool.
+#[cfg(target_family = "unix")]
+struct Prefetched {
+    len: u32,
+    data: BoxFuture<'static, Tensors>,
+}
+
+/// A data loader that transforms the data of another data loader. Created by DataLoader::map,
+/// DataLoader::map_async and DataLoader::map_batch.
+pub struct Map<L> {
+    inner: L,
+    transform: Transform,
+    #[cfg(target_family = "unix")]
+    pool: Option<crate::WorkerPool>,
+    /// The position of the underlying data loader is ahead of the position of the map by the
+    /// length of the prefetched data.
+    #[cfg(target_family = "unix")]
+    prefetched: std::sync::Mutex<Option<Prefetched>>,
+    /// Set when the map is shuffled, which makes the prefetched data out of order.
+    #[cfg(target_family = "unix")]
+    prefetched_stale: std::sync::atomic::AtomicBool,
+}
+
+impl<L: DataLoader> Map<L> {
+    pub(crate) fn each(
+        inner: L,
+        f: impl Fn(OwnedDecthingsTensor) -> OwnedDecthingsTensor + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(inner, Transform::Each(Arc::new(f)))
+    }
+
+    pub(crate) fn each_async<Fut: Future<Output = OwnedDecthingsTensor> + Send + 'static>(
+        inner: L,
+        f: impl Fn(OwnedDecthingsTensor) -> Fut + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(
+            inner,
+            Transform::EachAsync(Arc::new(move |x| Box::pin(f(x)))),
+        )
+    }
+
+    pub(crate) fn batch(inner: L, f: impl Fn(Tensors) -> Tensors + Send + Sync + 'static) -> Self {
+        Self::new(inner, Transform::Batch(Arc::new(f)))
+    }
+
+    fn new(inner: L, transform: Transform) -> Self {
+        Self {
+            inner,
+            transform,
+            #[cfg(target_family = "unix")]
+            pool: None,
+            #[cfg(target_family = "unix")]
+            prefetched: std::sync::Mutex::new(None),
+            #[cfg(target_family = "unix")]
+            prefetched_stale: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Runs the transform on the threads of *pool* instead of on the current task. The data points
+    /// of each read are then transformed in parallel. Transforms created by map_async are not
+    /// moved to the pool.
+    ///
+    /// Sequential reads with next are also pipelined. Each call to next reads the data points for
+    /// the following call of the same size while the pool transforms the current ones, so that the
+    /// transforms overlap with the reads from the host. The position only counts the data points
+    /// that have been returned. Range and index reads are not pipelined.
+    #[cfg(target_family = "unix")]
+    pub fn with_pool(mut self, pool: crate::WorkerPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Returns the underlying data loader, at the position of the map.
+    #[cfg_attr(not(target_family = "unix"), allow(unused_mut))]
+    pub fn into_inner(mut self) -> L {
+        #[cfg(target_family = "unix")]
+        self.discard_prefetched();
+        self.inner
+    }
+
+    /// Starts transforming *data*. When a pool is used, the work begins before the returned future
+    /// is polled.
+    fn start_transform(&self, data: Tensors) -> BoxFuture<'static, Tensors> {
+        let expected = data.len();
+        match &self.transform {
+            Transform::Each(f) => {
+                #[cfg(target_family = "unix")]
+                if let Some(pool) = &self.pool {
+                    let futs: Vec<_> = data
+                        .into_iter()
+                        .map(|x| {
+                            let f = Arc::clone(f);
+                            pool.run(move || f(x))
+                        })
+                        .collect();
+                    return Box::pin(futures::future::join_all(futs));
+                }
+                let res = data.into_iter().map(|x| f(x)).collect();
+                Box::pin(async { res })
+            }
+            Transform::EachAsync(f) => {
+                Box::pin(futures::future::join_all(data.into_iter().map(|x| f(x))))
+            }
+            Transform::Batch(f) => {
+                #[cfg(target_family = "unix")]
+                if let Some(pool) = &self.pool {
+                    let f = Arc::clone(f);
+                    return Box::pin(check_len(pool.run(move || f(data)), expected));
+                }
+                let res = f(data);
+                Box::pin(check_len(async { res }, expected))
+            }
+        }
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl<L: DataLoader> Map<L> {
+    fn prefetched_len(&self) -> u32 {
+        self.prefetched
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |x| x.len)
+    }
+
+    /// Drops the prefetched data and moves the underlying data loader back to the position of the
+    /// map.
+    fn discard_prefetched(&mut self) {
+        if let Some(prefetched) = self.prefetched.get_mut().unwrap().take() {
+            let position = self.inner.position() - prefetched.len;
+            self.inner.set_position(position);
+        }
+    }
+
+    async fn try_next_pipelined(&mut self, amount: u32) -> Result<Tensors, DataLoaderError> {
+        if self
+            .prefetched_stale
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            self.discard_prefetched();
+        }
+        let (len, current) = match self.prefetched.get_mut().unwrap().take() {
+            Some(prefetched) => (prefetched.len, prefetched.data),
+            None => {
+                let data = self.inner.try_next(amount).await?;
+                (data.len() as u32, self.start_transform(data))
+            }
+        };
+
+        if len > amount {
+            // The previous call read more than is needed now. Keep the rest for later.
+            let mut res = current.await;
+            let rest = res.split_off(amount as usize);
+            *self.prefetched.get_mut().unwrap() = Some(Prefetched {
+                len: len - amount,
+                data: Box::pin(async { rest }),
+            });
+            return Ok(res);
+        }
+        if len < amount && self.inner.remaining() > 0 {
+            // The previous call read less than is needed now. Read the rest without pipelining.
+            let mut res = current.await;
+            match self.inner.try_next(amount - len).await {
+                Ok(data) => {
+                    res.extend(self.start_transform(data).await);
+                    return Ok(res);
+                }
+                Err(e) => {
+                    *self.prefetched.get_mut().unwrap() = Some(Prefetched {
+                        len,
+                        data: Box::pin(async { res }),
+                    });
+                    return Err(e);
+                }
+            }
+        }
+
+        // The current data points are being transformed on the pool. Read the next ones in the
+        // meantime. If that read fails, the next call reads again and returns the error.
+        if self.inner.remaining() > 0
+            && let Ok(data) = self.inner.try_next(amount).await
+        {
+            *self.prefetched.get_mut().unwrap() = Some(Prefetched {
+                len: data.len() as u32,
+                data: self.start_transform(data),
+            });
+        }
+        Ok(current.await)
+    }
+}
+
+async fn check_len(fut: impl Future<Output = Tensors>, expected: usize) -> Tensors {
+    let res = fut.await;
+    if res.len() != expected {
+        panic!(
+            "DataLoader: The function given to map_batch must return as many data points as it was given. It was given {} data points, but returned {}.",
+            expected,
+            res.len()
+        );
+    }
+    res
+}
+
+impl<L: DataLoader> DataLoader for Map<L> {
+    fn total_byte_size(&self) -> u64 {
+        self.inner.total_byte_size()
+    }
+
+    fn try_shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+        Box::pin(async move {
+            #[cfg(target_family = "unix")]
+            for map in [self].iter().chain(others) {
+                map.prefetched_stale
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            let others: Vec<_> = others.iter().map(|x| &x.inner).collect();
+            self.inner
+                .try_shuffle_in_group_with_seed(&others, seed)
+                .await
+        })
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        ShuffleState {
+            position: self.position(),
+            ..self.inner.shuffle_state()
+        }
+    }
+
+    fn size(&self) -> u32 {
+        self.inner.size()
+    }
+
+    fn position(&self) -> u32 {
+        #[cfg(target_family = "unix")]
+        return self.inner.position() - self.prefetched_len();
+        #[cfg(not(target_family = "unix"))]
+        self.inner.position()
+    }
+
+    fn set_position(&mut self, position: u32) {
+        #[cfg(target_family = "unix")]
+        self.prefetched.get_mut().unwrap().take();
+        self.inner.set_position(position)
+    }
+
+    fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn try_next(&mut self, amount: u32) -> BoxFuture<'_, Result<Tensors, DataLoaderError>> {
+        Box::pin(async move {
+            #[cfg(target_family = "unix")]
+            if self.pool.is_some() && !matches!(self.transform, Transform::EachAsync(_)) {
+                return self.try_next_pipelined(amount).await;
+            }
+            let data = self.inner.try_next(amount).await?;
+            Ok(self.start_transform(data).await)
+        })
+    }
+
+    fn try_next_into<'a>(
+        &'a mut self,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Tensors, DataLoaderError>> {
+        let _ = buffer;
+        DataLoader::try_next(self, amount)
+    }
+
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Tensors, DataLoaderError>> {
+        Box::pin(async move {
+            let data = self.inner.try_read_range(start_index, amount).await?;
+            Ok(self.start_transform(data).await)
+        })
+    }
+
+    fn try_read_indices<'a>(
+        &'a self,
+        indices: &'a [u32],
+    ) -> BoxFuture<'a, Result<Tensors, DataLoaderError>> {
+        Box::pin(async move {
+            let data = self.inner.try_read_indices(indices).await?;
+            Ok(self.start_transform(data).await)
+        })
+    }
+}
+
+/// A data loader that skips the data points of another data loader for which a predicate returns
+/// false. Created by DataLoader::filter.
+///
+/// Filter can only be read sequentially, with next and next_into. Since it is not known in
+/// advance how many data points are skipped, size(), position() and remaining() refer to the
+/// underlying data loader and are upper bounds, and a read at the end of the data may return
+/// fewer data points than requested. Range and index reads fail with
+/// DataLoaderError::Unsupported, which also means that Filter cannot be used with samplers.
+pub struct Filter<L> {
+    inner: L,
+    predicate: Arc<dyn Fn(&OwnedDecthingsTensor) -> bool + Send + Sync>,
+}
+
+impl<L: DataLoader> Filter<L> {
+    pub(crate) fn new(
+        inner: L,
+        predicate: impl Fn(&OwnedDecthingsTensor) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            predicate: Arc::new(predicate),
+        }
+    }
+
+    /// Returns the underlying data loader.
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+}
+
+impl<L: DataLoader> DataLoader for Filter<L> {
+    fn total_byte_size(&self) -> u64 {
+        self.inner.total_byte_size()
+    }
+
+    fn try_shuffle_in_group_with_seed<'a>(
+        &'a self,
+        others: &'a [&'a Self],
+        seed: u64,
+    ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+        Box::pin(async move {
+            let others: Vec<_> = others.iter().map(|x| &x.inner).collect();
+            self.inner
+                .try_shuffle_in_group_with_seed(&others, seed)
+                .await
+        })
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        self.inner.shuffle_state()
+    }
+
+    fn size(&self) -> u32 {
+        self.inner.size()
+    }
+
+    fn position(&self) -> u32 {
+        self.inner.position()
+    }
+
+    fn set_position(&mut self, position: u32) {
+        self.inner.set_position(position)
+    }
+
+    fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.inner.set_timeout(timeout)
+    }
+
+    /// Reads until *amount* data points have passed the predicate, or until the end of the
+    /// underlying data loader.
+    fn try_next(&mut self, amount: u32) -> BoxFuture<'_, Result<Tensors, DataLoaderError>> {
+        Box::pin(async move {
+            let mut res = vec![];
+            while (res.len() as u32) < amount && self.inner.remaining() > 0 {
+                let data = self.inner.try_next(amount - res.len() as u32).await?;
+                res.extend(data.into_iter().filter(|x| (self.predicate)(x)));
+            }
+            Ok(res)
+        })
+    }
+
+    fn try_next_into<'a>(
+        &'a mut self,
+        amount: u32,
+        buffer: &'a mut bytes::BytesMut,
+    ) -> BoxFuture<'a, Result<Tensors, DataLoaderError>> {
+        let _ = buffer;
+        DataLoader::try_next(self, amount)
+    }
+
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Tensors, DataLoaderError>> {
+        let _ = (start_index, amount);
+        Box::pin(async {
+            Err(DataLoaderError::Unsupported {
+                operation: "try_read_range",
+            })
+        })
+    }
+
+    fn try_read_indices<'a>(
+        &'a self,
+        indices: &'a [u32],
+    ) -> BoxFuture<'a, Result<Tensors, DataLoaderError>> {
+        let _ = indices;
+        Box::pin(async {
+            Err(DataLoaderError::Unsupported {
+                operation: "try_read_indices",
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use decthings_api::tensor::DecthingsTensor;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::InMemoryDataLoader;
+
+    fn tensor(x: f32) -> OwnedDecthingsTensor {
+        DecthingsTensor::F32(ndarray::arr0(x).into_dyn().into()).into()
+    }
+
+    fn values(data: &[OwnedDecthingsTensor]) -> Vec<f32> {
+        data.iter()
+            .map(|x| match x.tensor() {
+                DecthingsTensor::F32(a) => *a.first().unwrap(),
+                _ => panic!("expected f32"),
+            })
+            .collect()
+    }
+
+    fn data_loader(size: u32) -> InMemoryDataLoader {
+        InMemoryDataLoader::from_tensors((0..size).map(|x| tensor(x as f32)))
+    }
+
+    fn double(x: OwnedDecthingsTensor) -> OwnedDecthingsTensor {
+        tensor(values(&[x])[0] * 2.0)
+    }
+
+    type Reads = std::sync::Arc<Mutex<Vec<(u32, u32)>>>;
+
+    /// Records the range reads of the underlying data loader.
+    struct Recording {
+        inner: InMemoryDataLoader,
+        reads: Reads,
+    }
+
+    impl DataLoader for Recording {
+        fn total_byte_size(&self) -> u64 {
+            DataLoader::total_byte_size(&self.inner)
+        }
+
+        fn try_shuffle_in_group_with_seed<'a>(
+            &'a self,
+            others: &'a [&'a Self],
+            seed: u64,
+        ) -> BoxFuture<'a, Result<(), DataLoaderError>> {
+            let others: Vec<_> = others.iter().map(|x| &x.inner).collect();
+            Box::pin(async move {
+                DataLoader::try_shuffle_in_group_with_seed(&self.inner, &others, seed).await
+            })
+        }
+
+        fn size(&self) -> u32 {
+            DataLoader::size(&self.inner)
+        }
+
+        fn position(&self) -> u32 {
+            DataLoader::position(&self.inner)
+        }
+
+        fn set_position(&mut self, position: u32) {
+            DataLoader::set_position(&mut self.inner, position)
+        }
+
+        fn try_read_range(
+            &self,
+            start_index: u32,
+            amount: u32,
+        ) -> BoxFuture<'_, Result<Tensors, DataLoaderError>> {
+            self.reads.lock().unwrap().push((start_index, amount));
+            DataLoader::try_read_range(&self.inner, start_index, amount)
+        }
+    }
+
+    fn recording(size: u32) -> (Recording, Reads) {
+        let reads = std::sync::Arc::new(Mutex::new(vec![]));
+        let data_loader = Recording {
+            inner: data_loader(size),
+            reads: std::sync::Arc::clone(&reads),
+        };
+        (data_loader, reads)
+    }
+
+    #[test]
+    fn map_transforms_each_read() {
+        let mut map = DataLoader::map(data_loader(5), double);
+        assert_eq!(values(&block_on(map.try_next(2)).unwrap()), [0.0, 2.0]);
+        assert_eq!(map.position(), 2);
+        let range = block_on(map.try_read_range(3, 5)).unwrap();
+        assert_eq!(values(&range), [6.0, 8.0]);
+        let indices = block_on(map.try_read_indices(&[4, 0])).unwrap();
+        assert_eq!(values(&indices), [8.0, 0.0]);
+    }
+
+    #[test]
+    fn map_async_transforms_each_read() {
+        let mut map = DataLoader::map_async(data_loader(3), |x| async move { double(x) });
+        assert_eq!(values(&block_on(map.try_next(3)).unwrap()), [0.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn map_batch_transforms_whole_reads() {
+        let mut map = DataLoader::map_batch(data_loader(4), |data| {
+            let sum: f32 = values(&data).iter().sum();
+            data.iter().map(|_| tensor(sum)).collect()
+        });
+        assert_eq!(values(&block_on(map.try_next(2)).unwrap()), [1.0, 1.0]);
+        assert_eq!(values(&block_on(map.try_next(2)).unwrap()), [5.0, 5.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "must return as many data points")]
+    fn map_batch_checks_length() {
+        let mut map = DataLoader::map_batch(data_loader(4), |_| vec![]);
+        block_on(map.try_next(2)).ok();
+    }
+
+    #[test]
+    fn filter_reads_until_amount_passed() {
+        let mut filter = DataLoader::filter(data_loader(10), |x| {
+            values(std::slice::from_ref(x))[0] % 3.0 == 0.0
+        });
+        assert_eq!(values(&block_on(filter.try_next(2)).unwrap()), [0.0, 3.0]);
+        assert_eq!(values(&block_on(filter.try_next(5)).unwrap()), [6.0, 9.0]);
+        assert_eq!(filter.remaining(), 0);
+        assert!(block_on(filter.try_next(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn filter_rejects_random_access() {
+        let filter = DataLoader::filter(data_loader(10), |_| true);
+        assert!(matches!(
+            block_on(filter.try_read_range(0, 1)),
+            Err(DataLoaderError::Unsupported { .. })
+        ));
+        assert!(matches!(
+            block_on(filter.try_read_indices(&[0])),
+            Err(DataLoaderError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn pool_map_reads_ahead() {
+        let (inner, reads) = recording(10);
+        let mut map = DataLoader::map(inner, double).with_pool(crate::WorkerPool::new(2));
+        assert_eq!(values(&block_on(map.try_next(3)).unwrap()), [0.0, 2.0, 4.0]);
+        // The data points of the next call were read during this one.
+        assert_eq!(*reads.lock().unwrap(), [(0, 3), (3, 3)]);
+        assert_eq!(map.position(), 3);
+        assert_eq!(map.remaining(), 7);
+        assert_eq!(map.shuffle_state().position, 3);
+
+        assert_eq!(
+            values(&block_on(map.try_next(3)).unwrap()),
+            [6.0, 8.0, 10.0]
+        );
+        assert_eq!(*reads.lock().unwrap(), [(0, 3), (3, 3), (6, 3)]);
+
+        let mut all = vec![];
+        while map.remaining() > 0 {
+            all.extend(values(&block_on(map.try_next(3)).unwrap()));
+        }
+        assert_eq!(all, [12.0, 14.0, 16.0, 18.0]);
+        assert_eq!(map.position(), 10);
+    }
+
+    #[test]
+    fn pool_map_handles_other_amounts() {
+        let mut map = DataLoader::map(data_loader(10), double).with_pool(crate::WorkerPool::new(2));
+        assert_eq!(values(&block_on(map.try_next(2)).unwrap()), [0.0, 2.0]);
+        assert_eq!(values(&block_on(map.try_next(1)).unwrap()), [4.0]);
+        assert_eq!(map.position(), 3);
+        assert_eq!(
+            values(&block_on(map.try_next(4)).unwrap()),
+            [6.0, 8.0, 10.0, 12.0]
+        );
+        assert_eq!(map.position(), 7);
+
+        map.set_position(1);
+        assert_eq!(values(&block_on(map.try_next(2)).unwrap()), [2.0, 4.0]);
+        let inner = map.into_inner();
+        assert_eq!(DataLoader::position(&inner), 3);
+    }
+
+    #[test]
+    fn pool_map_shuffle_discards_prefetched() {
+        let mut map = DataLoader::map(data_loader(10), double).with_pool(crate::WorkerPool::new(2));
+        block_on(map.try_next(3)).unwrap();
+        block_on(map.try_shuffle_with_seed(4)).unwrap();
+        let read = values(&block_on(map.try_next(3)).unwrap());
+
+        let expected = data_loader(10);
+        block_on(DataLoader::try_shuffle_with_seed(&expected, 4)).unwrap();
+        let expected = block_on(DataLoader::try_read_range(&expected, 3, 3)).unwrap();
+        let expected: Vec<f32> = values(&expected).iter().map(|x| x * 2.0).collect();
+        assert_eq!(read, expected);
+    }
+}
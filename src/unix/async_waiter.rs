@@ -3,6 +3,8 @@ use std::sync::{Arc, RwLock};
 enum ValueOrQueue<T> {
     Value(Arc<T>),
     Queue(Vec<super::asyncs::oneshot::Sender<Arc<T>>>),
+    /// The provider was dropped without providing a value.
+    Failed,
 }
 
 pub struct AsyncWaiterProvider<T> {
@@ -17,7 +19,7 @@ impl<T> AsyncWaiterProvider<T> {
             ValueOrQueue::Value(Arc::clone(&val)),
         );
         match waiting {
-            ValueOrQueue::Value(_) => unreachable!(),
+            ValueOrQueue::Value(_) | ValueOrQueue::Failed => unreachable!(),
             ValueOrQueue::Queue(queue) => {
                 for tx in queue {
                     tx.send(Arc::clone(&val)).ok();
@@ -27,6 +29,16 @@ impl<T> AsyncWaiterProvider<T> {
     }
 }
 
+impl<T> Drop for AsyncWaiterProvider<T> {
+    /// If no value was provided, the waiting and all later calls to AsyncWaiter::get return None.
+    fn drop(&mut self) {
+        let mut locked = self.value_or_queue.write().unwrap();
+        if let ValueOrQueue::Queue(_) = &*locked {
+            *locked = ValueOrQueue::Failed;
+        }
+    }
+}
+
 pub struct AsyncWaiter<T> {
     value_or_queue: Arc<RwLock<ValueOrQueue<T>>>,
 }
@@ -53,7 +65,7 @@ impl<T> AsyncWaiter<T> {
         )
     }
 
-    fn inner_get(&self) -> Result<Arc<T>, super::asyncs::oneshot::Receiver<Arc<T>>> {
+    fn inner_get(&self) -> Result<Option<Arc<T>>, super::asyncs::oneshot::Receiver<Arc<T>>> {
         let locked = self.value_or_queue.read().unwrap();
         match &*locked {
             ValueOrQueue::Queue(_) => {
@@ -67,21 +79,49 @@ impl<T> AsyncWaiter<T> {
                     }
                     ValueOrQueue::Value(val) => {
                         let cloned = Arc::clone(val);
-                        Ok(cloned)
+                        Ok(Some(cloned))
                     }
+                    ValueOrQueue::Failed => Ok(None),
                 }
             }
             ValueOrQueue::Value(val) => {
                 let cloned = Arc::clone(val);
-                Ok(cloned)
+                Ok(Some(cloned))
             }
+            ValueOrQueue::Failed => Ok(None),
         }
     }
 
     pub async fn get(&self) -> Option<Arc<T>> {
         match self.inner_get() {
-            Ok(val) => Some(val),
+            Ok(val) => val,
             Err(rx) => rx.await.ok(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_after_provide() {
+        let (waiter, provider) = AsyncWaiter::new();
+        let pending = waiter.clone();
+        let pending = std::thread::spawn(move || futures::executor::block_on(pending.get()));
+        provider.provide(5);
+        assert_eq!(*pending.join().unwrap().unwrap(), 5);
+        assert_eq!(*futures::executor::block_on(waiter.get()).unwrap(), 5);
+    }
+
+    #[test]
+    fn get_after_failure() {
+        let (waiter, provider) = AsyncWaiter::<u32>::new();
+        let pending = waiter.clone();
+        let pending = std::thread::spawn(move || futures::executor::block_on(pending.get()));
+        drop(provider);
+        assert!(pending.join().unwrap().is_none());
+        // Calls made after the provider was dropped do not wait forever.
+        assert!(futures::executor::block_on(waiter.get()).is_none());
+    }
+}
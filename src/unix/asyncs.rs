@@ -1,12 +1,33 @@
 pub use tokio::{
+    fs,
     io::Error,
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
-    net::UnixStream,
+    net::{UnixListener, UnixStream},
+    process,
+    sync::Semaphore,
     sync::mpsc::{channel, Receiver, Sender},
     sync::oneshot,
-    task::spawn,
+    task::{spawn, spawn_blocking},
+    time::timeout,
 };
 
+pub fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    tokio::runtime::Runtime::new()
+        .expect("Failed to start the async runtime")
+        .block_on(f)
+}
+
+/// Runs *f* in the background on the current async runtime, or blocks until it completes if this
+/// is not called from within one.
+pub fn spawn_or_block_on<F: std::future::Future<Output = ()> + Send + 'static>(f: F) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            handle.spawn(f);
+        }
+        Err(_) => futures::executor::block_on(f),
+    }
+}
+
 pub fn unix_split(
     stream: &mut UnixStream,
 ) -> (impl AsyncRead + Unpin + '_, impl AsyncWrite + Unpin + '_) {
@@ -46,3 +67,31 @@ pub async fn read_u64(mut reader: impl AsyncRead + Unpin) -> Result<u64, Error>
 pub async fn write_u64(mut writer: impl AsyncWrite + Unpin, value: u64) -> Result<(), Error> {
     writer.write_all(&value.to_be_bytes()).await
 }
+
+/// Reads and discards *length* bytes.
+pub async fn skip(reader: impl AsyncRead + Unpin, length: u64) -> Result<(), Error> {
+    let skipped = tokio::io::copy(&mut reader.take(length), &mut tokio::io::sink()).await?;
+    if skipped < length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Appends exactly *length* bytes to *buf*, without zeroing the memory first.
+pub async fn read_exact_buf(
+    reader: impl AsyncRead + Unpin,
+    buf: &mut bytes::BytesMut,
+    length: u64,
+) -> Result<(), Error> {
+    buf.reserve(length as usize);
+    let mut reader = reader.take(length);
+    let mut remaining = length;
+    while remaining > 0 {
+        let read = reader.read_buf(buf).await?;
+        if read == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        remaining -= read as u64;
+    }
+    Ok(())
+}
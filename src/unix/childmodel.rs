@@ -0,0 +1,624 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
+
+use decthings_api::tensor::{DecthingsParameterDefinition, OwnedDecthingsTensor};
+use futures::future::{BoxFuture, Either};
+
+use super::{asyncs, host_protocol};
+use crate::*;
+
+#[derive(Debug)]
+pub enum ChildModelError {
+    /// The child process could not be started, or communicating with it failed.
+    Io(std::io::Error),
+    /// The child process exited or closed the connection.
+    Disconnected,
+    /// The child model panicked, with the *details* that it reported.
+    Exception { details: Option<String> },
+    /// The child model did not find the instantiated model.
+    InstantiatedModelNotFound,
+    /// The child model rejected the params, since they did not match its parameter definitions.
+    InvalidParameters { details: String },
+    /// Reading data that the child model requested failed.
+    DataLoader(DataLoaderError),
+    /// The child model returned outputs that do not match their byte sizes, or that could not be
+    /// decoded as tensors.
+    InvalidOutput(String),
+}
+
+impl std::fmt::Display for ChildModelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "Failed to communicate with the child model: {error}"),
+            Self::Disconnected => write!(f, "The child model exited or closed the connection."),
+            Self::Exception {
+                details: Some(details),
+            } => {
+                write!(f, "The child model panicked: {details}")
+            }
+            Self::Exception { details: None } => write!(f, "The child model panicked."),
+            Self::InstantiatedModelNotFound => {
+                write!(f, "The child model did not find the instantiated model.")
+            }
+            Self::InvalidParameters { details } => {
+                write!(f, "The child model rejected the params: {details}")
+            }
+            Self::DataLoader(error) => {
+                write!(f, "Failed to read data for the child model: {error}")
+            }
+            Self::InvalidOutput(reason) => {
+                write!(f, "The child model returned invalid outputs: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChildModelError {}
+
+enum MessageToChild {
+    Command(host_protocol::CommandMessage),
+    ProvideData(u32, Vec<bytes::Bytes>),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ResultOrEvent {
+    Result {
+        id: String,
+        result: CallResult,
+    },
+    Event {
+        event: String,
+        #[serde(default)]
+        params: serde_json::Value,
+    },
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InstantiatedModelDisposed {
+    instantiated_model_id: String,
+    #[serde(default)]
+    error: Option<CallError>,
+}
+
+#[derive(serde::Deserialize)]
+struct CallResult {
+    #[serde(default)]
+    error: Option<CallError>,
+    #[serde(default)]
+    outputs: Option<Vec<host_protocol::EvaluateOutput>>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case", tag = "code")]
+enum CallError {
+    Exception {
+        #[serde(default)]
+        details: Option<String>,
+    },
+    InstantiatedModelNotFound,
+    InvalidParameters {
+        details: String,
+    },
+}
+
+impl From<CallError> for ChildModelError {
+    fn from(value: CallError) -> Self {
+        match value {
+            CallError::Exception { details } => Self::Exception { details },
+            CallError::InstantiatedModelNotFound => Self::InstantiatedModelNotFound,
+            CallError::InvalidParameters { details } => Self::InvalidParameters { details },
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+enum DataEvent {
+    #[serde(rename_all = "camelCase")]
+    RequestData {
+        dataset: String,
+        request_id: u32,
+        start_index: u32,
+        amount: u32,
+    },
+    #[serde(rename_all = "camelCase")]
+    Shuffle { datasets: Vec<String>, seed: u64 },
+}
+
+type ResultCallback = asyncs::oneshot::Sender<(CallResult, Vec<bytes::Bytes>)>;
+
+struct Shared {
+    tx: asyncs::Sender<MessageToChild>,
+    results: Mutex<HashMap<String, ResultCallback>>,
+    /// The data events of each dataset are forwarded to the call that the dataset belongs to.
+    datasets: Mutex<HashMap<String, asyncs::Sender<DataEvent>>>,
+    /// Called with the error of the InstantiatedModelDisposed event of each instantiated model.
+    disposals: Mutex<HashMap<String, asyncs::oneshot::Sender<Option<CallError>>>>,
+    id_counter: AtomicU64,
+    _process: asyncs::process::Child,
+}
+
+impl Shared {
+    fn next_id(&self) -> u64 {
+        self.id_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Handles a message from the child. Returns false if the message could not be parsed.
+    async fn handle_message(
+        &self,
+        msg: host_protocol::MessageFromModel,
+        initialized: &mut Option<asyncs::oneshot::Sender<()>>,
+    ) -> bool {
+        match msg {
+            host_protocol::MessageFromModel::ResultOrEvent(msg, blobs) => {
+                match serde_json::from_slice(&msg) {
+                    Ok(ResultOrEvent::Result { id, result }) => {
+                        let cb = self.results.lock().unwrap().remove(&id);
+                        if let Some(cb) = cb {
+                            cb.send((result, blobs)).ok();
+                        }
+                    }
+                    Ok(ResultOrEvent::Event { event, params }) => match event.as_str() {
+                        "modelSessionInitialized" => {
+                            if let Some(initialized) = initialized.take() {
+                                initialized.send(()).ok();
+                            }
+                        }
+                        "instantiatedModelDisposed" => {
+                            let Ok(disposed) =
+                                serde_json::from_value::<InstantiatedModelDisposed>(params)
+                            else {
+                                return false;
+                            };
+                            let cb = self
+                                .disposals
+                                .lock()
+                                .unwrap()
+                                .remove(&disposed.instantiated_model_id);
+                            if let Some(cb) = cb {
+                                cb.send(disposed.error).ok();
+                            }
+                        }
+                        _ => {}
+                    },
+                    Err(_) => return false,
+                }
+            }
+            host_protocol::MessageFromModel::DataEvent(msg) => {
+                let Ok(event) = serde_json::from_slice::<DataEvent>(&msg) else {
+                    return false;
+                };
+                let dataset = match &event {
+                    DataEvent::RequestData { dataset, .. } => Some(dataset),
+                    DataEvent::Shuffle { datasets, .. } => datasets.first(),
+                };
+                let tx =
+                    dataset.and_then(|dataset| self.datasets.lock().unwrap().get(dataset).cloned());
+                if let Some(tx) = tx {
+                    tx.send(event).await.ok();
+                }
+            }
+        }
+        true
+    }
+
+    /// Sends *command* and serves the data that the child requests from *dataset_sizes* until the
+    /// result with *id* arrives. *read* reads the data points at the given indices of a dataset.
+    async fn call<F: Future<Output = Result<Vec<bytes::Bytes>, ChildModelError>>>(
+        &self,
+        id: String,
+        command: host_protocol::CommandMessage,
+        dataset_sizes: HashMap<String, u32>,
+        read: impl Fn(String, Vec<u32>) -> F,
+    ) -> Result<(CallResult, Vec<bytes::Bytes>), ChildModelError> {
+        let (result_tx, result_rx) = asyncs::oneshot::channel();
+        let (data_tx, mut data_rx) = asyncs::channel(16);
+        self.results.lock().unwrap().insert(id.clone(), result_tx);
+        {
+            let mut datasets = self.datasets.lock().unwrap();
+            for dataset in dataset_sizes.keys() {
+                datasets.insert(dataset.clone(), data_tx.clone());
+            }
+        }
+        drop(data_tx);
+
+        let res = async {
+            self.tx
+                .send(MessageToChild::Command(command))
+                .await
+                .map_err(|_| ChildModelError::Disconnected)?;
+
+            let serve = async {
+                let mut permutations: HashMap<String, Vec<u32>> = HashMap::new();
+                while let Some(event) = asyncs::channel_recv(&mut data_rx).await {
+                    match event {
+                        DataEvent::Shuffle { datasets, seed } => {
+                            for dataset in datasets {
+                                if let Some(&size) = dataset_sizes.get(&dataset) {
+                                    permutations.insert(dataset, shuffle_permutation(size, seed));
+                                }
+                            }
+                        }
+                        DataEvent::RequestData {
+                            dataset,
+                            request_id,
+                            start_index,
+                            amount,
+                        } => {
+                            let Some(&size) = dataset_sizes.get(&dataset) else {
+                                continue;
+                            };
+                            let end = start_index.saturating_add(amount).min(size);
+                            let indices = (start_index.min(end)..end)
+                                .map(|index| match permutations.get(&dataset) {
+                                    Some(permutation) => permutation[index as usize],
+                                    None => index,
+                                })
+                                .collect();
+                            let data = match read(dataset, indices).await {
+                                Ok(data) => data,
+                                Err(e) => return e,
+                            };
+                            if self
+                                .tx
+                                .send(MessageToChild::ProvideData(request_id, data))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                    }
+                }
+                // The channel is closed right away if the call has no datasets. A lost connection
+                // is reported through result_rx instead.
+                futures::future::pending().await
+            };
+
+            match futures::future::select(result_rx, std::pin::pin!(serve)).await {
+                Either::Left((Ok(result), _)) => Ok(result),
+                Either::Left((Err(_), _)) => Err(ChildModelError::Disconnected),
+                Either::Right((e, _)) => Err(e),
+            }
+        }
+        .await;
+
+        self.results.lock().unwrap().remove(&id);
+        {
+            let mut datasets = self.datasets.lock().unwrap();
+            for dataset in dataset_sizes.keys() {
+                datasets.remove(dataset);
+            }
+        }
+
+        let (mut result, blobs) = res?;
+        match result.error.take() {
+            None => Ok((result, blobs)),
+            Some(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Another model running in a child process, which this process acts as the host of. This allows
+/// a model that is not a dependency of this crate, such as one of the other models in
+/// InstantiateModelOptions::other_models, to be used as a component. For a model that is a
+/// dependency, crate::instantiate_in_memory avoids the overhead of a separate process.
+///
+/// The child process is killed when the ChildModel and all of its instantiated models have been
+/// dropped.
+#[derive(Clone)]
+pub struct ChildModel {
+    shared: Arc<Shared>,
+}
+
+impl ChildModel {
+    /// Starts *command*, which should run a model that speaks the host protocol, such as an
+    /// executable under OtherModel::mount_path. Waits until the model has initialized.
+    pub async fn spawn(command: std::process::Command) -> Result<Self, ChildModelError> {
+        static SOCKET_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "decthings-child-model-{}-{}.sock",
+            std::process::id(),
+            SOCKET_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let listener = asyncs::UnixListener::bind(&path).map_err(ChildModelError::Io)?;
+
+        let connected = async {
+            let mut process = asyncs::process::Command::from(command)
+                .env("IPC_PATH", &path)
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(ChildModelError::Io)?;
+            // The child may exit without ever connecting.
+            let accepted = {
+                let accept = std::pin::pin!(listener.accept());
+                let exit = std::pin::pin!(process.wait());
+                match futures::future::select(accept, exit).await {
+                    Either::Left((Ok((stream, _)), _)) => Ok(stream),
+                    Either::Left((Err(e), _)) => Err(ChildModelError::Io(e)),
+                    Either::Right(_) => Err(ChildModelError::Disconnected),
+                }
+            };
+            accepted.map(|stream| (process, stream))
+        }
+        .await;
+        drop(listener);
+        std::fs::remove_file(&path).ok();
+        let (process, stream) = connected?;
+        let (reader, writer) = stream.into_split();
+
+        let (tx, mut rx) = asyncs::channel::<MessageToChild>(16);
+        asyncs::spawn(async move {
+            let mut writer = asyncs::BufWriter::new(writer);
+            while let Some(msg) = asyncs::channel_recv(&mut rx).await {
+                let res = match msg {
+                    MessageToChild::Command(command) => {
+                        host_protocol::write_command(&mut writer, &command).await
+                    }
+                    MessageToChild::ProvideData(request_id, blobs) => {
+                        host_protocol::write_provide_data(&mut writer, request_id, &blobs).await
+                    }
+                };
+                if res.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let shared = Arc::new(Shared {
+            tx,
+            results: Mutex::new(HashMap::new()),
+            datasets: Mutex::new(HashMap::new()),
+            disposals: Mutex::new(HashMap::new()),
+            id_counter: AtomicU64::new(0),
+            _process: process,
+        });
+
+        let (initialized_tx, initialized_rx) = asyncs::oneshot::channel();
+        // The reader only holds a weak reference, so that dropping the last ChildModel kills the
+        // process, which in turn ends the reader.
+        let weak: Weak<Shared> = Arc::downgrade(&shared);
+        asyncs::spawn(async move {
+            let mut reader = asyncs::BufReader::new(reader);
+            let mut initialized = Some(initialized_tx);
+            while let Ok(msg) = host_protocol::read_message_from_model(&mut reader).await {
+                let Some(shared) = weak.upgrade() else {
+                    return;
+                };
+                if !shared.handle_message(msg, &mut initialized).await {
+                    break;
+                }
+            }
+            // Dropping the callbacks makes waiting calls fail.
+            if let Some(shared) = weak.upgrade() {
+                shared.results.lock().unwrap().clear();
+                shared.datasets.lock().unwrap().clear();
+                shared.disposals.lock().unwrap().clear();
+            }
+        });
+
+        initialized_rx
+            .await
+            .map_err(|_| ChildModelError::Disconnected)?;
+        Ok(Self { shared })
+    }
+
+    /// Instantiates the child model with *weights*, which map keys to data as WeightsLoader::read
+    /// returns it.
+    pub async fn instantiate(
+        &self,
+        weights: HashMap<String, bytes::Bytes>,
+    ) -> Result<ChildInstantiated, ChildModelError> {
+        let id = self.shared.next_id().to_string();
+        let instantiated_model_id = format!("instantiated-{}", self.shared.next_id());
+        let weights: HashMap<String, (String, bytes::Bytes)> = weights
+            .into_iter()
+            .map(|(key, data)| (format!("{id}/{key}"), (key, data)))
+            .collect();
+
+        let command = host_protocol::CommandMessage::CallInstantiateModel {
+            id: id.clone(),
+            instantiated_model_id: instantiated_model_id.clone(),
+            weights: weights
+                .iter()
+                .map(|(dataset, (key, data))| host_protocol::Param {
+                    name: key.clone(),
+                    dataset: dataset.clone(),
+                    amount: 1,
+                    total_byte_size: data.len() as u64,
+                    local_file: None,
+                })
+                .collect(),
+            other_models: vec![],
+        };
+        let dataset_sizes = weights.keys().map(|dataset| (dataset.clone(), 1)).collect();
+        let weights = &weights;
+        self.shared
+            .call(id, command, dataset_sizes, |dataset, indices| async move {
+                let data = &weights[&dataset].1;
+                Ok(indices.iter().map(|_| data.clone()).collect())
+            })
+            .await?;
+
+        Ok(ChildInstantiated {
+            shared: self.shared.clone(),
+            instantiated_model_id,
+            disposed: AtomicBool::new(false),
+        })
+    }
+}
+
+/// A model instantiated in a ChildModel. Evaluating sends the data of the params to the child
+/// process, and decodes the outputs that it returns. The instantiated model is disposed in the
+/// child when this is dropped, unless try_dispose has been called. Dropping it does not require an
+/// async runtime.
+pub struct ChildInstantiated {
+    shared: Arc<Shared>,
+    instantiated_model_id: String,
+    disposed: AtomicBool,
+}
+
+impl ChildInstantiated {
+    /// Disposes the instantiated model in the child, and waits until the child has finished its
+    /// calls to it and called Instantiated::dispose. Does nothing if it has already been disposed.
+    pub async fn try_dispose(&self) -> Result<(), ChildModelError> {
+        if self.disposed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let (disposed_tx, disposed_rx) = asyncs::oneshot::channel();
+        self.shared
+            .disposals
+            .lock()
+            .unwrap()
+            .insert(self.instantiated_model_id.clone(), disposed_tx);
+        self.shared
+            .tx
+            .send(MessageToChild::Command(
+                host_protocol::CommandMessage::CallDisposeInstantiatedModel {
+                    instantiated_model_id: self.instantiated_model_id.clone(),
+                },
+            ))
+            .await
+            .map_err(|_| ChildModelError::Disconnected)?;
+        match disposed_rx.await {
+            Ok(None) => Ok(()),
+            Ok(Some(error)) => Err(error.into()),
+            Err(_) => Err(ChildModelError::Disconnected),
+        }
+    }
+
+    /// Same as Instantiated::evaluate, but returns an error instead of panicking.
+    pub async fn try_evaluate(
+        &self,
+        options: EvaluateOptions<impl DataLoader>,
+    ) -> Result<Vec<EvaluateOutput>, ChildModelError> {
+        let id = self.shared.next_id().to_string();
+        let params: HashMap<String, (String, _)> = options
+            .params
+            .into_iter()
+            .map(|(name, data_loader)| (format!("{id}/{name}"), (name, data_loader)))
+            .collect();
+
+        let command = host_protocol::CommandMessage::CallEvaluate {
+            id: id.clone(),
+            instantiated_model_id: self.instantiated_model_id.clone(),
+            params: params
+                .iter()
+                .map(|(dataset, (name, data_loader))| host_protocol::Param {
+                    name: name.clone(),
+                    dataset: dataset.clone(),
+                    amount: data_loader.size(),
+                    total_byte_size: data_loader.total_byte_size(),
+                    local_file: None,
+                })
+                .collect(),
+            expected_output_types: options
+                .expected_output_types
+                .into_iter()
+                .map(|(name, x)| DecthingsParameterDefinition {
+                    name,
+                    required: x.required,
+                    rules: x.rules,
+                })
+                .collect(),
+        };
+        let dataset_sizes = params
+            .iter()
+            .map(|(dataset, (_, data_loader))| (dataset.clone(), data_loader.size()))
+            .collect();
+        let params = &params;
+        let (result, blobs) = self
+            .shared
+            .call(id, command, dataset_sizes, |dataset, indices| async move {
+                let data = params[&dataset]
+                    .1
+                    .try_read_indices(&indices)
+                    .await
+                    .map_err(ChildModelError::DataLoader)?;
+                Ok(data.into_iter().map(|x| x.serialize()).collect())
+            })
+            .await?;
+
+        // All outputs are concatenated into a single blob.
+        let data = blobs.into_iter().next().unwrap_or_default();
+        let mut offset = 0;
+        let mut outputs = vec![];
+        for output in result.outputs.unwrap_or_default() {
+            let mut tensors = Vec::with_capacity(output.byte_sizes.len());
+            for byte_size in output.byte_sizes {
+                let end = offset + byte_size as usize;
+                if end > data.len() {
+                    return Err(ChildModelError::InvalidOutput(
+                        "The outputs are larger than the returned data.".to_owned(),
+                    ));
+                }
+                let tensor =
+                    OwnedDecthingsTensor::from_bytes(data.slice(offset..end)).map_err(|error| {
+                        ChildModelError::InvalidOutput(format!(
+                            r#"Failed to decode output "{}" as a tensor: {error:?}"#,
+                            output.name
+                        ))
+                    })?;
+                tensors.push(tensor);
+                offset = end;
+            }
+            outputs.push(EvaluateOutput {
+                name: output.name,
+                data: tensors,
+            });
+        }
+        Ok(outputs)
+    }
+}
+
+impl Instantiated for ChildInstantiated {
+    fn evaluate<'a>(
+        &'a self,
+        options: EvaluateOptions<impl DataLoader + 'a>,
+    ) -> BoxFuture<'a, Vec<EvaluateOutput>> {
+        Box::pin(async move {
+            self.try_evaluate(options)
+                .await
+                .unwrap_or_else(|e| panic!("ChildModel: {e}"))
+        })
+    }
+
+    fn dispose(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.try_dispose()
+                .await
+                .unwrap_or_else(|e| panic!("ChildModel: {e}"))
+        })
+    }
+}
+
+impl Drop for ChildInstantiated {
+    fn drop(&mut self) {
+        if *self.disposed.get_mut() {
+            return;
+        }
+        let msg = MessageToChild::Command(
+            host_protocol::CommandMessage::CallDisposeInstantiatedModel {
+                instantiated_model_id: std::mem::take(&mut self.instantiated_model_id),
+            },
+        );
+        // Sending is only awaited if the channel is full, since this may not run in an async
+        // context otherwise. Outside of a runtime, this blocks until the writer has room.
+        if let Err(e) = self.shared.tx.try_send(msg) {
+            let tx = self.shared.tx.clone();
+            let msg = e.into_inner();
+            asyncs::spawn_or_block_on(async move {
+                tx.send(msg).await.ok();
+            });
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+
+/// Options for caching the data that the host provides to data loaders. With the cache enabled,
+/// data points that have been read once are served locally, so that second and later epochs do not
+/// have to fetch them from the host again.
+#[derive(Clone, Debug)]
+pub struct DataCacheOptions {
+    /// The maximum number of bytes kept in memory. When exceeded, the least recently used data
+    /// points are moved to the disk tier, or discarded if there is no disk tier.
+    pub memory_budget: u64,
+    /// A directory in which data points that do not fit in memory are stored. A subdirectory is
+    /// created for this process, and removed when the connection to the host is closed.
+    pub disk_directory: Option<PathBuf>,
+    /// The maximum number of bytes stored in the disk tier. When exceeded, the oldest data points
+    /// are removed.
+    pub disk_budget: u64,
+}
+
+impl Default for DataCacheOptions {
+    fn default() -> Self {
+        Self {
+            memory_budget: 1 << 30,
+            disk_directory: None,
+            disk_budget: u64::MAX,
+        }
+    }
+}
+
+type Key = (String, u32);
+
+struct MemoryTier {
+    entries: HashMap<Key, (Bytes, u64)>,
+    /// Maps the tick of the last use of each entry to its key, oldest first.
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: u64,
+}
+
+struct DiskTier {
+    directory: PathBuf,
+    entries: HashMap<Key, u64>,
+    order: VecDeque<Key>,
+    bytes: u64,
+    /// Set by DataCache::clear.
+    closed: bool,
+}
+
+pub(super) struct DataCache {
+    memory_budget: u64,
+    disk_budget: u64,
+    memory: Mutex<MemoryTier>,
+    disk: Option<Mutex<DiskTier>>,
+}
+
+impl DataCache {
+    pub fn new(options: DataCacheOptions) -> Self {
+        let disk = options.disk_directory.map(|directory| {
+            let directory = directory.join(format!("decthings-data-cache-{}", std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap_or_else(|e| {
+                panic!(
+                    "Failed to create the data cache directory {}: {e}",
+                    directory.display()
+                )
+            });
+            Mutex::new(DiskTier {
+                directory,
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+                closed: false,
+            })
+        });
+        Self {
+            memory_budget: options.memory_budget,
+            disk_budget: options.disk_budget,
+            memory: Mutex::new(MemoryTier {
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+            }),
+            disk,
+        }
+    }
+
+    /// Returns the cached data point, or None if it is not cached. Data points found in the disk
+    /// tier are moved back to memory.
+    pub async fn get(&self, dataset: &str, index: u32) -> Option<Bytes> {
+        let key = (dataset.to_owned(), index);
+        {
+            let mut memory = self.memory.lock().unwrap();
+            memory.tick += 1;
+            let tick = memory.tick;
+            if let Some((data, last_used)) = memory.entries.get_mut(&key) {
+                let data = data.clone();
+                let previous = std::mem::replace(last_used, tick);
+                memory.lru.remove(&previous);
+                memory.lru.insert(tick, key);
+                return Some(data);
+            }
+        }
+
+        let path = {
+            let disk = self.disk.as_ref()?.lock().unwrap();
+            if !disk.entries.contains_key(&key) {
+                return None;
+            }
+            disk.path(&key)
+        };
+        let data = Bytes::from(super::asyncs::fs::read(path).await.ok()?);
+        self.insert(dataset, index, data.clone()).await;
+        Some(data)
+    }
+
+    /// Adds a data point to the memory tier, spilling the least recently used data points to disk
+    /// if the memory budget is exceeded.
+    pub async fn insert(&self, dataset: &str, index: u32, data: Bytes) {
+        let key = (dataset.to_owned(), index);
+        let mut evicted = vec![];
+        {
+            let mut memory = self.memory.lock().unwrap();
+            if memory.entries.contains_key(&key) {
+                return;
+            }
+            if data.len() as u64 > self.memory_budget {
+                // Too large for the memory tier, so go straight to disk.
+                evicted.push((key, data));
+            } else {
+                memory.tick += 1;
+                let tick = memory.tick;
+                memory.bytes += data.len() as u64;
+                memory.lru.insert(tick, key.clone());
+                memory.entries.insert(key, (data, tick));
+            }
+            while memory.bytes > self.memory_budget {
+                let Some((_, key)) = memory.lru.pop_first() else {
+                    break;
+                };
+                if let Some((data, _)) = memory.entries.remove(&key) {
+                    memory.bytes -= data.len() as u64;
+                    evicted.push((key, data));
+                }
+            }
+        }
+
+        if let Some(disk) = &self.disk {
+            for (key, data) in evicted {
+                self.spill(disk, key, data).await;
+            }
+        }
+    }
+
+    async fn spill(&self, disk: &Mutex<DiskTier>, key: Key, data: Bytes) {
+        let len = data.len() as u64;
+        if len > self.disk_budget {
+            return;
+        }
+        let (path, removed) = {
+            let mut disk = disk.lock().unwrap();
+            if disk.closed || disk.entries.contains_key(&key) {
+                return;
+            }
+            let mut removed = vec![];
+            while disk.bytes + len > self.disk_budget {
+                let Some(oldest) = disk.order.pop_front() else {
+                    break;
+                };
+                if let Some(size) = disk.entries.remove(&oldest) {
+                    disk.bytes -= size;
+                    removed.push(disk.path(&oldest));
+                }
+            }
+            disk.entries.insert(key.clone(), len);
+            disk.order.push_back(key.clone());
+            disk.bytes += len;
+            (disk.path(&key), removed)
+        };
+        for path in removed {
+            super::asyncs::fs::remove_file(path).await.ok();
+        }
+        if super::asyncs::fs::write(&path, &data).await.is_err() {
+            // The cache is best effort, so a failed write only means that the data point will be
+            // fetched from the host again.
+            let mut disk = disk.lock().unwrap();
+            if disk.entries.remove(&key).is_some() {
+                disk.bytes -= len;
+            }
+        }
+    }
+}
+
+impl DiskTier {
+    fn path(&self, (dataset, index): &Key) -> PathBuf {
+        // Hex-encode the dataset ID so that any ID is a valid file name.
+        let dataset: String = dataset.bytes().map(|b| format!("{b:02x}")).collect();
+        self.directory.join(format!("{dataset}-{index}"))
+    }
+}
+
+impl DataCache {
+    /// Removes all data points, and the directory of the disk tier. Data points inserted later are
+    /// only kept in memory.
+    pub fn clear(&self) {
+        let mut memory = self.memory.lock().unwrap();
+        memory.entries.clear();
+        memory.lru.clear();
+        memory.bytes = 0;
+        drop(memory);
+        if let Some(disk) = &self.disk {
+            let mut disk = disk.lock().unwrap();
+            disk.entries.clear();
+            disk.order.clear();
+            disk.bytes = 0;
+            // Nothing more is written once the directory is gone.
+            disk.closed = true;
+            std::fs::remove_dir_all(&disk.directory).ok();
+        }
+    }
+}
+
+impl Drop for DataCache {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unix::asyncs::block_on;
+
+    fn data(byte: u8, len: usize) -> Bytes {
+        Bytes::from(vec![byte; len])
+    }
+
+    /// A directory for the disk tier that is not shared with other tests.
+    fn directory(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("decthings-test-{name}-{}", std::process::id()))
+    }
+
+    fn cache(memory_budget: u64, disk: Option<(&str, u64)>) -> DataCache {
+        DataCache::new(DataCacheOptions {
+            memory_budget,
+            disk_directory: disk.map(|(name, _)| directory(name)),
+            disk_budget: disk.map_or(u64::MAX, |(_, budget)| budget),
+        })
+    }
+
+    fn disk_keys(cache: &DataCache) -> Vec<Key> {
+        cache
+            .disk
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .order
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn memory_evicts_least_recently_used() {
+        block_on(async {
+            let cache = cache(8, None);
+            cache.insert("d", 0, data(0, 4)).await;
+            cache.insert("d", 1, data(1, 4)).await;
+            assert_eq!(cache.get("d", 0).await, Some(data(0, 4)));
+            cache.insert("d", 2, data(2, 4)).await;
+            assert_eq!(cache.get("d", 1).await, None);
+            assert_eq!(cache.get("d", 0).await, Some(data(0, 4)));
+            assert_eq!(cache.get("d", 2).await, Some(data(2, 4)));
+            assert_eq!(cache.get("other", 0).await, None);
+        });
+    }
+
+    #[test]
+    fn spills_to_disk_and_reads_back() {
+        block_on(async {
+            let cache = cache(4, Some(("spill", u64::MAX)));
+            cache.insert("d", 0, data(0, 4)).await;
+            cache.insert("d", 1, data(1, 4)).await;
+            assert_eq!(disk_keys(&cache), [("d".to_owned(), 0)]);
+            let path = cache
+                .disk
+                .as_ref()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .path(&("d".to_owned(), 0));
+            assert_eq!(std::fs::read(path).unwrap(), vec![0; 4]);
+
+            // Reading from disk moves the data point back to memory, which spills the other one.
+            assert_eq!(cache.get("d", 0).await, Some(data(0, 4)));
+            assert_eq!(cache.get("d", 1).await, Some(data(1, 4)));
+
+            // Data points larger than the memory budget go straight to disk.
+            cache.insert("d", 2, data(2, 10)).await;
+            assert!(disk_keys(&cache).contains(&("d".to_owned(), 2)));
+            assert_eq!(cache.get("d", 2).await, Some(data(2, 10)));
+        });
+        remove_directory("spill");
+    }
+
+    #[test]
+    fn disk_removes_oldest_first() {
+        block_on(async {
+            let cache = cache(0, Some(("fifo", 8)));
+            cache.insert("d", 0, data(0, 4)).await;
+            cache.insert("d", 1, data(1, 4)).await;
+            cache.insert("d", 2, data(2, 4)).await;
+            assert_eq!(
+                disk_keys(&cache),
+                [("d".to_owned(), 1), ("d".to_owned(), 2)]
+            );
+            let files = std::fs::read_dir(
+                directory("fifo").join(format!("decthings-data-cache-{}", std::process::id())),
+            )
+            .unwrap()
+            .count();
+            assert_eq!(files, 2);
+            // Data points larger than the disk budget are not cached at all.
+            cache.insert("d", 3, data(3, 9)).await;
+            assert_eq!(cache.get("d", 3).await, None);
+        });
+        remove_directory("fifo");
+    }
+
+    #[test]
+    fn clear_and_drop_remove_directory() {
+        let subdirectory =
+            |name| directory(name).join(format!("decthings-data-cache-{}", std::process::id()));
+        block_on(async {
+            let cleared = cache(0, Some(("clear", u64::MAX)));
+            cleared.insert("d", 0, data(0, 4)).await;
+            assert!(subdirectory("clear").exists());
+            cleared.clear();
+            assert!(!subdirectory("clear").exists());
+            assert_eq!(cleared.get("d", 0).await, None);
+            // Nothing is written after the cache was cleared.
+            cleared.insert("d", 1, data(1, 4)).await;
+            assert!(!subdirectory("clear").exists());
+
+            let dropped = cache(0, Some(("drop", u64::MAX)));
+            assert!(subdirectory("drop").exists());
+            drop(dropped);
+            assert!(!subdirectory("drop").exists());
+        });
+        remove_directory("clear");
+        remove_directory("drop");
+    }
+
+    fn remove_directory(name: &str) {
+        std::fs::remove_dir_all(directory(name)).ok();
+    }
+}
@@ -6,12 +6,50 @@ use std::{
 };
 
 use crate::*;
-use futures::future::BoxFuture;
+use futures::{StreamExt, TryStreamExt, future::BoxFuture};
+
+/// Controls how data loaders fetch data from the host.
+#[derive(Clone, Debug)]
+pub struct DataRequestOptions {
+    /// Reads of more than this many data points are split into chunks that are requested
+    /// concurrently, and reassembled in order.
+    pub chunk_size: u32,
+    /// The maximum number of requests that a single data loader has in flight at once.
+    pub max_in_flight: usize,
+    /// The maximum number of bytes of requested data in flight at once, shared by all data
+    /// loaders. Reads are split into chunks that fit the budget, based on the average size of a
+    /// data point, and wait for earlier chunks to arrive while the budget is used up.
+    pub memory_budget: Option<u64>,
+    /// The maximum size in bytes of a single data point of a param. Larger data points are
+    /// discarded without being allocated, and the read fails with DataLoaderError::ElementTooLarge.
+    /// Weights are not limited.
+    pub max_element_size: Option<u64>,
+}
+
+impl Default for DataRequestOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 256,
+            max_in_flight: 4,
+            memory_budget: None,
+            max_element_size: None,
+        }
+    }
+}
+
+/// The provided data, and the buffer that it was read into, if any.
+type ProvidedData = (
+    Result<Vec<bytes::Bytes>, DataLoaderError>,
+    Option<bytes::BytesMut>,
+);
+
+type DataCallback = super::asyncs::oneshot::Sender<ProvidedData>;
 
 struct RequestData {
     start_index: u32,
     amount: u32,
-    cb: super::asyncs::oneshot::Sender<Vec<bytes::Bytes>>,
+    buffer: Option<bytes::BytesMut>,
+    cb: DataCallback,
 }
 
 pub struct DataLoaderImpl<'a> {
@@ -22,6 +60,197 @@ pub struct DataLoaderImpl<'a> {
     size: u32,
     total_byte_size: u64,
     position: u32,
+    timeout: Option<std::time::Duration>,
+    shuffle_seed: Mutex<Option<u64>>,
+    request_options: DataRequestOptions,
+    memory_budget: Option<Arc<super::asyncs::Semaphore>>,
+    cache: Option<Arc<super::datacache::DataCache>>,
+    /// When the cache is used, shuffling is done locally so that the host always sees the data in
+    /// its original order, and positions are mapped to indices through this permutation.
+    local_permutation: Mutex<Option<Vec<u32>>>,
+    /// Set for weights that the host has in a local file, see WeightsLoader::mmap.
+    local_file: Option<super::host_protocol::LocalFile>,
+}
+
+impl DataLoaderImpl<'_> {
+    /// Fetches a range of data points from the host, in concurrently requested chunks. If
+    /// *buffer* is given, its allocation is split into one part per chunk, and the data is read
+    /// into those parts.
+    async fn request(
+        &self,
+        start_index: u32,
+        amount: u32,
+        mut buffer: Option<&mut bytes::BytesMut>,
+    ) -> Result<Vec<bytes::Bytes>, DataLoaderError> {
+        let mut chunk_size = self.request_options.chunk_size.max(1);
+        if let Some(budget) = self.request_options.memory_budget {
+            let fitting = budget / self.average_element_size();
+            chunk_size = chunk_size.min(fitting.clamp(1, u32::MAX as u64) as u32);
+        }
+        let end = start_index + amount;
+        let chunks: Vec<(u32, u32)> = (start_index..end)
+            .step_by(chunk_size as usize)
+            .map(|start| (start, chunk_size.min(end - start)))
+            .collect();
+        let parts = match buffer.as_deref_mut() {
+            Some(buffer) => split_buffer(
+                buffer,
+                chunks
+                    .iter()
+                    .map(|&(_, amount)| self.average_element_size() * amount as u64),
+            ),
+            None => chunks.iter().map(|_| None).collect(),
+        };
+
+        let fut = async {
+            let mut chunks = futures::stream::iter(chunks.into_iter().zip(parts))
+                .map(|((start, amount), part)| self.request_chunk(start, amount, part))
+                .buffered(self.request_options.max_in_flight.max(1));
+            let mut res = Vec::with_capacity(amount as usize);
+            while let Some((data, part)) = chunks.try_next().await? {
+                // The remainder of the first part refers to the whole allocation, so keeping it
+                // lets the next read reuse the allocation.
+                if let Some(buffer) = buffer.take()
+                    && let Some(part) = part
+                {
+                    *buffer = part;
+                }
+                res.extend(data);
+            }
+            Ok(res)
+        };
+
+        match self.timeout {
+            Some(timeout) => super::asyncs::timeout(timeout, fut)
+                .await
+                .map_err(|_| DataLoaderError::Timeout)?,
+            None => fut.await,
+        }
+    }
+
+    async fn request_chunk(
+        &self,
+        start_index: u32,
+        amount: u32,
+        buffer: Option<bytes::BytesMut>,
+    ) -> Result<(Vec<bytes::Bytes>, Option<bytes::BytesMut>), DataLoaderError> {
+        // Held until the data has been provided.
+        let _permit = match &self.memory_budget {
+            Some(semaphore) => {
+                let estimate = (self.average_element_size() * amount as u64).div_ceil(1024);
+                let permits = estimate.clamp(1, budget_permits(&self.request_options));
+                Some(
+                    semaphore
+                        .acquire_many(permits as u32)
+                        .await
+                        .expect("The memory budget semaphore is never closed"),
+                )
+            }
+            None => None,
+        };
+
+        let (tx, rx) = super::asyncs::oneshot::channel();
+
+        self.request_data_tx
+            .send(RequestData {
+                start_index,
+                amount,
+                buffer,
+                cb: tx,
+            })
+            .await
+            .map_err(|_| DataLoaderError::Disconnected)?;
+
+        let (data, buffer) = rx.await.map_err(|_| DataLoaderError::Cancelled)?;
+        Ok((data?, buffer))
+    }
+
+    /// Reads a range of data points, from the cache if there is one and otherwise from the host.
+    /// Only data that is read from the host is placed in *buffer*.
+    async fn read_range_impl(
+        &self,
+        start_index: u32,
+        amount: u32,
+        buffer: Option<&mut bytes::BytesMut>,
+    ) -> Result<Vec<bytes::Bytes>, DataLoaderError> {
+        if start_index > self.size {
+            return Err(DataLoaderError::IndexOutOfRange {
+                index: start_index,
+                size: self.size,
+            });
+        }
+        let amount = amount.min(self.size - start_index);
+
+        if amount == 0 {
+            return Ok(vec![]);
+        }
+
+        match &self.cache {
+            Some(cache) => {
+                let indices: Vec<u32> = {
+                    let permutation = self.local_permutation.lock().unwrap();
+                    (start_index..start_index + amount)
+                        .map(|index| match &*permutation {
+                            Some(permutation) => permutation[index as usize],
+                            None => index,
+                        })
+                        .collect()
+                };
+                self.read_cached(cache, &indices).await
+            }
+            None => self.request(start_index, amount, buffer).await,
+        }
+    }
+
+    /// Fetches the weights, as they are stored, from the host.
+    async fn fetch_weights(&mut self) -> bytes::Bytes {
+        crate::DataLoaderBinary::set_position(self, 0);
+        crate::DataLoaderBinary::next(self, 1).await.remove(0)
+    }
+
+    fn average_element_size(&self) -> u64 {
+        self.total_byte_size
+            .div_ceil(self.size.max(1) as u64)
+            .max(1)
+    }
+
+    /// Reads the data points at the given unshuffled indices, fetching only those that are not
+    /// already cached.
+    async fn read_cached(
+        &self,
+        cache: &super::datacache::DataCache,
+        indices: &[u32],
+    ) -> Result<Vec<bytes::Bytes>, DataLoaderError> {
+        let mut res = Vec::with_capacity(indices.len());
+        let mut missing = vec![];
+        for &index in indices {
+            let data = cache.get(&self.dataset, index).await;
+            if data.is_none() {
+                missing.push(index);
+            }
+            res.push(data);
+        }
+        if missing.is_empty() {
+            return Ok(res.into_iter().flatten().collect());
+        }
+
+        let fetched =
+            crate::trait_def::read_indices_coalesced(&missing, self.size, |start, amount| {
+                Box::pin(self.request(start, amount, None))
+            })
+            .await?;
+        for (&index, data) in missing.iter().zip(&fetched) {
+            // Copied so that the cache does not keep the whole message buffer alive.
+            cache
+                .insert(&self.dataset, index, bytes::Bytes::copy_from_slice(data))
+                .await;
+        }
+        let mut fetched = fetched.into_iter();
+        Ok(res
+            .into_iter()
+            .map(|data| data.unwrap_or_else(|| fetched.next().unwrap()))
+            .collect())
+    }
 }
 
 impl<'a> DataLoaderBinary for DataLoaderImpl<'a> {
@@ -29,7 +258,19 @@ impl<'a> DataLoaderBinary for DataLoaderImpl<'a> {
         self.total_byte_size
     }
 
-    fn shuffle_in_group<'b>(&'b self, others: &'b [&'b Self]) -> BoxFuture<'b, ()> {
+    fn try_shuffle_in_group_with_seed<'b>(
+        &'b self,
+        others: &'b [&'b Self],
+        seed: u64,
+    ) -> BoxFuture<'b, Result<(), DataLoaderError>> {
+        if self.cache.is_some() {
+            for data_loader in [self].iter().chain(others) {
+                *data_loader.local_permutation.lock().unwrap() =
+                    Some(crate::shuffle_permutation(data_loader.size, seed));
+                *data_loader.shuffle_seed.lock().unwrap() = Some(seed);
+            }
+            return Box::pin(async { Ok(()) });
+        }
         let datasets: Vec<_> = [self]
             .iter()
             .chain(others)
@@ -39,11 +280,24 @@ impl<'a> DataLoaderBinary for DataLoaderImpl<'a> {
             self.sender
                 .send_data_event(super::host_protocol::DataEvent::Shuffle {
                     datasets: &datasets,
+                    seed,
                 })
-                .await;
+                .await
+                .map_err(|_| DataLoaderError::Disconnected)?;
+            for data_loader in [self].iter().chain(others) {
+                *data_loader.shuffle_seed.lock().unwrap() = Some(seed);
+            }
+            Ok(())
         })
     }
 
+    fn shuffle_state(&self) -> ShuffleState {
+        ShuffleState {
+            seed: *self.shuffle_seed.lock().unwrap(),
+            position: self.position,
+        }
+    }
+
     fn size(&self) -> u32 {
         self.size
     }
@@ -53,9 +307,9 @@ impl<'a> DataLoaderBinary for DataLoaderImpl<'a> {
     }
 
     fn set_position(&mut self, position: u32) {
-        if position >= self.size {
+        if position > self.size {
             panic!(
-                "DataLoader: Cannot set the the position to a value greater than or equal to the data size. The data size was {}, and position {} was attempted to be set.",
+                "DataLoader: Cannot set the the position to a value greater than the data size. The data size was {}, and position {} was attempted to be set.",
                 self.size, position
             );
         }
@@ -66,31 +320,25 @@ impl<'a> DataLoaderBinary for DataLoaderImpl<'a> {
         self.size - self.position
     }
 
-    fn next(&mut self, mut amount: u32) -> BoxFuture<'_, Vec<bytes::Bytes>> {
-        Box::pin(async move {
-            amount = amount.min(DataLoaderBinary::remaining(self));
-
-            if amount == 0 {
-                return vec![];
-            }
-
-            let prev_position = DataLoaderBinary::position(self);
-            self.position += amount;
-
-            let (tx, rx) = super::asyncs::oneshot::channel();
+    fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.timeout = timeout;
+    }
 
-            self.request_data_tx
-                .send(RequestData {
-                    start_index: prev_position,
-                    amount,
-                    cb: tx,
-                })
-                .await
-                .map_err(|_| ())
-                .unwrap();
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(self.read_range_impl(start_index, amount, None))
+    }
 
-            rx.await.unwrap()
-        })
+    fn try_read_range_into<'b>(
+        &'b self,
+        start_index: u32,
+        amount: u32,
+        buffer: &'b mut bytes::BytesMut,
+    ) -> BoxFuture<'b, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(self.read_range_impl(start_index, amount, Some(buffer)))
     }
 }
 
@@ -101,27 +349,127 @@ impl<'a> crate::WeightsLoader for DataLoaderImpl<'a> {
 
     fn read(&mut self) -> BoxFuture<'_, bytes::Bytes> {
         Box::pin(async {
-            crate::DataLoaderBinary::set_position(self, 0);
-            crate::DataLoaderBinary::next(self, 1).await.remove(0)
+            crate::WeightsLoader::try_read(self)
+                .await
+                .unwrap_or_else(|e| panic!("WeightsLoader: {e}"))
+        })
+    }
+
+    fn try_read(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async {
+            let data = self.fetch_weights().await;
+            crate::decompress_weights(data)
+        })
+    }
+
+    fn mmap(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        Box::pin(async {
+            crate::WeightsLoader::try_mmap(self)
+                .await
+                .unwrap_or_else(|e| panic!("WeightsLoader: {e}"))
         })
     }
+
+    fn try_mmap(&mut self) -> BoxFuture<'_, Result<bytes::Bytes, crate::WeightsError>> {
+        Box::pin(async { crate::decompress_weights(crate::WeightsLoader::read_raw(self).await) })
+    }
+
+    fn read_raw(&mut self) -> BoxFuture<'_, bytes::Bytes> {
+        Box::pin(async {
+            let mapped = self
+                .local_file
+                .as_ref()
+                .and_then(|file| map_local_file(file, self.total_byte_size));
+            match mapped {
+                Some(data) => data,
+                None => self.fetch_weights().await,
+            }
+        })
+    }
+}
+
+/// Maps *length* bytes of the local file read-only. Returns None if the file cannot be opened or is
+/// shorter than expected, since accessing a mapping past the end of the file is a fatal error.
+fn map_local_file(file: &super::host_protocol::LocalFile, length: u64) -> Option<bytes::Bytes> {
+    if length == 0 {
+        return Some(bytes::Bytes::new());
+    }
+    let f = std::fs::File::open(&file.path).ok()?;
+    if f.metadata().ok()?.len() < file.offset.checked_add(length)? {
+        return None;
+    }
+    // Safety: The mapping is read-only. The host must not modify the file while it is in use,
+    // just as it must not modify the weights that it sends.
+    let mmap = unsafe {
+        memmap2::MmapOptions::new()
+            .offset(file.offset)
+            .len(length.try_into().ok()?)
+            .map(&f)
+    }
+    .ok()?;
+    Some(bytes::Bytes::from_owner(mmap))
+}
+
+/// Splits the allocation of *buffer* into parts of the given sizes, after discarding its content.
+fn split_buffer(
+    buffer: &mut bytes::BytesMut,
+    sizes: impl Iterator<Item = u64> + Clone,
+) -> Vec<Option<bytes::BytesMut>> {
+    buffer.clear();
+    buffer.reserve(sizes.clone().sum::<u64>().try_into().unwrap_or(usize::MAX));
+    let mut rest = std::mem::take(buffer);
+    sizes
+        .map(|size| {
+            let at = (size as usize).min(rest.capacity());
+            let next = rest.split_off(at);
+            Some(std::mem::replace(&mut rest, next))
+        })
+        .collect()
+}
+
+/// The memory budget in permits of one KiB each.
+fn budget_permits(options: &DataRequestOptions) -> u64 {
+    options
+        .memory_budget
+        .map_or(0, |budget| budget.div_ceil(1024).clamp(1, u32::MAX as u64))
+}
+
+struct WaitingRequest {
+    cb: DataCallback,
+    /// See host_protocol::ProvideDataTarget::buffer.
+    buffer: Option<bytes::BytesMut>,
+    /// See DataRequestOptions::max_element_size. Not set for weights.
+    max_element_size: Option<u64>,
 }
 
 struct Requests {
-    waiting: HashMap<u32, super::asyncs::oneshot::Sender<Vec<bytes::Bytes>>>,
+    waiting: HashMap<u32, WaitingRequest>,
     id_counter: u32,
 }
 
 #[derive(Clone)]
 pub(super) struct DataLoaderManager {
     sender: super::host_protocol::Sender,
+    request_options: DataRequestOptions,
+    memory_budget: Option<Arc<super::asyncs::Semaphore>>,
+    cache: Option<Arc<super::datacache::DataCache>>,
     requests: Arc<Mutex<Requests>>,
 }
 
 impl DataLoaderManager {
-    pub fn new(sender: super::host_protocol::Sender) -> Self {
+    pub fn new(sender: super::host_protocol::Sender, options: &super::RunModelOptions) -> Self {
         Self {
             sender,
+            request_options: options.data_requests.clone(),
+            memory_budget: options.data_requests.memory_budget.map(|_| {
+                Arc::new(super::asyncs::Semaphore::new(
+                    budget_permits(&options.data_requests) as usize,
+                ))
+            }),
+            cache: options
+                .data_cache
+                .clone()
+                .map(|options| Arc::new(super::datacache::DataCache::new(options))),
             requests: Arc::new(Mutex::new(Requests {
                 waiting: HashMap::new(),
                 id_counter: 0,
@@ -129,12 +477,37 @@ impl DataLoaderManager {
         }
     }
 
-    pub fn provide_data(&self, request_id: u32, data: Vec<bytes::Bytes>) {
+    /// Returns how the data provided for *request_id* is to be read. The buffer is taken from the
+    /// request, and is handed back with provide_data.
+    pub fn provide_data_target(&self, request_id: u32) -> super::host_protocol::ProvideDataTarget {
+        match self.requests.lock().unwrap().waiting.get_mut(&request_id) {
+            Some(waiting) => super::host_protocol::ProvideDataTarget {
+                max_element_size: waiting.max_element_size,
+                buffer: waiting.buffer.take(),
+            },
+            None => Default::default(),
+        }
+    }
+
+    pub fn provide_data(
+        &self,
+        request_id: u32,
+        data: Result<Vec<bytes::Bytes>, DataLoaderError>,
+        buffer: Option<bytes::BytesMut>,
+    ) {
         let mut requests = self.requests.lock().unwrap();
         let waiting = requests.waiting.remove(&request_id);
 
         if let Some(waiting) = waiting {
-            waiting.send(data).ok();
+            waiting.cb.send((data, buffer)).ok();
+        }
+    }
+
+    /// Removes the disk tier of the data cache, if any. Called when the connection to the host is
+    /// closed.
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
         }
     }
 
@@ -143,11 +516,14 @@ impl DataLoaderManager {
         dataset: String,
         size: u32,
         total_byte_size: u64,
+        cache: Option<Arc<super::datacache::DataCache>>,
+        local_file: Option<super::host_protocol::LocalFile>,
+        max_element_size: Option<u64>,
     ) -> (
         impl DataLoaderBinary + WeightsLoader + 'static,
         impl Future<Output = ()> + Send + 'static,
     ) {
-        let (tx, mut rx) = super::asyncs::channel(1);
+        let (tx, mut rx) = super::asyncs::channel(self.request_options.max_in_flight.max(1));
 
         let sender = self.sender.clone();
         let requests = self.requests.clone();
@@ -161,6 +537,13 @@ impl DataLoaderManager {
                 size,
                 total_byte_size,
                 position: 0,
+                timeout: None,
+                shuffle_seed: Mutex::new(None),
+                request_options: self.request_options.clone(),
+                memory_budget: self.memory_budget.clone(),
+                cache,
+                local_permutation: Mutex::new(None),
+                local_file,
             },
             async move {
                 while let Some(request) = super::asyncs::channel_recv(&mut rx).await {
@@ -169,12 +552,19 @@ impl DataLoaderManager {
 
                         let request_id = requests.id_counter;
                         requests.id_counter += 1;
-                        requests.waiting.insert(request_id, request.cb);
+                        requests.waiting.insert(
+                            request_id,
+                            WaitingRequest {
+                                cb: request.cb,
+                                buffer: request.buffer,
+                                max_element_size,
+                            },
+                        );
 
                         request_id
                     };
 
-                    sender
+                    let sent = sender
                         .send_data_event(super::host_protocol::DataEvent::RequestData {
                             request_id,
                             dataset: &dataset,
@@ -182,6 +572,10 @@ impl DataLoaderManager {
                             amount: request.amount,
                         })
                         .await;
+                    if sent.is_err() {
+                        // Dropping the callback makes the waiting read fail.
+                        requests.lock().unwrap().waiting.remove(&request_id);
+                    }
                 }
             },
         )
@@ -196,17 +590,260 @@ impl DataLoaderManager {
         impl DataLoaderBinary + 'static,
         impl Future<Output = ()> + Send + 'static,
     ) {
-        self.do_create_data_loader(dataset, size, total_byte_size)
+        self.do_create_data_loader(
+            dataset,
+            size,
+            total_byte_size,
+            self.cache.clone(),
+            None,
+            self.request_options.max_element_size,
+        )
     }
 
     pub fn create_weights_loader(
         &self,
         dataset: String,
         byte_size: u64,
+        local_file: Option<super::host_protocol::LocalFile>,
     ) -> (
         impl WeightsLoader + 'static,
         impl Future<Output = ()> + Send + 'static,
     ) {
-        self.do_create_data_loader(dataset, 1, byte_size)
+        // Weights are only read once, so they are not cached. The element size limit is meant for
+        // the data of params, so it does not apply to weights.
+        self.do_create_data_loader(dataset, 1, byte_size, None, local_file, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unix::RunModelOptions;
+    use crate::unix::host_protocol::{self, MessageFromHost, MessageFromModel};
+
+    /// The requests seen by FakeHost. Requests are answered in batches, once no more requests
+    /// arrive, so the length of a batch is the number of requests that were in flight at once.
+    #[derive(Default)]
+    struct Seen {
+        batches: Vec<Vec<(u32, u32)>>,
+        shuffles: usize,
+    }
+
+    /// Data point *index* of every dataset is *element_size* bytes of the value index.
+    fn element(index: u32, element_size: usize) -> bytes::Bytes {
+        bytes::Bytes::from(vec![index as u8; element_size])
+    }
+
+    /// Runs *f* against a DataLoaderManager connected to a fake host, and returns its output
+    /// together with the requests that the host saw.
+    fn with_fake_host<T>(
+        options: RunModelOptions,
+        element_size: usize,
+        f: impl AsyncFnOnce(&DataLoaderManager) -> T,
+    ) -> (T, Seen) {
+        crate::unix::asyncs::block_on(async move {
+            let (model_side, mut host_side) = tokio::io::duplex(1 << 16);
+            let (sender, send_fut) = host_protocol::Sender::new(model_side);
+            crate::unix::asyncs::spawn(send_fut);
+            let manager = DataLoaderManager::new(sender, &options);
+
+            let seen = Arc::new(Mutex::new(Seen::default()));
+            let host_manager = manager.clone();
+            let host_seen = seen.clone();
+            let host = crate::unix::asyncs::spawn(async move {
+                let mut in_flight: Vec<(u32, u32, u32)> = vec![];
+                let mut blob_buffer = host_protocol::BlobBuffer::default();
+                loop {
+                    let read = host_protocol::read_message_from_model(&mut host_side);
+                    let msg = if in_flight.is_empty() {
+                        Some(read.await)
+                    } else {
+                        crate::unix::asyncs::timeout(std::time::Duration::from_millis(50), read)
+                            .await
+                            .ok()
+                    };
+                    match msg {
+                        Some(Ok(MessageFromModel::DataEvent(event))) => {
+                            let event: serde_json::Value = serde_json::from_slice(&event).unwrap();
+                            let field = |name: &str| event[name].as_u64().unwrap() as u32;
+                            match event["event"].as_str().unwrap() {
+                                "requestData" => in_flight.push((
+                                    field("requestId"),
+                                    field("startIndex"),
+                                    field("amount"),
+                                )),
+                                _ => host_seen.lock().unwrap().shuffles += 1,
+                            }
+                        }
+                        Some(Ok(MessageFromModel::ResultOrEvent(..))) => {}
+                        Some(Err(_)) => break,
+                        None => {
+                            in_flight.sort_by_key(|&(_, start_index, _)| start_index);
+                            host_seen.lock().unwrap().batches.push(
+                                in_flight
+                                    .iter()
+                                    .map(|&(_, start_index, amount)| (start_index, amount))
+                                    .collect(),
+                            );
+                            // Answered in reverse order, so that the chunks must be reassembled.
+                            // The data goes through the same functions as that from a real host.
+                            for (request_id, start_index, amount) in in_flight.drain(..).rev() {
+                                let data: Vec<_> = (start_index..start_index + amount)
+                                    .map(|index| element(index, element_size))
+                                    .collect();
+                                let mut msg = vec![];
+                                host_protocol::write_provide_data(&mut msg, request_id, &data)
+                                    .await
+                                    .unwrap();
+                                let read = host_protocol::read_message_from_host(
+                                    msg.as_slice(),
+                                    &mut blob_buffer,
+                                    |request_id| host_manager.provide_data_target(request_id),
+                                );
+                                let Ok(MessageFromHost::ProvideData(request_id, data, buffer)) =
+                                    read.await
+                                else {
+                                    panic!("Expected provided data");
+                                };
+                                host_manager.provide_data(request_id, data, buffer);
+                            }
+                        }
+                    }
+                }
+            });
+
+            let res = f(&manager).await;
+            host.abort();
+            let seen = std::mem::take(&mut *seen.lock().unwrap());
+            (res, seen)
+        })
+    }
+
+    fn options(data_requests: DataRequestOptions) -> RunModelOptions {
+        RunModelOptions {
+            data_cache: None,
+            data_requests,
+        }
+    }
+
+    #[test]
+    fn reads_are_split_into_chunks() {
+        let options = options(DataRequestOptions {
+            chunk_size: 3,
+            ..Default::default()
+        });
+        let (data, seen) = with_fake_host(options, 4, async |manager| {
+            let (loader, serve) = manager.create_data_loader("a".to_string(), 10, 40);
+            crate::unix::asyncs::spawn(serve);
+            DataLoaderBinary::read_range(&loader, 0, 10).await
+        });
+        let expected: Vec<_> = (0..10).map(|index| element(index, 4)).collect();
+        assert_eq!(data, expected);
+        assert_eq!(seen.batches, vec![vec![(0, 3), (3, 3), (6, 3), (9, 1)]]);
+    }
+
+    #[test]
+    fn requests_in_flight_are_limited() {
+        let options = options(DataRequestOptions {
+            chunk_size: 2,
+            max_in_flight: 2,
+            ..Default::default()
+        });
+        let (data, seen) = with_fake_host(options, 4, async |manager| {
+            let (loader, serve) = manager.create_data_loader("a".to_string(), 10, 40);
+            crate::unix::asyncs::spawn(serve);
+            DataLoaderBinary::read_range(&loader, 0, 10).await
+        });
+        let expected: Vec<_> = (0..10).map(|index| element(index, 4)).collect();
+        assert_eq!(data, expected);
+        assert_eq!(
+            seen.batches,
+            vec![vec![(0, 2), (2, 2)], vec![(4, 2), (6, 2)], vec![(8, 2)]]
+        );
+    }
+
+    #[test]
+    fn memory_budget_limits_chunks_and_is_shared() {
+        // Data points of 1 KiB and a budget of 2 KiB, so that chunks are two data points and only
+        // one of them is in flight at once, across both data loaders.
+        let options = options(DataRequestOptions {
+            max_in_flight: 4,
+            memory_budget: Some(2048),
+            ..Default::default()
+        });
+        let ((a, b), seen) = with_fake_host(options, 1024, async |manager| {
+            let (a, serve_a) = manager.create_data_loader("a".to_string(), 6, 6 * 1024);
+            let (b, serve_b) = manager.create_data_loader("b".to_string(), 6, 6 * 1024);
+            crate::unix::asyncs::spawn(serve_a);
+            crate::unix::asyncs::spawn(serve_b);
+            futures::join!(
+                DataLoaderBinary::read_range(&a, 0, 6),
+                DataLoaderBinary::read_range(&b, 0, 6)
+            )
+        });
+        let expected: Vec<_> = (0..6).map(|index| element(index, 1024)).collect();
+        assert_eq!(a, expected);
+        assert_eq!(b, expected);
+        assert_eq!(seen.batches.len(), 6);
+        for batch in &seen.batches {
+            assert_eq!(batch.len(), 1);
+            assert_eq!(batch[0].1, 2);
+        }
+    }
+
+    #[test]
+    fn cached_data_is_shuffled_locally() {
+        let options = RunModelOptions {
+            data_cache: Some(Default::default()),
+            data_requests: Default::default(),
+        };
+        let ((first, second), seen) = with_fake_host(options, 4, async |manager| {
+            let (loader, serve) = manager.create_data_loader("a".to_string(), 10, 40);
+            crate::unix::asyncs::spawn(serve);
+            DataLoaderBinary::shuffle_with_seed(&loader, 5).await;
+            let first = DataLoaderBinary::read_range(&loader, 0, 10).await;
+            DataLoaderBinary::shuffle_with_seed(&loader, 6).await;
+            let second = DataLoaderBinary::read_range(&loader, 0, 10).await;
+            (first, second)
+        });
+        let shuffled = |seed| -> Vec<_> {
+            crate::shuffle_permutation(10, seed)
+                .into_iter()
+                .map(|index| element(index, 4))
+                .collect()
+        };
+        assert_eq!(first, shuffled(5));
+        assert_eq!(second, shuffled(6));
+        // The host is asked for the data once, in its original order, and never shuffles.
+        assert_eq!(seen.batches, vec![vec![(0, 10)]]);
+        assert_eq!(seen.shuffles, 0);
+    }
+
+    #[test]
+    fn read_range_into_reads_into_the_buffer() {
+        let options = options(DataRequestOptions {
+            chunk_size: 4,
+            ..Default::default()
+        });
+        let ((first, second, start), seen) = with_fake_host(options, 4, async |manager| {
+            let (loader, serve) = manager.create_data_loader("a".to_string(), 10, 40);
+            crate::unix::asyncs::spawn(serve);
+            let mut buffer = bytes::BytesMut::with_capacity(40);
+            let start = buffer.as_ptr() as usize;
+            let first = DataLoaderBinary::read_range_into(&loader, 0, 10, &mut buffer).await;
+            let first_ptrs: Vec<_> = first.iter().map(|x| x.as_ptr() as usize).collect();
+            drop(first);
+            let second = DataLoaderBinary::read_range_into(&loader, 0, 10, &mut buffer).await;
+            (first_ptrs, second, start)
+        });
+        let expected: Vec<_> = (0..10).map(|index| element(index, 4)).collect();
+        assert_eq!(second, expected);
+        assert_eq!(seen.batches.len(), 2);
+        // Every chunk was read into its own part of the buffer, and the buffer was reused by the
+        // second read.
+        let ptrs: Vec<_> = (0..10).map(|index| start + 4 * index).collect();
+        assert_eq!(first, ptrs);
+        let second: Vec<_> = second.iter().map(|x| x.as_ptr() as usize).collect();
+        assert_eq!(second, ptrs);
     }
 }
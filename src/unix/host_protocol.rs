@@ -4,16 +4,29 @@ use serde::ser::SerializeSeq;
 
 use super::asyncs::{AsyncReadExt, AsyncWriteExt};
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Param {
     pub name: String,
     pub dataset: String,
     pub amount: u32,
     pub total_byte_size: u64,
+    /// Set by the host when the data is available in a local file, so that weights can be memory
+    /// mapped instead of sent over the socket.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_file: Option<LocalFile>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalFile {
+    pub path: String,
+    /// The byte offset of the data within the file. The length is the total byte size of the
+    /// param.
+    pub offset: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OtherModelWithWeights {
     pub id: String,
@@ -21,7 +34,7 @@ pub struct OtherModelWithWeights {
     pub weights: Vec<Param>,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OtherModel {
     pub id: String,
@@ -29,7 +42,7 @@ pub struct OtherModel {
 }
 
 #[allow(clippy::enum_variant_names)]
-#[derive(serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[serde(tag = "method", content = "params", rename_all = "camelCase")]
 pub enum CommandMessage {
     #[serde(rename_all = "camelCase")]
@@ -77,6 +90,8 @@ pub enum CallInitializeWeightsError {
         #[serde(skip_serializing_if = "Option::is_none")]
         details: Option<String>,
     },
+    /// The params did not match the parameter definitions of the model.
+    InvalidParameters { details: String },
 }
 
 #[derive(serde::Serialize)]
@@ -96,9 +111,13 @@ pub enum CallTrainError {
         details: Option<String>,
     },
     InstantiatedModelNotFound,
+    /// The params did not match the parameter definitions of the model.
+    InvalidParameters {
+        details: String,
+    },
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EvaluateOutput {
     pub name: String,
@@ -113,6 +132,10 @@ pub enum CallEvaluateError {
         details: Option<String>,
     },
     InstantiatedModelNotFound,
+    /// The params did not match the parameter definitions of the model.
+    InvalidParameters {
+        details: String,
+    },
 }
 
 #[derive(serde::Serialize)]
@@ -125,6 +148,16 @@ pub enum CallGetWeightsError {
     InstantiatedModelNotFound,
 }
 
+#[derive(serde::Serialize)]
+#[serde(rename_all = "snake_case", tag = "code")]
+pub enum DisposeInstantiatedModelError {
+    Exception {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        details: Option<String>,
+    },
+    InstantiatedModelNotFound,
+}
+
 #[allow(clippy::enum_variant_names)]
 #[derive(serde::Serialize)]
 #[serde(untagged, rename_all = "camelCase")]
@@ -174,7 +207,17 @@ fn serialize_asref_str_seq<S: serde::Serializer, T: AsRef<str>>(
 #[serde(tag = "event", content = "params")]
 pub enum EventMessage<'a, S: AsRef<str>> {
     #[serde(rename_all = "camelCase")]
-    ModelSessionInitialized {},
+    ModelSessionInitialized {
+        parameter_definitions: &'a crate::trait_def::ParameterDefinitions,
+    },
+    /// Sent when an instantiated model has been disposed, after its calls have finished and
+    /// Instantiated::dispose has returned.
+    #[serde(rename_all = "camelCase")]
+    InstantiatedModelDisposed {
+        instantiated_model_id: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<DisposeInstantiatedModelError>,
+    },
     #[serde(rename_all = "camelCase")]
     TrainingProgress {
         training_session_id: &'a str,
@@ -191,9 +234,35 @@ pub enum EventMessage<'a, S: AsRef<str>> {
         command_id: &'a str,
         #[serde(serialize_with = "serialize_asref_str_seq")]
         names: &'a [S],
+        /// Keys that are unchanged from the weights that the model was instantiated with.
+        #[serde(
+            serialize_with = "serialize_asref_str_seq",
+            skip_serializing_if = "is_empty"
+        )]
+        inherited: &'a [S],
+        /// Keys whose data follows the data of *names*, as patches against the weights that the
+        /// model was instantiated with. See crate::create_weights_delta for the format.
+        #[serde(
+            serialize_with = "serialize_asref_str_seq",
+            skip_serializing_if = "is_empty"
+        )]
+        deltas: &'a [S],
+        /// Set when the data of the last key in *names* is incomplete, and continues in the next
+        /// ProvideWeightsData event, which provides the same key. Used by
+        /// WeightsProvider::provide_stream.
+        #[serde(skip_serializing_if = "is_false")]
+        continued: bool,
     },
 }
 
+fn is_empty<T>(values: &&[T]) -> bool {
+    values.is_empty()
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
 #[derive(Debug, serde::Serialize)]
 #[serde(tag = "event", rename_all = "camelCase")]
 pub enum DataEvent<'a> {
@@ -204,17 +273,53 @@ pub enum DataEvent<'a> {
         start_index: u32,
         amount: u32,
     },
+    /// The order of the data is given by crate::shuffle_permutation(size, seed), applied to the
+    /// unshuffled data.
     #[serde(rename_all = "camelCase")]
-    Shuffle { datasets: &'a [&'a str] },
+    Shuffle { datasets: &'a [&'a str], seed: u64 },
 }
 
 pub enum MessageFromHost {
     Command(CommandMessage),
-    ProvideData(u32, Vec<bytes::Bytes>),
+    /// The request id, the data, and the buffer from ProvideDataTarget, if any. Once the blobs
+    /// have been split off, the buffer is empty but still refers to their allocation.
+    ProvideData(
+        u32,
+        Result<Vec<bytes::Bytes>, crate::DataLoaderError>,
+        Option<bytes::BytesMut>,
+    ),
+}
+
+/// How the blobs of provided data are read.
+#[derive(Default)]
+pub struct ProvideDataTarget {
+    /// Data points larger than this are skipped without being allocated, and the data is then
+    /// provided as an error.
+    pub max_element_size: Option<u64>,
+    /// A buffer to read the blobs into, instead of the BlobBuffer. See
+    /// DataLoaderBinary::try_read_range_into.
+    pub buffer: Option<bytes::BytesMut>,
 }
 
+/// A reusable buffer for the blobs of provided data. The blobs of a message are read into one
+/// contiguous allocation, which is split into one Bytes per blob. Once all Bytes from an
+/// allocation have been dropped, the allocation is reused for later messages.
+///
+/// Messages larger than MAX_REUSED_MESSAGE_SIZE, which are usually weights, get an allocation of
+/// their own. Otherwise the small blobs of later messages could keep it alive.
+#[derive(Default)]
+pub struct BlobBuffer {
+    buf: bytes::BytesMut,
+    last_message_size: usize,
+}
+
+const MAX_REUSED_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Reads the next message. The blobs of provided data are read as given by target(request_id).
 pub async fn read_message_from_host(
     mut reader: impl super::asyncs::AsyncRead + Unpin,
+    buffer: &mut BlobBuffer,
+    target: impl Fn(u32) -> ProvideDataTarget,
 ) -> Result<MessageFromHost, std::io::Error> {
     let first_byte = super::asyncs::read_u8(&mut reader).await?;
     if first_byte == 0 {
@@ -229,17 +334,121 @@ pub async fn read_message_from_host(
         // Provide data
         let request_id = super::asyncs::read_u32(&mut reader).await?;
         let num_blobs = super::asyncs::read_u32(&mut reader).await?;
-        let mut data = Vec::with_capacity(num_blobs as usize);
+        let ProvideDataTarget {
+            max_element_size,
+            buffer: mut target_buffer,
+        } = target(request_id);
+        let buf = match &mut target_buffer {
+            Some(buf) => buf,
+            None => {
+                // Messages tend to be of similar size, so reserving the size of the previous one
+                // usually makes the whole message fit in a single allocation.
+                buffer.buf.reserve(buffer.last_message_size);
+                &mut buffer.buf
+            }
+        };
+        let mut message_size = 0;
+        let mut data = Ok(Vec::with_capacity(num_blobs as usize));
+        for _ in 0..num_blobs {
+            let blob_length = super::asyncs::read_u64(&mut reader).await?;
+            if let Some(limit) = max_element_size.filter(|&limit| blob_length > limit) {
+                super::asyncs::skip(&mut reader, blob_length).await?;
+                data = Err(crate::DataLoaderError::ElementTooLarge {
+                    size: blob_length,
+                    limit,
+                });
+                continue;
+            }
+            super::asyncs::read_exact_buf(&mut reader, &mut *buf, blob_length).await?;
+            message_size += blob_length as usize;
+            let blob = buf.split().freeze();
+            if let Ok(data) = &mut data {
+                data.push(blob);
+            }
+        }
+        if target_buffer.is_none() {
+            if message_size > MAX_REUSED_MESSAGE_SIZE {
+                buffer.buf = bytes::BytesMut::new();
+                buffer.last_message_size = 0;
+            } else {
+                buffer.last_message_size = message_size;
+            }
+        }
+        Ok(MessageFromHost::ProvideData(
+            request_id,
+            data,
+            target_buffer,
+        ))
+    }
+}
+
+pub enum MessageFromModel {
+    /// A JSON result or event, and its blobs.
+    ResultOrEvent(Vec<u8>, Vec<bytes::Bytes>),
+    /// A JSON data event.
+    DataEvent(Vec<u8>),
+}
+
+/// Reads the next message sent by a model, which is the other end of Sender. Used when this
+/// process is the host of a child model.
+pub async fn read_message_from_model(
+    mut reader: impl super::asyncs::AsyncRead + Unpin,
+) -> Result<MessageFromModel, std::io::Error> {
+    let first_byte = super::asyncs::read_u8(&mut reader).await?;
+    if first_byte == 0 {
+        let num_blobs = super::asyncs::read_u32(&mut reader).await?;
+        let msg_length = super::asyncs::read_u64(&mut reader).await?;
+        let mut msg = vec![0; msg_length as usize];
+        reader.read_exact(&mut msg).await?;
+        let mut blobs = Vec::with_capacity(num_blobs as usize);
         for _ in 0..num_blobs {
-            let blob_length = super::asyncs::read_u64(&mut reader).await? as usize;
-            let mut buf = vec![0; blob_length as usize];
-            reader.read_exact(&mut buf).await?;
-            data.push(buf.into());
+            let blob_length = super::asyncs::read_u64(&mut reader).await?;
+            let mut blob = bytes::BytesMut::new();
+            super::asyncs::read_exact_buf(&mut reader, &mut blob, blob_length).await?;
+            blobs.push(blob.freeze());
         }
-        Ok(MessageFromHost::ProvideData(request_id, data))
+        // Each result or event ends with a marker byte.
+        super::asyncs::read_u8(&mut reader).await?;
+        Ok(MessageFromModel::ResultOrEvent(msg, blobs))
+    } else {
+        let msg_length = super::asyncs::read_u64(&mut reader).await?;
+        let mut msg = vec![0; msg_length as usize];
+        reader.read_exact(&mut msg).await?;
+        Ok(MessageFromModel::DataEvent(msg))
+    }
+}
+
+/// Sends a command to a model, which is read by read_message_from_host.
+pub async fn write_command(
+    mut writer: impl super::asyncs::AsyncWrite + Unpin,
+    command: &CommandMessage,
+) -> Result<(), std::io::Error> {
+    let msg = serde_json::to_vec(command).unwrap();
+    super::asyncs::write_u8(&mut writer, 0).await?;
+    super::asyncs::write_u64(&mut writer, msg.len() as u64).await?;
+    writer.write_all(&msg).await?;
+    writer.flush().await
+}
+
+/// Provides data to a model in response to DataEvent::RequestData.
+pub async fn write_provide_data(
+    mut writer: impl super::asyncs::AsyncWrite + Unpin,
+    request_id: u32,
+    blobs: &[bytes::Bytes],
+) -> Result<(), std::io::Error> {
+    super::asyncs::write_u8(&mut writer, 1).await?;
+    super::asyncs::write_u32(&mut writer, request_id).await?;
+    super::asyncs::write_u32(&mut writer, blobs.len().try_into().unwrap()).await?;
+    for blob in blobs {
+        super::asyncs::write_u64(&mut writer, blob.len() as u64).await?;
+        writer.write_all(blob).await?;
     }
+    writer.flush().await
 }
 
+#[derive(Debug)]
+pub struct Disconnected;
+
 enum MessageToHost {
     ResultOrEvent(Vec<u8>, Vec<bytes::Bytes>),
     DataEvent(Vec<u8>),
@@ -320,12 +529,12 @@ impl Sender {
             .unwrap();
     }
 
-    pub async fn send_data_event(&self, data_event: DataEvent<'_>) {
+    /// Returns an error if the connection to the host has been closed.
+    pub async fn send_data_event(&self, data_event: DataEvent<'_>) -> Result<(), Disconnected> {
         let msg = serde_json::to_vec(&data_event).unwrap();
         self.tx
             .send(MessageToHost::DataEvent(msg))
             .await
-            .map_err(|_| ())
-            .unwrap();
+            .map_err(|_| Disconnected)
     }
 }
@@ -1,9 +1,12 @@
 mod async_waiter;
 mod asyncs;
+mod childmodel;
+mod datacache;
 mod dataloader;
 mod host_protocol;
 mod traintracker;
 mod weightsprovider;
+mod worker_pool;
 
 use std::{
     collections::HashMap,
@@ -15,6 +18,11 @@ use dataloader::*;
 
 use futures::FutureExt;
 
+pub use childmodel::{ChildInstantiated, ChildModel, ChildModelError};
+pub use datacache::DataCacheOptions;
+pub use dataloader::DataRequestOptions;
+pub use worker_pool::WorkerPool;
+
 struct PanicInfo {
     backtrace: String,
     location: Option<(String, u32, u32)>,
@@ -76,16 +84,41 @@ fn format_panic(e: Box<dyn std::any::Any>) -> String {
     )
 }
 
+/// Checks the names of *params* against *definitions*, unless the model did not declare them.
+fn validate_params(
+    definitions: &Option<Vec<decthings_api::tensor::DecthingsParameterDefinition>>,
+    params: &[host_protocol::Param],
+) -> Result<(), String> {
+    match definitions {
+        Some(definitions) => {
+            crate::validate_param_names(definitions, params.iter().map(|x| x.name.as_str()))
+                .map_err(|e| e.to_string())
+        }
+        None => Ok(()),
+    }
+}
+
 struct InstantiatedModelWaiter<I: InstantiatedBinary> {
     waiter: async_waiter::AsyncWaiter<I>,
-    dispose_tx: asyncs::oneshot::Sender<()>,
+    /// Cloned by each call to the instantiated model, including the instantiation, and dropped
+    /// when the call finishes. Disposing waits until in_flight_rx is closed.
+    in_flight: asyncs::Sender<()>,
+    in_flight_rx: asyncs::Receiver<()>,
+    weight_keys: Vec<String>,
+}
+
+struct TrainingSession {
+    instantiated_model_id: String,
+    cancel_tx: async_waiter::AsyncWaiterProvider<()>,
 }
 
 struct Runner<M: ModelBinary> {
     sender: host_protocol::Sender,
     data_loader_manager: DataLoaderManager,
     instantiated_models: Arc<Mutex<HashMap<String, InstantiatedModelWaiter<M::Instantiated>>>>,
-    training_sessions: Arc<Mutex<HashMap<String, async_waiter::AsyncWaiterProvider<()>>>>,
+    training_sessions: Arc<Mutex<HashMap<String, TrainingSession>>>,
+    parameter_definitions: Arc<ParameterDefinitions>,
+    context: crate::SharedContext,
 }
 
 impl<M: ModelBinary> Clone for Runner<M> {
@@ -95,6 +128,8 @@ impl<M: ModelBinary> Clone for Runner<M> {
             data_loader_manager: self.data_loader_manager.clone(),
             instantiated_models: self.instantiated_models.clone(),
             training_sessions: self.training_sessions.clone(),
+            parameter_definitions: self.parameter_definitions.clone(),
+            context: self.context.clone(),
         }
     }
 }
@@ -120,10 +155,11 @@ where
         &self,
         dataset: String,
         total_byte_size: u64,
+        local_file: Option<host_protocol::LocalFile>,
     ) -> impl WeightsLoader + 'static {
-        let (data_loader, fut) = self
-            .data_loader_manager
-            .create_weights_loader(dataset, total_byte_size);
+        let (data_loader, fut) =
+            self.data_loader_manager
+                .create_weights_loader(dataset, total_byte_size, local_file);
         asyncs::spawn(fut);
         data_loader
     }
@@ -138,6 +174,17 @@ where
                 params,
                 other_models,
             } => {
+                if let Err(details) =
+                    validate_params(&self.parameter_definitions.initialize_weights, &params)
+                {
+                    let error =
+                        host_protocol::CallInitializeWeightsError::InvalidParameters { details };
+                    return Some((
+                        id,
+                        host_protocol::ResultMessage::CallInitializeWeights { error: Some(error) },
+                        vec![],
+                    ));
+                }
                 let error = match std::panic::AssertUnwindSafe(M::initialize_weights(
                     crate::trait_def::InitializeWeightsOptions {
                         params: params
@@ -152,6 +199,7 @@ where
                         weights_provider: weightsprovider::create_weights_provider(
                             &id,
                             self.sender.clone(),
+                            None,
                         ),
                         other_models: other_models
                             .into_iter()
@@ -169,6 +217,7 @@ where
                                                     self.create_weights_loader(
                                                         param.dataset,
                                                         param.total_byte_size,
+                                                        param.local_file,
                                                     ),
                                                 )
                                             })
@@ -177,6 +226,7 @@ where
                                 )
                             })
                             .collect(),
+                        context: self.context.clone(),
                     },
                 ))
                 .catch_unwind()
@@ -201,59 +251,57 @@ where
             } => {
                 let (waiter, provider) = async_waiter::AsyncWaiter::<M::Instantiated>::new();
 
-                let (dispose_tx, dispose_rx) = asyncs::oneshot::channel();
+                let (in_flight, in_flight_rx) = asyncs::channel(1);
+                let _in_flight = in_flight.clone();
 
                 {
                     let mut instantiated_models = self.instantiated_models.lock().unwrap();
                     instantiated_models.insert(
                         instantiated_model_id,
-                        InstantiatedModelWaiter { waiter, dispose_tx },
+                        InstantiatedModelWaiter {
+                            waiter,
+                            in_flight,
+                            in_flight_rx,
+                            weight_keys: weights.iter().map(|x| x.name.clone()).collect(),
+                        },
                     );
                 }
 
-                let mut provider = Some(provider);
-                let drop_fut = async {
-                    dispose_rx.await.unwrap();
-                    provider = None;
-                    Ok(())
-                };
-                let instantiate_fut = async {
-                    let res = std::panic::AssertUnwindSafe(M::instantiate_model(
-                        crate::trait_def::InstantiateModelOptions {
-                            weights: weights
-                                .into_iter()
-                                .map(|x| {
-                                    (
-                                        x.name,
-                                        self.create_weights_loader(x.dataset, x.total_byte_size),
-                                    )
-                                })
-                                .collect(),
-                            other_models: other_models
-                                .into_iter()
-                                .map(|other_model| {
-                                    (
-                                        other_model.id,
-                                        crate::trait_def::OtherModel {
-                                            mount_path: other_model.mount_path,
-                                        },
-                                    )
-                                })
-                                .collect(),
-                        },
-                    ))
-                    .catch_unwind()
-                    .await;
-                    Err::<(), _>(res)
-                };
-
-                let res = futures::try_join!(drop_fut, instantiate_fut).unwrap_err();
+                let res = std::panic::AssertUnwindSafe(M::instantiate_model(
+                    crate::trait_def::InstantiateModelOptions {
+                        weights: weights
+                            .into_iter()
+                            .map(|x| {
+                                (
+                                    x.name,
+                                    self.create_weights_loader(
+                                        x.dataset,
+                                        x.total_byte_size,
+                                        x.local_file,
+                                    ),
+                                )
+                            })
+                            .collect(),
+                        other_models: other_models
+                            .into_iter()
+                            .map(|other_model| {
+                                (
+                                    other_model.id,
+                                    crate::trait_def::OtherModel {
+                                        mount_path: other_model.mount_path,
+                                    },
+                                )
+                            })
+                            .collect(),
+                        context: self.context.clone(),
+                    },
+                ))
+                .catch_unwind()
+                .await;
 
                 let error = match res {
                     Ok(model) => {
-                        if let Some(provider) = provider {
-                            provider.provide(model);
-                        }
+                        provider.provide(model);
                         None
                     }
                     Err(e) => Some(host_protocol::CallInstantiateModelError::Exception {
@@ -269,12 +317,78 @@ where
             host_protocol::CommandMessage::CallDisposeInstantiatedModel {
                 instantiated_model_id,
             } => {
-                let mut instantiated_models = self.instantiated_models.lock().unwrap();
-                let disposed = instantiated_models.remove(&instantiated_model_id);
-                drop(instantiated_models);
-                if let Some(disposed) = disposed {
-                    disposed.dispose_tx.send(()).ok();
+                let disposed = self
+                    .instantiated_models
+                    .lock()
+                    .unwrap()
+                    .remove(&instantiated_model_id);
+                let Some(InstantiatedModelWaiter {
+                    waiter,
+                    in_flight,
+                    mut in_flight_rx,
+                    ..
+                }) = disposed
+                else {
+                    self.sender
+                        .send_event::<String>(
+                            host_protocol::EventMessage::InstantiatedModelDisposed {
+                                instantiated_model_id: &instantiated_model_id,
+                                error: Some(
+                                    host_protocol::DisposeInstantiatedModelError::InstantiatedModelNotFound,
+                                ),
+                            },
+                            vec![],
+                        )
+                        .await;
+                    return None;
+                };
+
+                let cancelled = {
+                    let mut training_sessions = self.training_sessions.lock().unwrap();
+                    let ids: Vec<String> = training_sessions
+                        .iter()
+                        .filter(|(_, x)| x.instantiated_model_id == instantiated_model_id)
+                        .map(|(id, _)| id.clone())
+                        .collect();
+                    ids.iter()
+                        .filter_map(|id| training_sessions.remove(id))
+                        .collect::<Vec<_>>()
+                };
+                for training_session in cancelled {
+                    training_session.cancel_tx.provide(());
                 }
+
+                // Returns None once every call to the instantiated model has finished.
+                drop(in_flight);
+                asyncs::channel_recv(&mut in_flight_rx).await;
+
+                let error = match waiter.get().await {
+                    Some(instantiated) => {
+                        match std::panic::AssertUnwindSafe(instantiated.dispose())
+                            .catch_unwind()
+                            .await
+                        {
+                            Ok(()) => None,
+                            Err(e) => {
+                                Some(host_protocol::DisposeInstantiatedModelError::Exception {
+                                    details: Some(format_panic(e)),
+                                })
+                            }
+                        }
+                    }
+                    None => None,
+                };
+                drop(waiter);
+
+                self.sender
+                    .send_event::<String>(
+                        host_protocol::EventMessage::InstantiatedModelDisposed {
+                            instantiated_model_id: &instantiated_model_id,
+                            error,
+                        },
+                        vec![],
+                    )
+                    .await;
                 None
             }
             host_protocol::CommandMessage::CallTrain {
@@ -283,30 +397,43 @@ where
                 instantiated_model_id,
                 params,
             } => {
+                if let Err(details) = validate_params(&self.parameter_definitions.train, &params) {
+                    let error = host_protocol::CallTrainError::InvalidParameters { details };
+                    return Some((
+                        id,
+                        host_protocol::ResultMessage::CallTrain { error: Some(error) },
+                        vec![],
+                    ));
+                }
+                // The training session is registered while the instantiated model is looked up, so
+                // that a dispose which arrives while waiting for the instantiation cancels it.
                 let instantiated = {
                     let instantiated_models = self.instantiated_models.lock().unwrap();
-                    instantiated_models
-                        .get(&instantiated_model_id)
-                        .map(|x| x.waiter.clone())
-                };
-                let instantiated = if let Some(instantiated) = instantiated {
-                    instantiated.get().await
-                } else {
-                    None
-                };
-
-                let error = match instantiated {
-                    Some(instantiated) => {
+                    instantiated_models.get(&instantiated_model_id).map(|x| {
                         let (train_tracker, cancel_tx) = traintracker::create_train_tracker(
                             self.sender.clone(),
                             &training_session_id,
                         );
+                        self.training_sessions.lock().unwrap().insert(
+                            training_session_id.clone(),
+                            TrainingSession {
+                                instantiated_model_id,
+                                cancel_tx,
+                            },
+                        );
+                        (x.waiter.clone(), x.in_flight.clone(), train_tracker)
+                    })
+                };
+                let (instantiated, _in_flight) = match instantiated {
+                    Some((waiter, in_flight, train_tracker)) => (
+                        waiter.get().await.map(|x| (x, train_tracker)),
+                        Some(in_flight),
+                    ),
+                    None => (None, None),
+                };
 
-                        {
-                            let mut training_sessions = self.training_sessions.lock().unwrap();
-                            training_sessions.insert(training_session_id.clone(), cancel_tx);
-                        }
-
+                let error = match instantiated {
+                    Some((instantiated, train_tracker)) => {
                         let res = std::panic::AssertUnwindSafe(
                             instantiated.as_ref().train(crate::trait_def::TrainOptions {
                                 params: params
@@ -339,7 +466,13 @@ where
                             }),
                         }
                     }
-                    None => Some(host_protocol::CallTrainError::InstantiatedModelNotFound),
+                    None => {
+                        let mut training_sessions = self.training_sessions.lock().unwrap();
+                        training_sessions.remove(&training_session_id);
+                        drop(training_sessions);
+
+                        Some(host_protocol::CallTrainError::InstantiatedModelNotFound)
+                    }
                 };
                 Some((
                     id,
@@ -351,11 +484,11 @@ where
                 training_session_id,
             } => {
                 let mut training_sessions = self.training_sessions.lock().unwrap();
-                let cancel_tx = training_sessions.remove(&training_session_id);
+                let training_session = training_sessions.remove(&training_session_id);
                 drop(training_sessions);
 
-                if let Some(cancel_tx) = cancel_tx {
-                    cancel_tx.provide(());
+                if let Some(training_session) = training_session {
+                    training_session.cancel_tx.provide(());
                 }
                 None
             }
@@ -365,16 +498,27 @@ where
                 params,
                 expected_output_types,
             } => {
+                if let Err(details) = validate_params(&self.parameter_definitions.evaluate, &params)
+                {
+                    let error = host_protocol::CallEvaluateError::InvalidParameters { details };
+                    return Some((
+                        id,
+                        host_protocol::ResultMessage::CallEvaluate {
+                            outputs: None,
+                            error: Some(error),
+                        },
+                        vec![],
+                    ));
+                }
                 let instantiated = {
                     let instantiated_models = self.instantiated_models.lock().unwrap();
                     instantiated_models
                         .get(&instantiated_model_id)
-                        .map(|x| x.waiter.clone())
+                        .map(|x| (x.waiter.clone(), x.in_flight.clone()))
                 };
-                let instantiated = if let Some(instantiated) = instantiated {
-                    instantiated.get().await
-                } else {
-                    None
+                let (instantiated, _in_flight) = match instantiated {
+                    Some((waiter, in_flight)) => (waiter.get().await, Some(in_flight)),
+                    None => (None, None),
                 };
                 let (outputs, error, data) = match instantiated {
                     Some(instantiated) => {
@@ -471,12 +615,13 @@ where
                     let instantiated_models = self.instantiated_models.lock().unwrap();
                     instantiated_models
                         .get(&instantiated_model_id)
-                        .map(|x| x.waiter.clone())
+                        .map(|x| (x.waiter.clone(), x.weight_keys.clone(), x.in_flight.clone()))
                 };
-                let instantiated = if let Some(instantiated) = instantiated {
-                    instantiated.get().await
-                } else {
-                    None
+                let (instantiated, weight_keys, _in_flight) = match instantiated {
+                    Some((waiter, weight_keys, in_flight)) => {
+                        (waiter.get().await, weight_keys, Some(in_flight))
+                    }
+                    None => (None, vec![], None),
                 };
                 let error = match instantiated {
                     Some(instantiated) => {
@@ -485,7 +630,9 @@ where
                                 weights_provider: weightsprovider::create_weights_provider(
                                     &id,
                                     self.sender.clone(),
+                                    Some(&weight_keys),
                                 ),
+                                instantiated_weights: weight_keys.clone(),
                             },
                         ))
                         .catch_unwind()
@@ -509,12 +656,24 @@ where
     }
 
     async fn run<R: asyncs::AsyncRead + Unpin>(&self, mut reader: R) {
+        let mut buffer = host_protocol::BlobBuffer::default();
         loop {
             let runner = self.clone();
-            match host_protocol::read_message_from_host(&mut reader)
-                .await
-                .expect("Failed to read incoming message from host")
-            {
+            let message =
+                host_protocol::read_message_from_host(&mut reader, &mut buffer, |request_id| {
+                    self.data_loader_manager.provide_data_target(request_id)
+                })
+                .await;
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    // The process exits with the panic below, while spawned commands may still
+                    // hold the cache, so its directory is removed here.
+                    self.data_loader_manager.clear_cache();
+                    panic!("Failed to read incoming message from host: {e:?}");
+                }
+            };
+            match message {
                 host_protocol::MessageFromHost::Command(cmd) => {
                     asyncs::spawn(async move {
                         let Some((id, result, blobs_output)) = runner.handle_command(cmd).await
@@ -525,17 +684,45 @@ where
                         runner.sender.send_result(id, result, blobs_output).await;
                     });
                 }
-                host_protocol::MessageFromHost::ProvideData(request_id, data) => {
-                    self.data_loader_manager.provide_data(request_id, data)
-                }
+                host_protocol::MessageFromHost::ProvideData(request_id, data, buffer) => self
+                    .data_loader_manager
+                    .provide_data(request_id, data, buffer),
             }
         }
     }
 }
 
+/// Options for run_model_with_options.
+#[derive(Clone, Debug, Default)]
+pub struct RunModelOptions {
+    /// Caches the data provided by the host, so that second and later epochs are served locally.
+    /// Disabled by default.
+    pub data_cache: Option<DataCacheOptions>,
+    /// How data loaders request data from the host: the chunk size, the number of requests in
+    /// flight and the memory budget for requested data.
+    pub data_requests: DataRequestOptions,
+}
+
 pub async fn run_model<M: ModelBinary + Send + Sync + 'static>()
 where
     M::Instantiated: Send + Sync,
+{
+    run_model_with_options::<M>(RunModelOptions::default()).await
+}
+
+/// Same as run_model, but starts an async runtime and blocks until the model exits. This is what
+/// the main function generated by `#[decthings_model::model]` calls.
+pub fn run_model_blocking<M: ModelBinary + Send + Sync + 'static>()
+where
+    M::Instantiated: Send + Sync,
+{
+    asyncs::block_on(run_model::<M>())
+}
+
+pub async fn run_model_with_options<M: ModelBinary + Send + Sync + 'static>(
+    options: RunModelOptions,
+) where
+    M::Instantiated: Send + Sync,
 {
     std::panic::set_hook(Box::new(panic_hook));
 
@@ -551,11 +738,16 @@ where
 
     let (sender, sender_fut) = host_protocol::Sender::new(writer);
 
+    let mut context = crate::SharedContext::default();
+    M::setup(&mut context).await;
+
     let runner = Runner::<M> {
         sender: sender.clone(),
-        data_loader_manager: DataLoaderManager::new(sender.clone()),
+        data_loader_manager: DataLoaderManager::new(sender.clone(), &options),
         instantiated_models: Arc::new(Mutex::new(HashMap::new())),
         training_sessions: Arc::new(Mutex::new(HashMap::new())),
+        parameter_definitions: Arc::new(M::parameter_definitions()),
+        context,
     };
     let runner = &runner;
 
@@ -563,7 +755,9 @@ where
         async {
             sender
                 .send_event::<String>(
-                    host_protocol::EventMessage::ModelSessionInitialized {},
+                    host_protocol::EventMessage::ModelSessionInitialized {
+                        parameter_definitions: &runner.parameter_definitions,
+                    },
                     vec![],
                 )
                 .await;
@@ -1,12 +1,47 @@
 use std::collections::HashSet;
 
 use crate::*;
-use futures::future::BoxFuture;
+use futures::{StreamExt, future::BoxFuture};
 
 struct WeightsProviderImpl<'a> {
     command_id: &'a str,
     sender: super::host_protocol::Sender,
     provided: HashSet<String>,
+    /// The keys the model was instantiated with, or None outside of get_weights.
+    instantiated_weights: Option<HashSet<String>>,
+}
+
+impl WeightsProviderImpl<'_> {
+    fn mark_provided(&mut self, key: &str) {
+        if !self.provided.insert(key.to_owned()) {
+            panic!(r#"WeightsProvider: Weight key "{key}" was provided multiple times."#);
+        }
+    }
+
+    async fn send_chunk(&self, key: &str, chunk: bytes::Bytes, continued: bool) {
+        self.sender
+            .send_event(
+                super::host_protocol::EventMessage::ProvideWeightsData {
+                    command_id: self.command_id,
+                    names: &[key],
+                    inherited: &[],
+                    deltas: &[],
+                    continued,
+                },
+                vec![chunk],
+            )
+            .await;
+    }
+
+    fn check_instantiated(&self, key: &str, method: &str) {
+        match &self.instantiated_weights {
+            None => panic!("WeightsProvider: {method} can only be used in get_weights."),
+            Some(keys) if !keys.contains(key) => panic!(
+                r#"WeightsProvider: {method} was called with weight key "{key}", which the model was not instantiated with."#
+            ),
+            Some(_) => {}
+        }
+    }
 }
 
 impl<'a> WeightsProvider for WeightsProviderImpl<'a> {
@@ -16,16 +51,23 @@ impl<'a> WeightsProvider for WeightsProviderImpl<'a> {
     ) -> BoxFuture<'b, ()> {
         Box::pin(async move {
             for (key, _) in data {
-                if !self.provided.insert(key.as_ref().to_owned()) {
-                    panic!(
-                        r#"WeightsProvider: Weight key "{}" was provided multiple times."#,
-                        key.as_ref()
-                    );
-                }
+                self.mark_provided(key.as_ref());
             }
             if self.provided.len() as u32 + data.len() as u32 > 100 {
                 panic!("WeightsProvider: Cannot provide more than 100 keys.");
             }
+            // Compression is CPU-bound, so it runs on the blocking threads of the runtime.
+            let data: Vec<_> = futures::future::join_all(data.iter().map(|(key, data)| {
+                let data = data.clone();
+                async move {
+                    let compressed =
+                        super::asyncs::spawn_blocking(move || crate::compress_weights(&data))
+                            .await
+                            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+                    (key, compressed)
+                }
+            }))
+            .await;
             if data.iter().any(|(_, data)| data.len() > 1024usize.pow(3)) {
                 panic!(
                     "WeightsProvider: Cannot provide more than 1 gigabyte for a single key. Split it into multiple keys."
@@ -50,6 +92,9 @@ impl<'a> WeightsProvider for WeightsProviderImpl<'a> {
                         super::host_protocol::EventMessage::ProvideWeightsData {
                             command_id: self.command_id,
                             names: &names,
+                            inherited: &[],
+                            deltas: &[],
+                            continued: false,
                         },
                         to_send,
                     )
@@ -57,15 +102,95 @@ impl<'a> WeightsProvider for WeightsProviderImpl<'a> {
             }
         })
     }
+
+    fn provide_stream<'b>(
+        &'b mut self,
+        key: &'b str,
+        data: impl futures::Stream<Item = bytes::Bytes> + Send + 'b,
+    ) -> BoxFuture<'b, ()> {
+        Box::pin(async move {
+            self.mark_provided(key);
+            if self.provided.len() > 100 {
+                panic!("WeightsProvider: Cannot provide more than 100 keys.");
+            }
+
+            // Each chunk is sent once the next one is available, so that the last chunk can be
+            // sent without the continuation marker.
+            let mut data = std::pin::pin!(data);
+            let mut pending = bytes::Bytes::new();
+            while let Some(chunk) = data.next().await {
+                if chunk.len() > 1024usize.pow(3) {
+                    panic!(
+                        "WeightsProvider: Cannot provide a chunk of more than 1 gigabyte in provide_stream."
+                    );
+                }
+                if chunk.is_empty() {
+                    continue;
+                }
+                let previous = std::mem::replace(&mut pending, chunk);
+                if !previous.is_empty() {
+                    self.send_chunk(key, previous, true).await;
+                }
+            }
+            self.send_chunk(key, pending, false).await;
+        })
+    }
+
+    fn inherit<'b>(&'b mut self, keys: &'b [&'b str]) -> BoxFuture<'b, ()> {
+        Box::pin(async move {
+            for key in keys {
+                self.check_instantiated(key, "inherit");
+                self.mark_provided(key);
+            }
+            self.sender
+                .send_event(
+                    super::host_protocol::EventMessage::ProvideWeightsData {
+                        command_id: self.command_id,
+                        names: &[],
+                        inherited: keys,
+                        deltas: &[],
+                        continued: false,
+                    },
+                    vec![],
+                )
+                .await;
+        })
+    }
+
+    fn provide_delta<'b>(&'b mut self, key: &'b str, delta: bytes::Bytes) -> BoxFuture<'b, ()> {
+        Box::pin(async move {
+            self.check_instantiated(key, "provide_delta");
+            self.mark_provided(key);
+            if delta.len() > 1024usize.pow(3) {
+                panic!("WeightsProvider: Cannot provide a delta of more than 1 gigabyte.");
+            }
+            self.sender
+                .send_event(
+                    super::host_protocol::EventMessage::ProvideWeightsData {
+                        command_id: self.command_id,
+                        names: &[],
+                        inherited: &[],
+                        deltas: &[key],
+                        continued: false,
+                    },
+                    vec![delta],
+                )
+                .await;
+        })
+    }
 }
 
-pub(super) fn create_weights_provider(
-    command_id: &str,
+/// *instantiated_weights* are the keys that the model was instantiated with when called from
+/// get_weights, and None otherwise.
+pub(super) fn create_weights_provider<'a>(
+    command_id: &'a str,
     sender: super::host_protocol::Sender,
-) -> impl WeightsProvider + '_ {
+    instantiated_weights: Option<&[String]>,
+) -> impl WeightsProvider + 'a {
     WeightsProviderImpl {
         command_id,
         sender,
         provided: HashSet::new(),
+        instantiated_weights: instantiated_weights.map(|x| x.iter().cloned().collect()),
     }
 }
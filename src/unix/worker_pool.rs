@@ -0,0 +1,98 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, mpsc},
+};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of threads for CPU-bound work, such as preprocessing in DataLoader::map. Cloning the pool
+/// gives another handle to the same threads. The threads exit when all handles have been dropped.
+#[derive(Clone)]
+pub struct WorkerPool {
+    tx: Arc<Mutex<mpsc::Sender<Job>>>,
+    threads: usize,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        if threads == 0 {
+            panic!("WorkerPool: The number of threads must be positive.");
+        }
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads {
+            let rx = Arc::clone(&rx);
+            std::thread::Builder::new()
+                .name(format!("decthings-worker-{i}"))
+                .spawn(move || {
+                    loop {
+                        let job = rx.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    }
+                })
+                .expect("WorkerPool: Failed to spawn thread");
+        }
+        Self {
+            tx: Arc::new(Mutex::new(tx)),
+            threads,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Runs *f* on one of the threads. The work starts immediately, before the returned future is
+    /// polled. If *f* panics, the panic is resumed when the future is awaited.
+    pub fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> impl Future<Output = T> + Send + 'static {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        self.tx
+            .lock()
+            .unwrap()
+            .send(Box::new(move || {
+                tx.send(std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)))
+                    .ok();
+            }))
+            .expect("WorkerPool: All threads have exited");
+        async move {
+            match rx.await.expect("WorkerPool: The job was dropped") {
+                Ok(val) => val,
+                Err(e) => std::panic::resume_unwind(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn worker_pool_runs_jobs() {
+        let pool = WorkerPool::new(3);
+        assert_eq!(pool.threads(), 3);
+        let jobs: Vec<_> = (0..10).map(|x| pool.run(move || x * x)).collect();
+        assert_eq!(
+            block_on(futures::future::join_all(jobs)),
+            (0..10).map(|x| x * x).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn worker_pool_resumes_panics() {
+        let pool = WorkerPool::new(1);
+        let job = pool.run(|| panic!("job failed"));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| block_on(job)));
+        assert!(res.is_err());
+        // The thread survives the panic.
+        assert_eq!(block_on(pool.run(|| 1)), 1);
+    }
+}
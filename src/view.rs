@@ -0,0 +1,465 @@
+use std::sync::Mutex;
+
+use decthings_api::tensor::OwnedDecthingsTensor;
+use futures::future::BoxFuture;
+
+use crate::{DataLoader, DataLoaderBinary, DataLoaderError, ShuffleState, shuffle_permutation};
+
+/// The bookkeeping shared by DataLoaderView and DataLoaderBinaryView. Maps positions in the view
+/// to indices in the underlying data loader.
+///
+/// The view stores the indices of its data points in the unshuffled data of the underlying data
+/// loader. These are resolved through the shuffle order of the underlying data loader when the view
+/// is created, and mapped back through its shuffle order at the time of each read. The shuffle
+/// order is determined from the seed in its shuffle_state.
+struct ViewIndices {
+    indices: Vec<u32>,
+    shuffled: Mutex<Option<(u64, Vec<u32>)>>,
+    position: u32,
+    inner_size: u32,
+    /// The inverse of the shuffle order of the underlying data loader, for the seed it was last
+    /// created for.
+    inner_inverse: Mutex<Option<(u64, Vec<u32>)>>,
+}
+
+impl ViewIndices {
+    fn split(size: u32, inner_seed: Option<u64>, fraction: f64, seed: u64) -> (Self, Self) {
+        if !(0.0..=1.0).contains(&fraction) {
+            panic!(
+                "DataLoader: The split fraction must be between 0 and 1, but {fraction} was given."
+            );
+        }
+        let mut permutation = shuffle_permutation(size, seed);
+        let second = permutation.split_off((fraction * size as f64).round() as usize);
+        (
+            Self::new(size, inner_seed, permutation),
+            Self::new(size, inner_seed, second),
+        )
+    }
+
+    fn slice(size: u32, inner_seed: Option<u64>, range: std::ops::Range<u32>) -> Self {
+        if range.start > range.end || range.end > size {
+            panic!(
+                "DataLoader: Cannot slice {}..{} from a data loader with {} data points.",
+                range.start, range.end, size
+            );
+        }
+        Self::new(size, inner_seed, range.collect())
+    }
+
+    /// *positions* are positions in the underlying data loader, which is currently shuffled with
+    /// *inner_seed*.
+    fn new(inner_size: u32, inner_seed: Option<u64>, mut positions: Vec<u32>) -> Self {
+        if let Some(inner_seed) = inner_seed {
+            let permutation = shuffle_permutation(inner_size, inner_seed);
+            for position in &mut positions {
+                *position = permutation[*position as usize];
+            }
+        }
+        // Sorting makes reads from an unshuffled data loader coalesce into fewer requests.
+        positions.sort_unstable();
+        Self {
+            indices: positions,
+            shuffled: Mutex::new(None),
+            position: 0,
+            inner_size,
+            inner_inverse: Mutex::new(None),
+        }
+    }
+
+    fn size(&self) -> u32 {
+        self.indices.len() as u32
+    }
+
+    fn estimate_byte_size(&self, total_byte_size: u64, total_size: u32) -> u64 {
+        if total_size == 0 {
+            return 0;
+        }
+        (total_byte_size as u128 * self.size() as u128 / total_size as u128) as u64
+    }
+
+    fn shuffle_in_group<'a>(views: impl Iterator<Item = &'a Self>, seed: u64) {
+        for view in views {
+            *view.shuffled.lock().unwrap() = Some((seed, shuffle_permutation(view.size(), seed)));
+        }
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        ShuffleState {
+            seed: self.shuffled.lock().unwrap().as_ref().map(|x| x.0),
+            position: self.position,
+        }
+    }
+
+    fn set_position(&mut self, position: u32) {
+        if position > self.size() {
+            panic!(
+                "DataLoader: Cannot set the the position to a value greater than the data size. The data size was {}, and position {} was attempted to be set.",
+                self.size(),
+                position
+            );
+        }
+        self.position = position;
+    }
+
+    /// Returns the indices in the underlying data loader of the given range of the view.
+    fn range(
+        &self,
+        start_index: u32,
+        amount: u32,
+        inner_state: ShuffleState,
+        inner_size: u32,
+    ) -> Result<Vec<u32>, DataLoaderError> {
+        if start_index > self.size() {
+            return Err(DataLoaderError::IndexOutOfRange {
+                index: start_index,
+                size: self.size(),
+            });
+        }
+        let amount = amount.min(self.size() - start_index);
+        self.map(
+            (start_index..start_index + amount).collect(),
+            inner_state,
+            inner_size,
+        )
+    }
+
+    /// Maps indices in the view to indices in the underlying data loader, which currently has
+    /// *inner_state* and *inner_size*.
+    fn map(
+        &self,
+        mut indices: Vec<u32>,
+        inner_state: ShuffleState,
+        inner_size: u32,
+    ) -> Result<Vec<u32>, DataLoaderError> {
+        if inner_size != self.inner_size {
+            panic!(
+                "DataLoader: The size of the data loader of a view changed from {} to {}.",
+                self.inner_size, inner_size
+            );
+        }
+        let shuffled = self.shuffled.lock().unwrap();
+        let mut inner_inverse = self.inner_inverse.lock().unwrap();
+        let inner_inverse = match inner_state.seed {
+            Some(seed) => {
+                if inner_inverse.as_ref().is_none_or(|x| x.0 != seed) {
+                    let mut inverse = vec![0; self.inner_size as usize];
+                    for (position, index) in shuffle_permutation(self.inner_size, seed)
+                        .into_iter()
+                        .enumerate()
+                    {
+                        inverse[index as usize] = position as u32;
+                    }
+                    *inner_inverse = Some((seed, inverse));
+                }
+                inner_inverse.as_ref().map(|x| &x.1)
+            }
+            None => None,
+        };
+        for index in &mut indices {
+            if *index >= self.size() {
+                return Err(DataLoaderError::IndexOutOfRange {
+                    index: *index,
+                    size: self.size(),
+                });
+            }
+            let unshuffled = match &*shuffled {
+                Some((_, permutation)) => permutation[*index as usize],
+                None => *index,
+            };
+            let inner_index = self.indices[unshuffled as usize];
+            *index = match inner_inverse {
+                Some(inverse) => inverse[inner_index as usize],
+                None => inner_index,
+            };
+        }
+        Ok(indices)
+    }
+}
+
+/// A view of a subset of the data points of a DataLoader, created by DataLoader::split or
+/// DataLoader::slice. The view has its own size, position and shuffle order, and reads from the
+/// underlying data loader without changing its position. The data points of the view are fixed
+/// when it is created, so shuffling the underlying data loader afterwards does not move data
+/// points between views. This relies on the shuffle_state of the underlying data loader reporting
+/// the seed it was shuffled with.
+pub struct DataLoaderView<'a, L: ?Sized> {
+    inner: &'a L,
+    indices: ViewIndices,
+}
+
+impl<'a, L: DataLoader + ?Sized> DataLoaderView<'a, L> {
+    pub(crate) fn split(inner: &'a L, fraction: f64, seed: u64) -> (Self, Self) {
+        let (first, second) =
+            ViewIndices::split(inner.size(), inner.shuffle_state().seed, fraction, seed);
+        (
+            Self {
+                inner,
+                indices: first,
+            },
+            Self {
+                inner,
+                indices: second,
+            },
+        )
+    }
+
+    pub(crate) fn slice(inner: &'a L, range: std::ops::Range<u32>) -> Self {
+        Self {
+            inner,
+            indices: ViewIndices::slice(inner.size(), inner.shuffle_state().seed, range),
+        }
+    }
+}
+
+impl<L: DataLoader + ?Sized> DataLoader for DataLoaderView<'_, L> {
+    fn total_byte_size(&self) -> u64 {
+        self.indices
+            .estimate_byte_size(self.inner.total_byte_size(), self.inner.size())
+    }
+
+    fn try_shuffle_in_group_with_seed<'b>(
+        &'b self,
+        others: &'b [&'b Self],
+        seed: u64,
+    ) -> BoxFuture<'b, Result<(), DataLoaderError>> {
+        ViewIndices::shuffle_in_group([self].iter().chain(others).map(|x| &x.indices), seed);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        self.indices.shuffle_state()
+    }
+
+    fn size(&self) -> u32 {
+        self.indices.size()
+    }
+
+    fn position(&self) -> u32 {
+        self.indices.position
+    }
+
+    fn set_position(&mut self, position: u32) {
+        self.indices.set_position(position)
+    }
+
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(async move {
+            let indices = self.indices.range(
+                start_index,
+                amount,
+                self.inner.shuffle_state(),
+                self.inner.size(),
+            )?;
+            self.inner.try_read_indices(&indices).await
+        })
+    }
+
+    fn try_read_indices<'b>(
+        &'b self,
+        indices: &'b [u32],
+    ) -> BoxFuture<'b, Result<Vec<OwnedDecthingsTensor>, DataLoaderError>> {
+        Box::pin(async move {
+            let indices = self.indices.map(
+                indices.to_vec(),
+                self.inner.shuffle_state(),
+                self.inner.size(),
+            )?;
+            self.inner.try_read_indices(&indices).await
+        })
+    }
+}
+
+/// Same as DataLoaderView, but for a DataLoaderBinary. Created by DataLoaderBinary::split or
+/// DataLoaderBinary::slice.
+pub struct DataLoaderBinaryView<'a, L: ?Sized> {
+    inner: &'a L,
+    indices: ViewIndices,
+}
+
+impl<'a, L: DataLoaderBinary + ?Sized> DataLoaderBinaryView<'a, L> {
+    pub(crate) fn split(inner: &'a L, fraction: f64, seed: u64) -> (Self, Self) {
+        let (first, second) =
+            ViewIndices::split(inner.size(), inner.shuffle_state().seed, fraction, seed);
+        (
+            Self {
+                inner,
+                indices: first,
+            },
+            Self {
+                inner,
+                indices: second,
+            },
+        )
+    }
+
+    pub(crate) fn slice(inner: &'a L, range: std::ops::Range<u32>) -> Self {
+        Self {
+            inner,
+            indices: ViewIndices::slice(inner.size(), inner.shuffle_state().seed, range),
+        }
+    }
+}
+
+impl<L: DataLoaderBinary + ?Sized> DataLoaderBinary for DataLoaderBinaryView<'_, L> {
+    fn total_byte_size(&self) -> u64 {
+        self.indices
+            .estimate_byte_size(self.inner.total_byte_size(), self.inner.size())
+    }
+
+    fn try_shuffle_in_group_with_seed<'b>(
+        &'b self,
+        others: &'b [&'b Self],
+        seed: u64,
+    ) -> BoxFuture<'b, Result<(), DataLoaderError>> {
+        ViewIndices::shuffle_in_group([self].iter().chain(others).map(|x| &x.indices), seed);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn shuffle_state(&self) -> ShuffleState {
+        self.indices.shuffle_state()
+    }
+
+    fn size(&self) -> u32 {
+        self.indices.size()
+    }
+
+    fn position(&self) -> u32 {
+        self.indices.position
+    }
+
+    fn set_position(&mut self, position: u32) {
+        self.indices.set_position(position)
+    }
+
+    fn try_read_range(
+        &self,
+        start_index: u32,
+        amount: u32,
+    ) -> BoxFuture<'_, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(async move {
+            let indices = self.indices.range(
+                start_index,
+                amount,
+                self.inner.shuffle_state(),
+                self.inner.size(),
+            )?;
+            self.inner.try_read_indices(&indices).await
+        })
+    }
+
+    fn try_read_indices<'b>(
+        &'b self,
+        indices: &'b [u32],
+    ) -> BoxFuture<'b, Result<Vec<bytes::Bytes>, DataLoaderError>> {
+        Box::pin(async move {
+            let indices = self.indices.map(
+                indices.to_vec(),
+                self.inner.shuffle_state(),
+                self.inner.size(),
+            )?;
+            self.inner.try_read_indices(&indices).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::InMemoryDataLoader;
+
+    fn data_loader(size: u8) -> InMemoryDataLoader {
+        InMemoryDataLoader::new((0..size).map(|x| bytes::Bytes::from(vec![x])).collect())
+    }
+
+    fn read_all(data_loader: &impl DataLoaderBinary) -> Vec<u8> {
+        block_on(DataLoaderBinary::try_read_range(
+            data_loader,
+            0,
+            DataLoaderBinary::size(data_loader),
+        ))
+        .unwrap()
+        .iter()
+        .map(|x| x[0])
+        .collect()
+    }
+
+    fn sorted(mut values: Vec<u8>) -> Vec<u8> {
+        values.sort_unstable();
+        values
+    }
+
+    #[test]
+    fn split_is_disjoint_and_complete() {
+        let parent = data_loader(10);
+        let (first, second) = DataLoaderBinary::split(&parent, 0.7, 3);
+        assert_eq!(
+            (
+                DataLoaderBinary::size(&first),
+                DataLoaderBinary::size(&second)
+            ),
+            (7, 3)
+        );
+        let mut all = read_all(&first);
+        all.extend(read_all(&second));
+        assert_eq!(sorted(all), (0..10).collect::<Vec<_>>());
+
+        let (again, _) = DataLoaderBinary::split(&parent, 0.7, 3);
+        assert_eq!(read_all(&again), read_all(&first));
+    }
+
+    #[test]
+    fn slice_reads_range() {
+        let parent = data_loader(10);
+        let slice = DataLoaderBinary::slice(&parent, 2..5);
+        assert_eq!(read_all(&slice), vec![2, 3, 4]);
+        let read = block_on(DataLoaderBinary::try_read_indices(&slice, &[2, 0])).unwrap();
+        assert_eq!(
+            read,
+            vec![bytes::Bytes::from(vec![4]), bytes::Bytes::from(vec![2])]
+        );
+        assert!(matches!(
+            block_on(DataLoaderBinary::try_read_indices(&slice, &[3])),
+            Err(DataLoaderError::IndexOutOfRange { index: 3, size: 3 })
+        ));
+    }
+
+    #[test]
+    fn shuffling_parent_keeps_view_contents() {
+        let parent = data_loader(20);
+        let (train, validation) = DataLoaderBinary::split(&parent, 0.5, 1);
+        let train_before = sorted(read_all(&train));
+        let validation_before = sorted(read_all(&validation));
+        block_on(DataLoaderBinary::shuffle_with_seed(&parent, 5));
+        assert_eq!(sorted(read_all(&train)), train_before);
+        assert_eq!(sorted(read_all(&validation)), validation_before);
+    }
+
+    #[test]
+    fn view_of_shuffled_parent_uses_its_order() {
+        let parent = data_loader(10);
+        block_on(DataLoaderBinary::shuffle_with_seed(&parent, 8));
+        let first_three = read_all(&parent)[..3].to_vec();
+        let slice = DataLoaderBinary::slice(&parent, 0..3);
+        assert_eq!(sorted(read_all(&slice)), sorted(first_three.clone()));
+        block_on(DataLoaderBinary::shuffle_with_seed(&parent, 9));
+        assert_eq!(sorted(read_all(&slice)), sorted(first_three));
+    }
+
+    #[test]
+    fn shuffling_view_changes_order_only() {
+        let parent = data_loader(10);
+        let slice = DataLoaderBinary::slice(&parent, 0..8);
+        block_on(DataLoaderBinary::shuffle_with_seed(&slice, 2));
+        let shuffled = read_all(&slice);
+        assert_ne!(shuffled, (0..8).collect::<Vec<_>>());
+        assert_eq!(sorted(shuffled), (0..8).collect::<Vec<_>>());
+        assert_eq!(DataLoaderBinary::shuffle_state(&slice).seed, Some(2));
+    }
+}
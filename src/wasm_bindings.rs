@@ -1,5 +1,21 @@
 pub use pollster;
 
+/// Checks the names of *params* against *definitions*, unless the model did not declare them.
+#[doc(hidden)]
+pub fn validate_params<P>(
+    definitions: &Option<Vec<decthings_api::tensor::DecthingsParameterDefinition>>,
+    params: &[P],
+    name: impl Fn(&P) -> &String,
+) -> Result<(), String> {
+    match definitions {
+        Some(definitions) => {
+            crate::validate_param_names(definitions, params.iter().map(|x| name(x).as_str()))
+                .map_err(|e| e.to_string())
+        }
+        None => Ok(()),
+    }
+}
+
 #[macro_export]
 macro_rules! export_decthings_model {
     ($ty:ident with_types_in $($path_to_types_root:tt)*) => {
@@ -8,6 +24,7 @@ macro_rules! export_decthings_model {
                 pub position: u32,
                 pub amount: u32,
                 pub total_byte_size: u64,
+                pub shuffle_seed: ::std::sync::Mutex<::core::option::Option<u64>>,
                 pub inner: super::$($path_to_types_root)*::exports::decthings::model::model::DataLoader,
             }
 
@@ -15,6 +32,91 @@ macro_rules! export_decthings_model {
                 pub byte_size: u64,
                 pub inner: super::$($path_to_types_root)*::exports::decthings::model::model::WeightsLoader,
             }
+
+            /// Returns the context of the exported model, which is created by calling Model::setup
+            /// the first time.
+            pub fn shared_context() -> ::decthings_model::SharedContext {
+                static CONTEXT: ::std::sync::OnceLock<::decthings_model::SharedContext> = ::std::sync::OnceLock::new();
+                CONTEXT
+                    .get_or_init(|| {
+                        let mut context = ::decthings_model::SharedContext::default();
+                        ::decthings_model::wasm_bindings::pollster::block_on(
+                            <super::$ty as ::decthings_model::ModelBinary>::setup(&mut context),
+                        );
+                        context
+                    })
+                    .clone()
+            }
+
+            /// The parameter definitions of the exported model, which are only created once.
+            pub fn parameter_definitions() -> &'static ::decthings_model::ParameterDefinitions {
+                static DEFINITIONS: ::std::sync::OnceLock<::decthings_model::ParameterDefinitions> = ::std::sync::OnceLock::new();
+                DEFINITIONS.get_or_init(<super::$ty as ::decthings_model::ModelBinary>::parameter_definitions)
+            }
+
+            pub fn convert_definitions(
+                definitions: &::core::option::Option<::std::vec::Vec<::decthings_model::decthings_api::tensor::DecthingsParameterDefinition>>,
+            ) -> ::core::option::Option<::std::vec::Vec<super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsParameterDefinition>> {
+                definitions.as_ref().map(|definitions| {
+                    definitions.iter().map(|definition| super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsParameterDefinition {
+                        name: definition.name.clone(),
+                        required: definition.required,
+                        rules: super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsTensorRules {
+                            shape: definition.rules.shape.clone(),
+                            allowed_types: definition.rules.allowed_types.iter().map(|x| match x {
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::F32 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::F32
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::F64 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::F64
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::I8 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::I8
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::I16 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::I16
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::I32 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::I32
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::I64 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::I64
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::U8 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::U8
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::U16 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::U16
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::U32 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::U32
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::U64 => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::U64
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::String => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::String
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::Boolean => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::Boolean
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::Binary => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::Binary
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::Image => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::Image
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::Audio => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::Audio
+                                }
+                                ::decthings_model::decthings_api::tensor::DecthingsElementType::Video => {
+                                    super::$($path_to_types_root)*::exports::decthings::model::model::DecthingsElementType::Video
+                                }
+                            }).collect(),
+                        },
+                    }).collect()
+                })
+            }
         }
 
         impl ::decthings_model::DataLoaderBinary for _decthings_model::DataLoaderBinaryImpl {
@@ -22,12 +124,23 @@ macro_rules! export_decthings_model {
                 self.total_byte_size
             }
 
-            fn shuffle_in_group<'a>(&'a self, others: &'a [&'a Self]) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ()> + Send + 'a>> {
+            fn try_shuffle_in_group_with_seed<'a>(&'a self, others: &'a [&'a Self], seed: u64) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::core::result::Result<(), ::decthings_model::DataLoaderError>> + Send + 'a>> {
                 ::std::boxed::Box::pin(async move {
-                    self.inner.shuffle(&others.iter().map(|x| &x.inner).collect::<::std::vec::Vec<_>>());
+                    self.inner.shuffle_with_seed(&others.iter().map(|x| &x.inner).collect::<::std::vec::Vec<_>>(), seed);
+                    for data_loader in [self].iter().chain(others) {
+                        *data_loader.shuffle_seed.lock().unwrap() = Some(seed);
+                    }
+                    Ok(())
                 })
             }
 
+            fn shuffle_state(&self) -> ::decthings_model::ShuffleState {
+                ::decthings_model::ShuffleState {
+                    seed: *self.shuffle_seed.lock().unwrap(),
+                    position: self.position,
+                }
+            }
+
             fn size(&self) -> u32 {
                 self.amount
             }
@@ -40,16 +153,19 @@ macro_rules! export_decthings_model {
                 self.position = position;
             }
 
-            fn next(&mut self, mut amount: u32) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::std::vec::Vec<::decthings_model::bytes::Bytes>> + Send + '_>> {
-                amount = amount.min(::decthings_model::DataLoaderBinary::remaining(self));
-
-                let start_index = self.position;
-                self.position += amount;
+            fn try_read_range(&self, start_index: u32, amount: u32) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::core::result::Result<::std::vec::Vec<::decthings_model::bytes::Bytes>, ::decthings_model::DataLoaderError>> + Send + '_>> {
                 ::std::boxed::Box::pin(async move {
+                    if start_index > self.amount {
+                        return Err(::decthings_model::DataLoaderError::IndexOutOfRange {
+                            index: start_index,
+                            size: self.amount,
+                        });
+                    }
+                    let amount = amount.min(self.amount - start_index);
                     if amount == 0 {
-                        return vec![];
+                        return Ok(vec![]);
                     }
-                    self.inner.read(start_index, amount).into_iter().map(Into::into).collect()
+                    Ok(self.inner.read(start_index, amount).into_iter().map(Into::into).collect())
                 })
             }
         }
@@ -89,7 +205,72 @@ macro_rules! export_decthings_model {
                 ::std::boxed::Box::pin(async move {
                     $($path_to_types_root)*::exports::decthings::model::model::WeightsProvider::provide(
                         self,
-                        &data.into_iter().map(|data| (data.0.as_ref().to_owned(), data.1.to_vec())).collect::<::std::vec::Vec<_>>()
+                        &data.into_iter().map(|data| (data.0.as_ref().to_owned(), ::decthings_model::compress_weights(&data.1).to_vec())).collect::<::std::vec::Vec<_>>()
+                    );
+                })
+            }
+
+            fn provide_stream<'a>(
+                &'a mut self,
+                key: &'a str,
+                data: impl ::decthings_model::futures::Stream<Item = ::decthings_model::bytes::Bytes> + Send + 'a,
+            ) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ()> + Send + 'a>> {
+                ::std::boxed::Box::pin(async move {
+                    // Same as the unix implementation. Each chunk is sent once the next one is
+                    // available, so that the last chunk can be sent without the continuation
+                    // marker. Like there, streamed weights are not compressed.
+                    let mut data = ::core::pin::pin!(data);
+                    let mut pending = ::decthings_model::bytes::Bytes::new();
+                    while let Some(chunk) = ::decthings_model::futures::StreamExt::next(&mut data).await {
+                        if chunk.len() > 1024usize.pow(3) {
+                            panic!(
+                                "WeightsProvider: Cannot provide a chunk of more than 1 gigabyte in provide_stream."
+                            );
+                        }
+                        if chunk.is_empty() {
+                            continue;
+                        }
+                        let previous = ::core::mem::replace(&mut pending, chunk);
+                        if !previous.is_empty() {
+                            $($path_to_types_root)*::exports::decthings::model::model::WeightsProvider::provide_chunk(
+                                self,
+                                key,
+                                &previous,
+                                true,
+                            );
+                        }
+                    }
+                    $($path_to_types_root)*::exports::decthings::model::model::WeightsProvider::provide_chunk(
+                        self,
+                        key,
+                        &pending,
+                        false,
+                    );
+                })
+            }
+
+            fn inherit<'a>(
+                &'a mut self,
+                keys: &'a [&'a str],
+            ) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ()> + Send + 'a>> {
+                ::std::boxed::Box::pin(async move {
+                    $($path_to_types_root)*::exports::decthings::model::model::WeightsProvider::inherit(
+                        self,
+                        &keys.iter().map(|key| (*key).to_owned()).collect::<::std::vec::Vec<_>>()
+                    );
+                })
+            }
+
+            fn provide_delta<'a>(
+                &'a mut self,
+                key: &'a str,
+                delta: ::decthings_model::bytes::Bytes,
+            ) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ()> + Send + 'a>> {
+                ::std::boxed::Box::pin(async move {
+                    $($path_to_types_root)*::exports::decthings::model::model::WeightsProvider::provide_delta(
+                        self,
+                        key,
+                        &delta,
                     );
                 })
             }
@@ -101,6 +282,19 @@ macro_rules! export_decthings_model {
             }
 
             fn read(&mut self) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::decthings_model::bytes::Bytes> + Send + '_>> {
+                ::std::boxed::Box::pin(async move {
+                    ::decthings_model::decompress_weights(self.inner.read().into())
+                        .unwrap_or_else(|e| panic!("WeightsLoader: {e}"))
+                })
+            }
+
+            fn try_read(&mut self) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = Result<::decthings_model::bytes::Bytes, ::decthings_model::WeightsError>> + Send + '_>> {
+                ::std::boxed::Box::pin(async move {
+                    ::decthings_model::decompress_weights(self.inner.read().into())
+                })
+            }
+
+            fn read_raw(&mut self) -> ::core::pin::Pin<::std::boxed::Box<dyn ::core::future::Future<Output = ::decthings_model::bytes::Bytes> + Send + '_>> {
                 ::std::boxed::Box::pin(async move { self.inner.read().into() })
             }
         }
@@ -110,6 +304,11 @@ macro_rules! export_decthings_model {
                 &self,
                 options: $($path_to_types_root)*::exports::decthings::model::model::EvaluateOptions,
             ) -> Result<::std::vec::Vec<$($path_to_types_root)*::exports::decthings::model::model::EvaluateOutput>, String> {
+                ::decthings_model::wasm_bindings::validate_params(
+                    &_decthings_model::parameter_definitions().evaluate,
+                    &options.params,
+                    |param| &param.name,
+                )?;
                 Ok(
                     ::decthings_model::wasm_bindings::pollster::block_on(
                         T::evaluate(
@@ -121,6 +320,7 @@ macro_rules! export_decthings_model {
                                         position: 0,
                                         amount: param.amount,
                                         total_byte_size: param.total_byte_size,
+                                        shuffle_seed: ::std::sync::Mutex::new(None),
                                         inner: param.data_loader,
                                     },
                                 )).collect(),
@@ -202,6 +402,11 @@ macro_rules! export_decthings_model {
             }
 
             fn train(&self, options: $($path_to_types_root)*::exports::decthings::model::model::TrainOptions) -> Result<(), String> {
+                ::decthings_model::wasm_bindings::validate_params(
+                    &_decthings_model::parameter_definitions().train,
+                    &options.params,
+                    |param| &param.name,
+                )?;
                 ::decthings_model::wasm_bindings::pollster::block_on(
                     T::train(
                         self,
@@ -212,6 +417,7 @@ macro_rules! export_decthings_model {
                                     position: 0,
                                     amount: param.amount,
                                     total_byte_size: param.total_byte_size,
+                                    shuffle_seed: ::std::sync::Mutex::new(None),
                                     inner: param.data_loader,
                                 },
                             )).collect(),
@@ -231,11 +437,17 @@ macro_rules! export_decthings_model {
                         self,
                         ::decthings_model::GetWeightsOptions {
                             weights_provider: options.weights_provider,
+                            instantiated_weights: options.instantiated_weights,
                         }
                     )
                 );
                 Ok(())
             }
+
+            fn dispose(&self) -> Result<(), String> {
+                ::decthings_model::wasm_bindings::pollster::block_on(T::dispose(self));
+                Ok(())
+            }
         }
 
         impl<T: ::decthings_model::ModelBinary> $($path_to_types_root)*::exports::decthings::model::model::Guest for T
@@ -243,9 +455,24 @@ macro_rules! export_decthings_model {
         {
             type Instantiated = T::Instantiated;
 
+            fn get_parameter_definitions() -> $($path_to_types_root)*::exports::decthings::model::model::ParameterDefinitions {
+                let definitions = _decthings_model::parameter_definitions();
+                $($path_to_types_root)*::exports::decthings::model::model::ParameterDefinitions {
+                    initialize_weights: _decthings_model::convert_definitions(&definitions.initialize_weights),
+                    train: _decthings_model::convert_definitions(&definitions.train),
+                    evaluate: _decthings_model::convert_definitions(&definitions.evaluate),
+                    evaluate_output: _decthings_model::convert_definitions(&definitions.evaluate_output),
+                }
+            }
+
             fn initialize_weights(
                 options: $($path_to_types_root)*::exports::decthings::model::model::InitializeWeightsOptions,
             ) -> Result<(), String> {
+                ::decthings_model::wasm_bindings::validate_params(
+                    &_decthings_model::parameter_definitions().initialize_weights,
+                    &options.params,
+                    |param| &param.name,
+                )?;
                 ::decthings_model::wasm_bindings::pollster::block_on(
                     T::initialize_weights(
                         ::decthings_model::InitializeWeightsOptions {
@@ -255,6 +482,7 @@ macro_rules! export_decthings_model {
                                     position: 0,
                                     amount: param.amount,
                                     total_byte_size: param.total_byte_size,
+                                    shuffle_seed: ::std::sync::Mutex::new(None),
                                     inner: param.data_loader,
                                 },
                             )).collect(),
@@ -273,7 +501,8 @@ macro_rules! export_decthings_model {
                                         )).collect(),
                                     },
                                 ))
-                                .collect()
+                                .collect(),
+                            context: _decthings_model::shared_context(),
                         }
                     )
                 );
@@ -299,7 +528,8 @@ macro_rules! export_decthings_model {
                                     ::decthings_model::OtherModel {
                                         mount_path: other_model.mount_path,
                                     },
-                                )).collect()
+                                )).collect(),
+                                context: _decthings_model::shared_context(),
                             }
                         )
                     )
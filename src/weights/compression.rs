@@ -0,0 +1,118 @@
+use super::WeightsError;
+
+const MAGIC: &[u8; 8] = b"\x89DTCOMP\x00";
+const HEADER_LEN: usize = 17;
+
+const ALGORITHM_ZSTD: u8 = 1;
+const ALGORITHM_LZ4: u8 = 2;
+
+/// The decompressed size is read from the header, so it is bounded to avoid a huge allocation
+/// when the header is corrupt. Larger data is not compressed.
+const MAX_DECOMPRESSED_LEN: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Compresses weights with zstd or lz4, depending on the enabled cargo features, preferring zstd if
+/// both are enabled. The compressed data starts with a header that decompress_weights recognizes.
+/// If no compression feature is enabled, if compressing does not make the data smaller, or if the
+/// data is larger than 16 GiB, *data* is returned unchanged.
+///
+/// The weights providers given to models call this for every value, so models do not need to.
+pub fn compress_weights(data: &bytes::Bytes) -> bytes::Bytes {
+    #[cfg(any(feature = "zstd", feature = "lz4"))]
+    if data.len() as u64 <= MAX_DECOMPRESSED_LEN {
+        #[cfg(feature = "zstd")]
+        let (algorithm, compressed) = (
+            ALGORITHM_ZSTD,
+            zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+                .expect("Failed to compress weights"),
+        );
+        #[cfg(not(feature = "zstd"))]
+        let (algorithm, compressed) = (ALGORITHM_LZ4, lz4_flex::block::compress(data));
+
+        if HEADER_LEN + compressed.len() < data.len() {
+            let mut res = Vec::with_capacity(HEADER_LEN + compressed.len());
+            res.extend_from_slice(MAGIC);
+            res.push(algorithm);
+            res.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            res.extend_from_slice(&compressed);
+            return res.into();
+        }
+    }
+    data.clone()
+}
+
+/// Returns true if *data* was compressed by compress_weights.
+pub fn is_compressed_weights(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && &data[..8] == MAGIC
+}
+
+/// Decompresses data created by compress_weights. Data that is not compressed is returned
+/// unchanged.
+pub fn decompress_weights(data: bytes::Bytes) -> Result<bytes::Bytes, WeightsError> {
+    if !is_compressed_weights(&data) {
+        return Ok(data);
+    }
+    let algorithm = data[8];
+    let len = u64::from_le_bytes(data[9..17].try_into().unwrap());
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|_| len <= MAX_DECOMPRESSED_LEN)
+        .ok_or_else(|| {
+            WeightsError::Decompress(format!(
+                "The decompressed size of {len} bytes in the header is too large."
+            ))
+        })?;
+    let decompressed: Result<Vec<u8>, String> = match algorithm {
+        ALGORITHM_ZSTD => {
+            #[cfg(feature = "zstd")]
+            {
+                zstd::bulk::decompress(&data[HEADER_LEN..], len).map_err(|e| e.to_string())
+            }
+            #[cfg(not(feature = "zstd"))]
+            return Err(WeightsError::CompressionNotEnabled { algorithm: "zstd" });
+        }
+        ALGORITHM_LZ4 => {
+            #[cfg(feature = "lz4")]
+            {
+                lz4_flex::block::decompress(&data[HEADER_LEN..], len).map_err(|e| e.to_string())
+            }
+            #[cfg(not(feature = "lz4"))]
+            return Err(WeightsError::CompressionNotEnabled { algorithm: "lz4" });
+        }
+        _ => Err(format!("Unknown compression algorithm {algorithm}.")),
+    };
+    match decompressed {
+        Ok(decompressed) if decompressed.len() == len => Ok(decompressed.into()),
+        Ok(_) => Err(WeightsError::Decompress(
+            "The decompressed size does not match the header.".to_owned(),
+        )),
+        Err(e) => Err(WeightsError::Decompress(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = bytes::Bytes::from(vec![7u8; 4096]);
+        let compressed = compress_weights(&data);
+        assert_eq!(
+            is_compressed_weights(&compressed),
+            cfg!(any(feature = "zstd", feature = "lz4"))
+        );
+        assert_eq!(decompress_weights(compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        let mut data = MAGIC.to_vec();
+        data.push(ALGORITHM_ZSTD);
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        data.extend_from_slice(&[0; 16]);
+        assert!(matches!(
+            decompress_weights(data.into()),
+            Err(WeightsError::Decompress(_))
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+use super::WeightsError;
+
+const MAGIC: &[u8; 4] = b"DTWD";
+
+/// Changed ranges closer than this are merged, since each range costs 16 bytes of header.
+const MERGE_GAP: usize = 16;
+
+/// Creates a patch for WeightsProvider::provide_delta that replaces the weights *base* with *new*.
+/// *base* must be the weights as stored, as returned by WeightsLoader::read_raw, since that is the
+/// data that the host patches. If *base* is compressed, *new* is compressed with
+/// crate::compress_weights before the patch is created, so that the patched weights are compressed
+/// as well and decompressed when read. Otherwise *new* is stored uncompressed, which keeps the
+/// patch small, whether or not a compression feature is enabled.
+///
+/// The patch format is the magic bytes "DTWD", followed by the length of the patched data as a
+/// little endian u64, followed by any number of ranges. Each range is an offset and a length, both
+/// little endian u64, followed by that many bytes that replace the data at the offset. The data is
+/// first truncated or zero-extended to the patched length, and the ranges are then written in
+/// order.
+pub fn create_weights_delta(base: &[u8], new: &[u8]) -> bytes::Bytes {
+    let compressed;
+    let new = if super::is_compressed_weights(base) {
+        compressed = super::compress_weights(&bytes::Bytes::copy_from_slice(new));
+        &compressed[..]
+    } else {
+        new
+    };
+
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    let mut i = 0;
+    while i < new.len() {
+        if base.get(i) == Some(&new[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < new.len() && base.get(i) != Some(&new[i]) {
+            i += 1;
+        }
+        match ranges.last_mut() {
+            Some((_, end)) if start - *end <= MERGE_GAP => *end = i,
+            _ => ranges.push((start, i)),
+        }
+    }
+
+    let changed: usize = ranges.iter().map(|(start, end)| 16 + end - start).sum();
+    let mut res = Vec::with_capacity(12 + changed);
+    res.extend_from_slice(MAGIC);
+    res.extend_from_slice(&(new.len() as u64).to_le_bytes());
+    for (start, end) in ranges {
+        res.extend_from_slice(&(start as u64).to_le_bytes());
+        res.extend_from_slice(&((end - start) as u64).to_le_bytes());
+        res.extend_from_slice(&new[start..end]);
+    }
+    res.into()
+}
+
+/// Applies a patch created by create_weights_delta to *base*.
+pub fn apply_weights_delta(base: &[u8], delta: &[u8]) -> Result<bytes::Bytes, WeightsError> {
+    let invalid = |reason: &str| WeightsError::InvalidDelta(reason.to_owned());
+    let read_u64 = |pos: usize| {
+        delta
+            .get(pos..pos + 8)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("Unexpected end of the delta."))
+    };
+
+    if delta.get(..4) != Some(MAGIC) {
+        return Err(invalid(
+            "The delta does not start with the delta magic bytes.",
+        ));
+    }
+    let len = read_u64(4)?;
+    // Data beyond the end of base is always part of a range, so a valid delta is never longer
+    // than that. This avoids a huge allocation for a corrupt length.
+    if len > base.len().saturating_add(delta.len()) {
+        return Err(invalid("The patched length exceeds the data of the delta."));
+    }
+    let mut res = base[..len.min(base.len())].to_vec();
+    res.resize(len, 0);
+
+    let mut pos = 12;
+    while pos < delta.len() {
+        let offset = read_u64(pos)?;
+        let amount = read_u64(pos + 8)?;
+        pos += 16;
+        let data = delta
+            .get(pos..pos.saturating_add(amount))
+            .ok_or_else(|| invalid("Unexpected end of the delta."))?;
+        let target = res
+            .get_mut(offset..offset.saturating_add(amount))
+            .ok_or_else(|| invalid("A range of the delta is outside of the patched data."))?;
+        target.copy_from_slice(data);
+        pos += amount;
+    }
+    Ok(res.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(base: &[u8], new: &[u8]) -> bytes::Bytes {
+        let delta = create_weights_delta(base, new);
+        assert_eq!(apply_weights_delta(base, &delta).unwrap(), new);
+        delta
+    }
+
+    #[test]
+    fn unchanged() {
+        let data = [1, 2, 3, 4];
+        assert_eq!(round_trip(&data, &data).len(), 12);
+        assert_eq!(round_trip(&[], &[]).len(), 12);
+    }
+
+    #[test]
+    fn changed_ranges() {
+        let base: Vec<u8> = (0..200).collect();
+        let mut new = base.clone();
+        new[3] = 0;
+        new[150..160].fill(0);
+        let delta = round_trip(&base, &new);
+        assert_eq!(delta.len(), 12 + 16 + 1 + 16 + 10);
+    }
+
+    #[test]
+    fn merges_close_ranges() {
+        let base = [0u8; 64];
+        let mut new = base;
+        new[10] = 1;
+        new[20] = 1;
+        let delta = round_trip(&base, &new);
+        assert_eq!(delta.len(), 12 + 16 + 11);
+    }
+
+    #[test]
+    fn changes_length() {
+        round_trip(&[1, 2, 3, 4], &[1, 2]);
+        round_trip(&[1, 2], &[1, 2, 0, 0, 5]);
+        round_trip(&[], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_invalid_deltas() {
+        let base = [0u8; 8];
+        let delta = create_weights_delta(&base, &[1u8; 8]);
+        let invalid = |delta: &[u8]| {
+            matches!(
+                apply_weights_delta(&base, delta),
+                Err(WeightsError::InvalidDelta(_))
+            )
+        };
+        assert!(invalid(&delta[..delta.len() - 1]));
+        assert!(invalid(&delta[..20]));
+        assert!(invalid(b"XXXX"));
+
+        let mut out_of_range = delta.to_vec();
+        out_of_range[12..20].copy_from_slice(&4u64.to_le_bytes());
+        assert!(invalid(&out_of_range));
+
+        let mut too_long = delta.to_vec();
+        too_long[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(invalid(&too_long));
+    }
+
+    #[test]
+    fn compressed_weights_stay_compressed() {
+        let base = crate::compress_weights(&bytes::Bytes::from(vec![1u8; 4096]));
+        let new = vec![2u8; 4096];
+        let patched = apply_weights_delta(&base, &create_weights_delta(&base, &new)).unwrap();
+        assert_eq!(
+            crate::is_compressed_weights(&patched),
+            crate::is_compressed_weights(&base)
+        );
+        assert_eq!(crate::decompress_weights(patched).unwrap(), new);
+    }
+
+    #[test]
+    fn uncompressed_weights_stay_uncompressed() {
+        // Compressible, but patched as it is regardless of the enabled features.
+        let base = vec![1u8; 4096];
+        let mut new = base.clone();
+        new[100] = 2;
+        let delta = round_trip(&base, &new);
+        assert_eq!(delta.len(), 12 + 16 + 1);
+    }
+}
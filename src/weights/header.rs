@@ -0,0 +1,105 @@
+use super::WeightsError;
+
+const MAGIC: &[u8; 4] = b"DTWT";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 13;
+
+/// The CRC-32 (IEEE) checksum of *data*.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// Prepends a header to *data*, consisting of a magic number, the format version, *schema_version*
+/// and a checksum of *data*.
+pub fn encode_weights_blob(schema_version: u32, data: &[u8]) -> bytes::Bytes {
+    let mut res = Vec::with_capacity(HEADER_LEN + data.len());
+    res.extend_from_slice(MAGIC);
+    res.push(FORMAT_VERSION);
+    res.extend_from_slice(&schema_version.to_le_bytes());
+    res.extend_from_slice(&crc32(data).to_le_bytes());
+    res.extend_from_slice(data);
+    res.into()
+}
+
+/// Verifies the header of a blob created by encode_weights_blob, and returns the schema version and
+/// the data after the header. *key* is only used in errors.
+pub fn decode_weights_blob(
+    key: &str,
+    blob: &bytes::Bytes,
+) -> Result<(u32, bytes::Bytes), WeightsError> {
+    if blob.len() < HEADER_LEN || &blob[..4] != MAGIC {
+        return Err(WeightsError::MissingHeader {
+            key: key.to_owned(),
+        });
+    }
+    if blob[4] != FORMAT_VERSION {
+        return Err(WeightsError::UnsupportedFormat {
+            key: key.to_owned(),
+            version: blob[4],
+        });
+    }
+    let schema_version = u32::from_le_bytes(blob[5..9].try_into().unwrap());
+    let checksum = u32::from_le_bytes(blob[9..13].try_into().unwrap());
+    let data = blob.slice(HEADER_LEN..);
+    if crc32(&data) != checksum {
+        return Err(WeightsError::ChecksumMismatch {
+            key: key.to_owned(),
+        });
+    }
+    Ok((schema_version, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_known_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7be43);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414fa339
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let blob = encode_weights_blob(7, b"data");
+        assert_eq!(blob.len(), HEADER_LEN + 4);
+        let (version, data) = decode_weights_blob("key", &blob).unwrap();
+        assert_eq!(version, 7);
+        assert_eq!(data, &b"data"[..]);
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        let mut blob = encode_weights_blob(0, b"data").to_vec();
+        *blob.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decode_weights_blob("key", &blob.into()),
+            Err(WeightsError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_other_format_versions() {
+        let mut blob = encode_weights_blob(0, b"data").to_vec();
+        blob[4] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            decode_weights_blob("key", &blob.into()),
+            Err(WeightsError::UnsupportedFormat { version, .. }) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn detects_missing_header() {
+        for blob in [&b""[..], b"DTWT", b"not a header at all"] {
+            assert!(matches!(
+                decode_weights_blob("key", &bytes::Bytes::copy_from_slice(blob)),
+                Err(WeightsError::MissingHeader { .. })
+            ));
+        }
+    }
+}
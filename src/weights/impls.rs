@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use decthings_api::tensor::{DecthingsElementType, DecthingsTensor, OwnedDecthingsTensor};
+use ndarray::{Array, ArrayD, ArrayView1, CowArray, Dimension, IxDyn};
+
+use super::{ModelWeights, WeightsError};
+
+/// An element type that can be stored in weights.
+pub trait WeightsElement: Clone + Send + Sync + 'static {
+    const TYPE: DecthingsElementType;
+
+    fn to_tensor(array: CowArray<'_, Self, IxDyn>) -> DecthingsTensor<'_>;
+
+    fn from_tensor(tensor: DecthingsTensor<'_>) -> Option<CowArray<'_, Self, IxDyn>>;
+}
+
+macro_rules! weights_element {
+    ($($t:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl WeightsElement for $t {
+                const TYPE: DecthingsElementType = DecthingsElementType::$variant;
+
+                fn to_tensor(array: CowArray<'_, Self, IxDyn>) -> DecthingsTensor<'_> {
+                    DecthingsTensor::$variant(array)
+                }
+
+                fn from_tensor(tensor: DecthingsTensor<'_>) -> Option<CowArray<'_, Self, IxDyn>> {
+                    match tensor {
+                        DecthingsTensor::$variant(array) => Some(array),
+                        _ => None,
+                    }
+                }
+            }
+
+            impl ModelWeights for $t {
+                fn save_weights(&self, prefix: &str, out: &mut Vec<(String, bytes::Bytes)>) {
+                    save_array(prefix, ndarray::arr0(*self).into_dyn().into(), out);
+                }
+
+                fn load_weights(
+                    prefix: &str,
+                    weights: &HashMap<String, bytes::Bytes>,
+                ) -> Result<Self, WeightsError> {
+                    let array = load_array::<Self>(prefix, weights)?;
+                    if array.ndim() != 0 {
+                        return Err(WeightsError::WrongShape {
+                            key: prefix.to_owned(),
+                            shape: array.shape().to_vec(),
+                        });
+                    }
+                    Ok(array.into_iter().next().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+weights_element!(
+    f32 => F32,
+    f64 => F64,
+    i8 => I8,
+    i16 => I16,
+    i32 => I32,
+    i64 => I64,
+    u8 => U8,
+    u16 => U16,
+    u32 => U32,
+    u64 => U64,
+    bool => Boolean,
+);
+
+fn save_array<T: WeightsElement>(
+    key: &str,
+    array: CowArray<'_, T, IxDyn>,
+    out: &mut Vec<(String, bytes::Bytes)>,
+) {
+    out.push((key.to_owned(), T::to_tensor(array).serialize().into()));
+}
+
+fn load_array<T: WeightsElement>(
+    key: &str,
+    weights: &HashMap<String, bytes::Bytes>,
+) -> Result<ArrayD<T>, WeightsError> {
+    let data = weights.get(key).ok_or_else(|| WeightsError::Missing {
+        key: key.to_owned(),
+    })?;
+    let tensor =
+        OwnedDecthingsTensor::from_bytes(data.clone()).map_err(|error| WeightsError::Decode {
+            key: key.to_owned(),
+            error,
+        })?;
+    let tensor = tensor.tensor();
+    let found = tensor.typ();
+    T::from_tensor(tensor)
+        .map(|array| array.into_owned())
+        .ok_or_else(|| WeightsError::WrongType {
+            key: key.to_owned(),
+            expected: T::TYPE,
+            found,
+        })
+}
+
+impl<T: WeightsElement, D: Dimension> ModelWeights for Array<T, D> {
+    fn save_weights(&self, prefix: &str, out: &mut Vec<(String, bytes::Bytes)>) {
+        save_array(prefix, self.view().into_dyn().into(), out);
+    }
+
+    fn load_weights(
+        prefix: &str,
+        weights: &HashMap<String, bytes::Bytes>,
+    ) -> Result<Self, WeightsError> {
+        let array = load_array::<T>(prefix, weights)?;
+        let shape = array.shape().to_vec();
+        array
+            .into_dimensionality()
+            .map_err(|_| WeightsError::WrongShape {
+                key: prefix.to_owned(),
+                shape,
+            })
+    }
+}
+
+impl<T: WeightsElement> ModelWeights for Vec<T> {
+    fn save_weights(&self, prefix: &str, out: &mut Vec<(String, bytes::Bytes)>) {
+        save_array(prefix, ArrayView1::from(self).into_dyn().into(), out);
+    }
+
+    fn load_weights(
+        prefix: &str,
+        weights: &HashMap<String, bytes::Bytes>,
+    ) -> Result<Self, WeightsError> {
+        let array = Array::<T, ndarray::Ix1>::load_weights(prefix, weights)?;
+        if array.is_standard_layout() {
+            Ok(array.into_raw_vec())
+        } else {
+            Ok(array.to_vec())
+        }
+    }
+}
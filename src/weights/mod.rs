@@ -0,0 +1,412 @@
+mod compression;
+mod delta;
+mod header;
+mod impls;
+mod safetensors;
+
+use std::collections::HashMap;
+
+use decthings_api::tensor::{DecthingsElementType, DeserializeDecthingsTensorError};
+
+use crate::{OtherModelWithWeights, WeightsLoader, WeightsProvider};
+
+pub use compression::*;
+pub use delta::*;
+pub use header::*;
+pub use impls::WeightsElement;
+pub use safetensors::*;
+
+#[cfg(feature = "derive")]
+pub use decthings_model_derive::ModelWeights;
+
+/// A model state that can be stored as weights. Each value is stored under its own key as a
+/// serialized DecthingsTensor, and nested values use dotted keys, such as "encoder.weight".
+///
+/// Implemented for ndarray arrays, Vec and scalars of the numeric element types, and for structs
+/// using `#[derive(ModelWeights)]`. The derive macro accepts `#[weights(schema_version = 2)]`,
+/// `#[weights(migrate = path::to::function)]` and `#[weights(accept_headerless)]` on the struct to
+/// set SCHEMA_VERSION, migrate and ACCEPT_HEADERLESS.
+pub trait ModelWeights: Sized {
+    /// The version of the layout of the weights. Increase it when the weights change in a way that
+    /// older weights cannot be loaded, and implement migrate to convert them. Only the version of
+    /// the outermost state is used.
+    const SCHEMA_VERSION: u32 = 0;
+
+    /// Whether from_weights accepts keys without a header, such as weights saved before headers
+    /// were added. Such keys are taken as schema version 0, and are converted by migrate if
+    /// SCHEMA_VERSION is not 0. Since nothing about them can be verified, from_weights fails with
+    /// WeightsError::MissingHeader for them unless this is set.
+    const ACCEPT_HEADERLESS: bool = false;
+
+    /// Appends the weights of self to *out*. *prefix* is the key of self, and is empty for the
+    /// outermost state.
+    fn save_weights(&self, prefix: &str, out: &mut Vec<(String, bytes::Bytes)>);
+
+    /// Reads self from *weights*, where *prefix* is the key of self.
+    fn load_weights(
+        prefix: &str,
+        weights: &HashMap<String, bytes::Bytes>,
+    ) -> Result<Self, WeightsError>;
+
+    /// Converts weights stored with schema version *from_version* to the current layout, before
+    /// they are loaded. The weights have already had their headers verified and removed. By
+    /// default, weights of another schema version cannot be loaded.
+    fn migrate(
+        from_version: u32,
+        weights: &mut HashMap<String, bytes::Bytes>,
+    ) -> Result<(), WeightsError> {
+        let _ = weights;
+        Err(WeightsError::SchemaMismatch {
+            expected: Self::SCHEMA_VERSION,
+            found: from_version,
+        })
+    }
+
+    /// Returns the weights of self as (key, data) pairs. Each value starts with a header holding
+    /// SCHEMA_VERSION and a checksum, see encode_weights_blob.
+    fn to_weights(&self) -> Vec<(String, bytes::Bytes)> {
+        let mut out = vec![];
+        self.save_weights("", &mut out);
+        for (_, data) in &mut out {
+            *data = encode_weights_blob(Self::SCHEMA_VERSION, data);
+        }
+        out
+    }
+
+    /// Reads self from a map of keys to data, such as the one returned by read_weights. The
+    /// headers written by to_weights are verified, and migrate is called if the weights were
+    /// stored with another schema version. Keys without a header are rejected, unless
+    /// ACCEPT_HEADERLESS is set.
+    fn from_weights(weights: &HashMap<String, bytes::Bytes>) -> Result<Self, WeightsError> {
+        let mut version = None;
+        let mut stripped = HashMap::with_capacity(weights.len());
+        for (key, blob) in weights {
+            let (found, data) = match decode_weights_blob(key, blob) {
+                Err(WeightsError::MissingHeader { .. }) if Self::ACCEPT_HEADERLESS => {
+                    (0, blob.clone())
+                }
+                res => res?,
+            };
+            match version {
+                Some(expected) if expected != found => {
+                    return Err(WeightsError::SchemaMismatch { expected, found });
+                }
+                _ => version = Some(found),
+            }
+            stripped.insert(key.clone(), data);
+        }
+        if let Some(version) = version.filter(|&x| x != Self::SCHEMA_VERSION) {
+            Self::migrate(version, &mut stripped)?;
+        }
+        Self::load_weights("", &stripped)
+    }
+}
+
+/// Returns the key of the value *name* within the value with key *prefix*.
+#[doc(hidden)]
+pub fn weights_key(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_owned()
+    } else if name.is_empty() {
+        prefix.to_owned()
+    } else {
+        format!("{prefix}.{name}")
+    }
+}
+
+#[derive(Debug)]
+pub enum WeightsError {
+    /// No weights were stored under *key*.
+    Missing { key: String },
+    /// The data under *key* could not be decoded as a tensor.
+    Decode {
+        key: String,
+        error: DeserializeDecthingsTensorError,
+    },
+    /// The tensor under *key* has element type *found*, but *expected* was required.
+    WrongType {
+        key: String,
+        expected: DecthingsElementType,
+        found: DecthingsElementType,
+    },
+    /// The tensor under *key* has a shape that does not fit the value it is loaded into.
+    WrongShape { key: String, shape: Vec<usize> },
+    /// The data under *key* does not start with the header written by encode_weights_blob.
+    MissingHeader { key: String },
+    /// The header of the data under *key* has a format *version* that is not supported.
+    UnsupportedFormat { key: String, version: u8 },
+    /// The data under *key* does not match the checksum in its header.
+    ChecksumMismatch { key: String },
+    /// The weights were stored with schema version *found*, but *expected* was required.
+    SchemaMismatch { expected: u32, found: u32 },
+    /// The weights were compressed with *algorithm*, but the cargo feature of that algorithm is
+    /// not enabled.
+    CompressionNotEnabled { algorithm: &'static str },
+    /// The weights could not be decompressed.
+    Decompress(String),
+    /// A delta created by create_weights_delta could not be applied.
+    InvalidDelta(String),
+}
+
+impl std::fmt::Display for WeightsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing { key } => write!(f, "No weights were stored under the key \"{key}\"."),
+            Self::Decode { key, error } => {
+                write!(
+                    f,
+                    "Failed to decode the weights \"{key}\" as a tensor: {error:?}"
+                )
+            }
+            Self::WrongType {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The weights \"{key}\" have element type {found}, but {expected} was expected."
+            ),
+            Self::WrongShape { key, shape } => write!(
+                f,
+                "The weights \"{key}\" have shape {shape:?}, which does not fit the value they are loaded into."
+            ),
+            Self::MissingHeader { key } => write!(
+                f,
+                "The weights \"{key}\" do not start with a weights header. They may have been stored without using ModelWeights, in which case ModelWeights::ACCEPT_HEADERLESS allows loading them."
+            ),
+            Self::UnsupportedFormat { key, version } => write!(
+                f,
+                "The weights \"{key}\" have format version {version}, which is not supported."
+            ),
+            Self::ChecksumMismatch { key } => write!(
+                f,
+                "The weights \"{key}\" do not match their checksum, so they are corrupt."
+            ),
+            Self::SchemaMismatch { expected, found } => write!(
+                f,
+                "The weights were stored with schema version {found}, but version {expected} was expected."
+            ),
+            Self::CompressionNotEnabled { algorithm } => write!(
+                f,
+                "The weights are compressed with {algorithm}, but the \"{algorithm}\" feature of decthings-model is not enabled."
+            ),
+            Self::Decompress(reason) => write!(f, "Failed to decompress the weights: {reason}"),
+            Self::InvalidDelta(reason) => write!(f, "Invalid weights delta: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for WeightsError {}
+
+/// Provides the weights of *state* to *provider*. The keys are those of ModelWeights::to_weights.
+pub async fn save_weights(state: &impl ModelWeights, provider: &mut impl WeightsProvider) {
+    provider.provide_all(&state.to_weights()).await;
+}
+
+/// Reads all of *weights*, for example InstantiateModelOptions::weights, into a map of keys to
+/// data.
+pub async fn read_weights(
+    weights: &mut HashMap<String, impl WeightsLoader>,
+) -> HashMap<String, bytes::Bytes> {
+    futures::future::join_all(
+        weights
+            .iter_mut()
+            .map(|(key, loader)| async move { (key.clone(), loader.read().await) }),
+    )
+    .await
+    .into_iter()
+    .collect()
+}
+
+/// Reads all of *weights* and loads a state of type *S* from them.
+pub async fn load_weights<S: ModelWeights>(
+    weights: &mut HashMap<String, impl WeightsLoader>,
+) -> Result<S, WeightsError> {
+    let weights =
+        futures::future::try_join_all(weights.iter_mut().map(|(key, loader)| async move {
+            Ok::<_, WeightsError>((key.clone(), loader.try_read().await?))
+        }))
+        .await?;
+    S::from_weights(&weights.into_iter().collect())
+}
+
+impl<WL: WeightsLoader> OtherModelWithWeights<WL> {
+    /// The keys of the weights of the other model and their sizes in bytes, sorted by key. The
+    /// sizes are of the stored data, which is smaller than the read data if it is compressed.
+    pub fn keys(&self) -> Vec<(&str, u64)> {
+        let mut keys: Vec<_> = self
+            .weights
+            .iter()
+            .map(|(key, loader)| (key.as_str(), loader.byte_size()))
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Reads all weights of the other model and loads a state of type *S* from them, which works
+    /// if the other model saved its weights with ModelWeights.
+    pub async fn load<S: ModelWeights>(&mut self) -> Result<S, WeightsError> {
+        load_weights(&mut self.weights).await
+    }
+
+    /// Provides the weights of the other model under *keys* to *provider*, unchanged and under the
+    /// same keys. The data is passed on as it is stored, with WeightsLoader::read_raw, so it is
+    /// neither decoded nor decompressed, and is memory mapped when possible. It is streamed with
+    /// WeightsProvider::provide_stream in chunks of at most 1 GiB, so it is not copied or
+    /// compressed again, and may be of any size. Compressed weights stay compressed.
+    pub async fn copy_to(
+        &mut self,
+        keys: &[&str],
+        provider: &mut impl WeightsProvider,
+    ) -> Result<(), WeightsError> {
+        if let Some(key) = keys.iter().find(|key| !self.weights.contains_key(**key)) {
+            return Err(WeightsError::Missing {
+                key: (*key).to_owned(),
+            });
+        }
+        const CHUNK_SIZE: usize = 1024 * 1024 * 1024;
+        for key in keys {
+            let data = self.weights.get_mut(*key).unwrap().try_read_raw().await?;
+            let chunks = (0..data.len())
+                .step_by(CHUNK_SIZE)
+                .map(|start| data.slice(start..(start + CHUNK_SIZE).min(data.len())));
+            provider
+                .provide_stream(key, futures::stream::iter(chunks))
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_weights_round_trip() {
+        let state = vec![1.0f32, 2.0, 3.0];
+        let weights: HashMap<_, _> = state.to_weights().into_iter().collect();
+        assert_eq!(Vec::<f32>::from_weights(&weights).unwrap(), state);
+    }
+
+    /// Weights that were saved without headers, as before ModelWeights::to_weights added them.
+    fn headerless(state: &impl ModelWeights) -> HashMap<String, bytes::Bytes> {
+        let mut weights = vec![];
+        state.save_weights("", &mut weights);
+        weights.into_iter().collect()
+    }
+
+    #[test]
+    fn from_weights_rejects_keys_without_header() {
+        let weights = headerless(&vec![1u8, 2, 3]);
+        assert!(matches!(
+            Vec::<u8>::from_weights(&weights),
+            Err(WeightsError::MissingHeader { key }) if key.is_empty()
+        ));
+    }
+
+    struct Headerless<const SCHEMA_VERSION: u32>(Vec<u8>);
+
+    impl<const SCHEMA_VERSION: u32> ModelWeights for Headerless<SCHEMA_VERSION> {
+        const SCHEMA_VERSION: u32 = SCHEMA_VERSION;
+        const ACCEPT_HEADERLESS: bool = true;
+
+        fn save_weights(&self, prefix: &str, out: &mut Vec<(String, bytes::Bytes)>) {
+            self.0.save_weights(prefix, out);
+        }
+
+        fn load_weights(
+            prefix: &str,
+            weights: &HashMap<String, bytes::Bytes>,
+        ) -> Result<Self, WeightsError> {
+            Ok(Self(ModelWeights::load_weights(prefix, weights)?))
+        }
+
+        fn migrate(
+            from_version: u32,
+            weights: &mut HashMap<String, bytes::Bytes>,
+        ) -> Result<(), WeightsError> {
+            assert_eq!(from_version, 0);
+            let data = weights.remove("").unwrap();
+            let mut state = Vec::<u8>::load_weights("", &HashMap::from([("".to_owned(), data)]))?;
+            state.reverse();
+            let mut out = vec![];
+            state.save_weights("", &mut out);
+            weights.extend(out);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn from_weights_accepts_keys_without_header_as_version_0() {
+        let weights = headerless(&vec![1u8, 2, 3]);
+        assert_eq!(
+            Headerless::<0>::from_weights(&weights).unwrap().0,
+            [1, 2, 3]
+        );
+        assert_eq!(
+            Headerless::<1>::from_weights(&weights).unwrap().0,
+            [3, 2, 1]
+        );
+
+        // A mix of keys with and without headers is of two schema versions.
+        let mut weights = weights;
+        weights.insert("b".to_owned(), encode_weights_blob(1, b""));
+        assert!(matches!(
+            Headerless::<1>::from_weights(&weights),
+            Err(WeightsError::SchemaMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn from_weights_rejects_mixed_schema_versions() {
+        let weights = HashMap::from([
+            ("a".to_owned(), encode_weights_blob(1, b"")),
+            ("b".to_owned(), encode_weights_blob(2, b"")),
+        ]);
+        assert!(matches!(
+            Vec::<u8>::from_weights(&weights),
+            Err(WeightsError::SchemaMismatch { .. })
+        ));
+    }
+
+    #[derive(Default)]
+    struct Collected(HashMap<String, bytes::Bytes>);
+
+    impl WeightsProvider for Collected {
+        fn provide_all<'a>(
+            &'a mut self,
+            data: &'a [(impl AsRef<str> + Send + Sync + 'a, bytes::Bytes)],
+        ) -> futures::future::BoxFuture<'a, ()> {
+            for (key, data) in data {
+                self.0.insert(key.as_ref().to_owned(), data.clone());
+            }
+            Box::pin(async {})
+        }
+    }
+
+    #[test]
+    fn copy_to_passes_on_the_stored_data() {
+        // Compressed with an unknown algorithm, so the data can only be passed on as it is stored.
+        let mut stored = b"\x89DTCOMP\x00\xff".to_vec();
+        stored.extend_from_slice(&4u64.to_le_bytes());
+        stored.extend_from_slice(b"data");
+        let mut other = OtherModelWithWeights {
+            mount_path: String::new(),
+            weights: HashMap::from([(
+                "a".to_owned(),
+                crate::InMemoryWeightsLoader::new(stored.clone().into()),
+            )]),
+        };
+        let loader = other.weights.get_mut("a").unwrap();
+        assert!(futures::executor::block_on(loader.try_read()).is_err());
+
+        let mut provider = Collected::default();
+        futures::executor::block_on(other.copy_to(&["a"], &mut provider)).unwrap();
+        assert_eq!(provider.0["a"], stored);
+        assert!(matches!(
+            futures::executor::block_on(other.copy_to(&["b"], &mut provider)),
+            Err(WeightsError::Missing { key }) if key == "b"
+        ));
+    }
+}
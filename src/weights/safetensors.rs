@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+
+use decthings_api::tensor::{DecthingsElementType, DecthingsTensor};
+use ndarray::{Array, ArrayView, CowArray, IxDyn};
+
+use crate::{WeightsLoader, WeightsProvider};
+
+/// Element types that can be read from safetensors.
+pub trait SafetensorsElement: Clone + Send + Sync + 'static {
+    /// The name of the type in a safetensors header, such as "F32".
+    const DTYPE: &'static str;
+
+    #[doc(hidden)]
+    fn cast(data: &[u8]) -> Option<&[Self]>;
+
+    #[doc(hidden)]
+    fn from_le_bytes(data: &[u8]) -> Self;
+}
+
+macro_rules! safetensors_element {
+    ($($t:ty => $dtype:literal),* $(,)?) => {
+        $(
+            impl SafetensorsElement for $t {
+                const DTYPE: &'static str = $dtype;
+
+                fn cast(data: &[u8]) -> Option<&[Self]> {
+                    #[cfg(target_endian = "little")]
+                    {
+                        byte_slice_cast::AsSliceOf::as_slice_of::<$t>(data).ok()
+                    }
+                    #[cfg(not(target_endian = "little"))]
+                    {
+                        let _ = data;
+                        None
+                    }
+                }
+
+                fn from_le_bytes(data: &[u8]) -> Self {
+                    <$t>::from_le_bytes(data.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+safetensors_element!(
+    f32 => "F32",
+    f64 => "F64",
+    i8 => "I8",
+    i16 => "I16",
+    i32 => "I32",
+    i64 => "I64",
+    u8 => "U8",
+    u16 => "U16",
+    u32 => "U32",
+    u64 => "U64",
+);
+
+impl SafetensorsElement for bool {
+    const DTYPE: &'static str = "BOOL";
+
+    fn cast(_data: &[u8]) -> Option<&[Self]> {
+        None
+    }
+
+    fn from_le_bytes(data: &[u8]) -> Self {
+        data[0] != 0
+    }
+}
+
+fn element_size(dtype: &str) -> Option<usize> {
+    match dtype {
+        "BOOL" | "U8" | "I8" | "F8_E4M3" | "F8_E5M2" => Some(1),
+        "U16" | "I16" | "F16" | "BF16" => Some(2),
+        "U32" | "I32" | "F32" => Some(4),
+        "U64" | "I64" | "F64" => Some(8),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum SafetensorsError {
+    /// The data is not in the safetensors format.
+    Invalid(String),
+    /// There is no tensor named *name*.
+    Missing { name: String },
+    /// The tensor *name* has dtype *found*, but *expected* was required.
+    WrongDtype {
+        name: String,
+        expected: &'static str,
+        found: String,
+    },
+    /// The tensor *name* has shape *found*, but *expected* was required.
+    WrongShape {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    /// Tensors of element type *typ* cannot be stored in safetensors.
+    UnsupportedType(DecthingsElementType),
+}
+
+impl std::fmt::Display for SafetensorsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(reason) => write!(f, "Invalid safetensors data: {reason}"),
+            Self::Missing { name } => write!(f, "There is no tensor named \"{name}\"."),
+            Self::WrongDtype {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The tensor \"{name}\" has dtype {found}, but {expected} was expected."
+            ),
+            Self::WrongShape {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The tensor \"{name}\" has shape {found:?}, but {expected:?} was expected."
+            ),
+            Self::UnsupportedType(typ) => {
+                write!(f, "Tensors of type {typ} cannot be stored as safetensors.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SafetensorsError {}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct TensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: (usize, usize),
+}
+
+/// Tensors parsed from data in the safetensors format. The tensors refer to the parsed data, so
+/// no copies are made unless needed for alignment.
+pub struct SafeTensors {
+    data: bytes::Bytes,
+    tensors: HashMap<String, TensorInfo>,
+    metadata: HashMap<String, String>,
+}
+
+impl SafeTensors {
+    /// Parses *data* in the safetensors format, validating the header.
+    pub fn parse(data: bytes::Bytes) -> Result<Self, SafetensorsError> {
+        let invalid = |reason: &str| SafetensorsError::Invalid(reason.to_owned());
+
+        let header_len = data
+            .get(..8)
+            .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+            .ok_or_else(|| invalid("The data is too short to contain a header."))?;
+        let header_end = usize::try_from(header_len)
+            .ok()
+            .and_then(|x| x.checked_add(8))
+            .filter(|&x| x <= data.len())
+            .ok_or_else(|| invalid("The header length exceeds the data."))?;
+
+        let mut header: HashMap<String, serde_json::Value> =
+            serde_json::from_slice(&data[8..header_end])
+                .map_err(|e| SafetensorsError::Invalid(format!("The header is not valid: {e}")))?;
+        let metadata = match header.remove("__metadata__") {
+            Some(metadata) => serde_json::from_value(metadata)
+                .map_err(|e| SafetensorsError::Invalid(format!("Invalid metadata: {e}")))?,
+            None => HashMap::new(),
+        };
+
+        let body_len = data.len() - header_end;
+        let mut tensors = HashMap::with_capacity(header.len());
+        for (name, info) in header {
+            let info: TensorInfo = serde_json::from_value(info).map_err(|e| {
+                SafetensorsError::Invalid(format!("Invalid header for tensor \"{name}\": {e}"))
+            })?;
+            let (start, end) = info.data_offsets;
+            if start > end || end > body_len {
+                return Err(SafetensorsError::Invalid(format!(
+                    "The data offsets of tensor \"{name}\" are out of range."
+                )));
+            }
+            if let Some(size) = element_size(&info.dtype) {
+                let numel = info.shape.iter().try_fold(1usize, |a, &b| a.checked_mul(b));
+                if numel.and_then(|x| x.checked_mul(size)) != Some(end - start) {
+                    return Err(SafetensorsError::Invalid(format!(
+                        "The size of tensor \"{name}\" does not match its shape and dtype."
+                    )));
+                }
+            }
+            tensors.insert(name, info);
+        }
+
+        let mut offsets: Vec<_> = tensors
+            .iter()
+            .map(|(name, info)| (info.data_offsets, name))
+            .collect();
+        offsets.sort_unstable();
+        for pair in offsets.windows(2) {
+            let ((_, prev_end), prev_name) = pair[0];
+            let ((start, _), name) = pair[1];
+            if start < prev_end {
+                return Err(SafetensorsError::Invalid(format!(
+                    "The data of tensor \"{name}\" overlaps the data of tensor \"{prev_name}\"."
+                )));
+            }
+        }
+
+        Ok(Self {
+            data: data.slice(header_end..),
+            tensors,
+            metadata,
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tensors.keys().map(|x| x.as_str())
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.metadata
+    }
+
+    /// Returns the dtype of the tensor *name*, such as "F32".
+    pub fn dtype(&self, name: &str) -> Option<&str> {
+        self.tensors.get(name).map(|x| x.dtype.as_str())
+    }
+
+    pub fn shape(&self, name: &str) -> Option<&[usize]> {
+        self.tensors.get(name).map(|x| x.shape.as_slice())
+    }
+
+    /// Returns the raw little endian data of the tensor *name*.
+    pub fn raw(&self, name: &str) -> Option<bytes::Bytes> {
+        let (start, end) = self.tensors.get(name)?.data_offsets;
+        Some(self.data.slice(start..end))
+    }
+
+    /// Returns the tensor *name*, checking that its dtype matches *T*. The returned array borrows
+    /// the parsed data if it is suitably aligned, and is otherwise a copy.
+    pub fn tensor<T: SafetensorsElement>(
+        &self,
+        name: &str,
+    ) -> Result<CowArray<'_, T, IxDyn>, SafetensorsError> {
+        let info = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| SafetensorsError::Missing {
+                name: name.to_owned(),
+            })?;
+        if info.dtype != T::DTYPE {
+            return Err(SafetensorsError::WrongDtype {
+                name: name.to_owned(),
+                expected: T::DTYPE,
+                found: info.dtype.clone(),
+            });
+        }
+        let data = &self.data[info.data_offsets.0..info.data_offsets.1];
+        let shape = IxDyn(&info.shape);
+        Ok(match T::cast(data) {
+            Some(slice) => ArrayView::from_shape(shape, slice).unwrap().into(),
+            None => {
+                let size = element_size(T::DTYPE).unwrap();
+                let values = data.chunks_exact(size).map(T::from_le_bytes).collect();
+                Array::from_shape_vec(shape, values).unwrap().into()
+            }
+        })
+    }
+
+    /// Same as tensor, but also checks that the shape of the tensor is *shape*.
+    pub fn tensor_with_shape<T: SafetensorsElement>(
+        &self,
+        name: &str,
+        shape: &[usize],
+    ) -> Result<CowArray<'_, T, IxDyn>, SafetensorsError> {
+        let tensor = self.tensor(name)?;
+        if tensor.shape() != shape {
+            return Err(SafetensorsError::WrongShape {
+                name: name.to_owned(),
+                expected: shape.to_vec(),
+                found: tensor.shape().to_vec(),
+            });
+        }
+        Ok(tensor)
+    }
+}
+
+fn append_le_bytes(
+    tensor: &DecthingsTensor<'_>,
+    out: &mut Vec<u8>,
+) -> Result<(), SafetensorsError> {
+    macro_rules! append {
+        ($array:expr) => {
+            $array
+                .iter()
+                .for_each(|x| out.extend_from_slice(&x.to_le_bytes()))
+        };
+    }
+    match tensor {
+        DecthingsTensor::F32(array) => append!(array),
+        DecthingsTensor::F64(array) => append!(array),
+        DecthingsTensor::I8(array) => append!(array),
+        DecthingsTensor::I16(array) => append!(array),
+        DecthingsTensor::I32(array) => append!(array),
+        DecthingsTensor::I64(array) => append!(array),
+        DecthingsTensor::U8(array) => append!(array),
+        DecthingsTensor::U16(array) => append!(array),
+        DecthingsTensor::U32(array) => append!(array),
+        DecthingsTensor::U64(array) => append!(array),
+        DecthingsTensor::Boolean(array) => out.extend(array.iter().map(|&x| x as u8)),
+        other => return Err(SafetensorsError::UnsupportedType(other.typ())),
+    }
+    Ok(())
+}
+
+fn dtype_of(tensor: &DecthingsTensor<'_>) -> Result<&'static str, SafetensorsError> {
+    Ok(match tensor {
+        DecthingsTensor::F32(_) => f32::DTYPE,
+        DecthingsTensor::F64(_) => f64::DTYPE,
+        DecthingsTensor::I8(_) => i8::DTYPE,
+        DecthingsTensor::I16(_) => i16::DTYPE,
+        DecthingsTensor::I32(_) => i32::DTYPE,
+        DecthingsTensor::I64(_) => i64::DTYPE,
+        DecthingsTensor::U8(_) => u8::DTYPE,
+        DecthingsTensor::U16(_) => u16::DTYPE,
+        DecthingsTensor::U32(_) => u32::DTYPE,
+        DecthingsTensor::U64(_) => u64::DTYPE,
+        DecthingsTensor::Boolean(_) => bool::DTYPE,
+        other => return Err(SafetensorsError::UnsupportedType(other.typ())),
+    })
+}
+
+/// Serializes the named *tensors* in the safetensors format.
+pub fn serialize_safetensors(
+    tensors: &[(impl AsRef<str>, DecthingsTensor<'_>)],
+    metadata: &HashMap<String, String>,
+) -> Result<bytes::Bytes, SafetensorsError> {
+    let mut header = serde_json::Map::new();
+    if !metadata.is_empty() {
+        header.insert(
+            "__metadata__".to_owned(),
+            serde_json::to_value(metadata).unwrap(),
+        );
+    }
+    let mut body = vec![];
+    for (name, tensor) in tensors {
+        let name = name.as_ref();
+        let start = body.len();
+        append_le_bytes(tensor, &mut body)?;
+        let info = TensorInfo {
+            dtype: dtype_of(tensor)?.to_owned(),
+            shape: tensor.shape().to_vec(),
+            data_offsets: (start, body.len()),
+        };
+        if header
+            .insert(name.to_owned(), serde_json::to_value(info).unwrap())
+            .is_some()
+        {
+            return Err(SafetensorsError::Invalid(format!(
+                "The tensor name \"{name}\" is used more than once."
+            )));
+        }
+    }
+
+    let mut header = serde_json::to_vec(&header).unwrap();
+    // Pad the header with spaces so that the tensor data is 8-byte aligned.
+    header.resize(header.len().next_multiple_of(8), b' ');
+
+    let mut res = Vec::with_capacity(8 + header.len() + body.len());
+    res.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    res.extend_from_slice(&header);
+    res.extend_from_slice(&body);
+    Ok(res.into())
+}
+
+/// Provides the named *tensors* to *provider* as a single safetensors blob under *key*.
+pub async fn save_safetensors(
+    provider: &mut impl WeightsProvider,
+    key: &str,
+    tensors: &[(impl AsRef<str>, DecthingsTensor<'_>)],
+    metadata: &HashMap<String, String>,
+) -> Result<(), SafetensorsError> {
+    let data = serialize_safetensors(tensors, metadata)?;
+    provider.provide(key, data).await;
+    Ok(())
+}
+
+/// Reads the weights of *loader* and parses them in the safetensors format.
+pub async fn load_safetensors(
+    loader: &mut impl WeightsLoader,
+) -> Result<SafeTensors, SafetensorsError> {
+    SafeTensors::parse(loader.read().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_header(header: &str, body: &[u8]) -> bytes::Bytes {
+        let mut data = (header.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(header.as_bytes());
+        data.extend_from_slice(body);
+        data.into()
+    }
+
+    fn invalid(data: bytes::Bytes) -> bool {
+        matches!(SafeTensors::parse(data), Err(SafetensorsError::Invalid(_)))
+    }
+
+    #[test]
+    fn round_trip() {
+        let a = ndarray::arr2(&[[1.0f32, 2.0, 3.0], [4.0, 5.0, 6.0]]).into_dyn();
+        let b = ndarray::arr1(&[true, false]).into_dyn();
+        let metadata = HashMap::from([("format".to_owned(), "pt".to_owned())]);
+        let data = serialize_safetensors(
+            &[
+                ("a", DecthingsTensor::F32(a.view().into())),
+                ("b", DecthingsTensor::Boolean(b.view().into())),
+            ],
+            &metadata,
+        )
+        .unwrap();
+        // The tensor data is 8-byte aligned.
+        assert_eq!(u64::from_le_bytes(data[..8].try_into().unwrap()) % 8, 0);
+
+        let parsed = SafeTensors::parse(data).unwrap();
+        assert_eq!(parsed.metadata(), &metadata);
+        assert_eq!(parsed.dtype("a"), Some("F32"));
+        assert_eq!(parsed.tensor_with_shape::<f32>("a", &[2, 3]).unwrap(), a);
+        assert_eq!(parsed.tensor::<bool>("b").unwrap(), b);
+        assert!(matches!(
+            parsed.tensor::<i32>("a"),
+            Err(SafetensorsError::WrongDtype { .. })
+        ));
+        assert!(matches!(
+            parsed.tensor_with_shape::<f32>("a", &[3, 2]),
+            Err(SafetensorsError::WrongShape { .. })
+        ));
+        assert!(matches!(
+            parsed.tensor::<f32>("c"),
+            Err(SafetensorsError::Missing { .. })
+        ));
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let a = ndarray::arr1(&[1u8]).into_dyn();
+        let tensors = [
+            ("a", DecthingsTensor::U8(a.view().into())),
+            ("a", DecthingsTensor::U8(a.view().into())),
+        ];
+        assert!(serialize_safetensors(&tensors, &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn parses_header() {
+        let data = with_header(
+            r#"{"x":{"dtype":"I16","shape":[2],"data_offsets":[0,4]}}"#,
+            &[1, 0, 255, 255],
+        );
+        let parsed = SafeTensors::parse(data).unwrap();
+        assert_eq!(parsed.names().collect::<Vec<_>>(), ["x"]);
+        assert_eq!(parsed.shape("x"), Some(&[2][..]));
+        assert_eq!(parsed.raw("x").unwrap(), &[1, 0, 255, 255][..]);
+        assert_eq!(
+            parsed.tensor::<i16>("x").unwrap(),
+            ndarray::arr1(&[1i16, -1]).into_dyn()
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        assert!(invalid(bytes::Bytes::from_static(&[1, 0, 0])));
+        assert!(invalid(with_header("{}", &[]).slice(..9)));
+        let mut data = u64::MAX.to_le_bytes().to_vec();
+        data.extend_from_slice(b"{}");
+        assert!(invalid(data.into()));
+    }
+
+    #[test]
+    fn rejects_invalid_header() {
+        assert!(invalid(with_header("not json", &[])));
+        assert!(invalid(with_header(r#"{"x":{"dtype":"F32"}}"#, &[])));
+        assert!(invalid(with_header(r#"{"__metadata__":{"a":1}}"#, &[])));
+    }
+
+    #[test]
+    fn rejects_out_of_range_offsets() {
+        let body = [0; 8];
+        assert!(invalid(with_header(
+            r#"{"x":{"dtype":"U8","shape":[4],"data_offsets":[6,10]}}"#,
+            &body
+        )));
+        assert!(invalid(with_header(
+            r#"{"x":{"dtype":"U8","shape":[0],"data_offsets":[4,2]}}"#,
+            &body
+        )));
+    }
+
+    #[test]
+    fn rejects_size_mismatch() {
+        assert!(invalid(with_header(
+            r#"{"x":{"dtype":"F32","shape":[3],"data_offsets":[0,8]}}"#,
+            &[0; 8]
+        )));
+        assert!(invalid(with_header(
+            r#"{"x":{"dtype":"U8","shape":[18446744073709551615,2],"data_offsets":[0,8]}}"#,
+            &[0; 8]
+        )));
+    }
+
+    #[test]
+    fn rejects_overlapping_offsets() {
+        assert!(invalid(with_header(
+            r#"{"x":{"dtype":"U8","shape":[4],"data_offsets":[0,4]},"y":{"dtype":"U8","shape":[4],"data_offsets":[2,6]}}"#,
+            &[0; 8]
+        )));
+        assert!(invalid(with_header(
+            r#"{"x":{"dtype":"U8","shape":[4],"data_offsets":[0,4]},"y":{"dtype":"U8","shape":[4],"data_offsets":[0,4]}}"#,
+            &[0; 8]
+        )));
+        let empty = with_header(
+            r#"{"x":{"dtype":"U8","shape":[4],"data_offsets":[0,4]},"y":{"dtype":"U8","shape":[0],"data_offsets":[4,4]},"z":{"dtype":"U8","shape":[4],"data_offsets":[4,8]}}"#,
+            &[0; 8],
+        );
+        assert!(SafeTensors::parse(empty).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+#![cfg(target_family = "unix")]
+
+use std::collections::HashMap;
+
+use decthings_model::{
+    ChildModel, ChildModelError, DataLoader, DataRequestOptions, EvaluateOptions, EvaluateOutput,
+    InMemoryDataLoader, InstantiateModelOptions, ModelInputs, ParameterDefinitions,
+    RunModelOptions, WeightsLoader,
+    decthings_api::tensor::{DecthingsTensor, OwnedDecthingsTensor},
+};
+use ndarray::{Array0, arr0};
+
+/// Set in the environment of the child process, which makes the child_process test run the model.
+const CHILD_ENV: &str = "DECTHINGS_MODEL_TEST_CHILD";
+
+const SEED: u64 = 42;
+
+#[derive(ModelInputs)]
+#[allow(dead_code)]
+struct Inputs {
+    input: Vec<Array0<f32>>,
+}
+
+/// Returns its input, shuffled with SEED.
+struct Shuffler;
+
+#[decthings_model::model(main = false)]
+impl Shuffler {
+    fn parameter_definitions() -> ParameterDefinitions {
+        ParameterDefinitions {
+            evaluate: Some(Inputs::parameter_definitions()),
+            ..Default::default()
+        }
+    }
+
+    async fn instantiate_model(
+        options: InstantiateModelOptions<impl WeightsLoader>,
+    ) -> ShufflerInstantiated {
+        let _ = options;
+        ShufflerInstantiated
+    }
+}
+
+struct ShufflerInstantiated;
+
+#[decthings_model::instantiated]
+impl ShufflerInstantiated {
+    async fn evaluate(&self, options: EvaluateOptions<impl DataLoader>) -> Vec<EvaluateOutput> {
+        let mut params = options.params;
+        let input = params.get_mut("input").unwrap();
+        input.shuffle_with_seed(SEED).await;
+        let data = input.next(input.remaining()).await;
+        vec![EvaluateOutput {
+            name: "output".to_owned(),
+            data,
+        }]
+    }
+}
+
+/// Runs Shuffler when this test binary is spawned by ChildModel::spawn, and does nothing
+/// otherwise. Reads are split into chunks of 3 data points, with at most 2 in flight.
+#[test]
+fn child_process() {
+    if std::env::var_os(CHILD_ENV).is_none() {
+        return;
+    }
+    let options = RunModelOptions {
+        data_requests: DataRequestOptions {
+            chunk_size: 3,
+            max_in_flight: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(decthings_model::run_model_with_options::<Shuffler>(options));
+}
+
+fn child_command() -> std::process::Command {
+    let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+    command
+        .args(["--exact", "child_process", "--test-threads=1"])
+        .env(CHILD_ENV, "1")
+        .stdout(std::process::Stdio::null());
+    command
+}
+
+fn input(size: u32) -> InMemoryDataLoader {
+    InMemoryDataLoader::from_tensors(
+        (0..size).map(|i| DecthingsTensor::F32(arr0(i as f32).into_dyn().into()).into()),
+    )
+}
+
+fn values(tensors: &[OwnedDecthingsTensor]) -> Vec<f32> {
+    tensors
+        .iter()
+        .map(|tensor| match tensor.tensor() {
+            DecthingsTensor::F32(array) => array.first().copied().unwrap(),
+            _ => panic!("Expected f32"),
+        })
+        .collect()
+}
+
+fn evaluate_options(
+    params: impl IntoIterator<Item = (&'static str, InMemoryDataLoader)>,
+) -> EvaluateOptions<InMemoryDataLoader> {
+    EvaluateOptions {
+        params: params
+            .into_iter()
+            .map(|(name, data_loader)| (name.to_owned(), data_loader))
+            .collect(),
+        expected_output_types: HashMap::new(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn evaluate_round_trips_through_the_child() {
+    let model = ChildModel::spawn(child_command()).await.unwrap();
+    let instantiated = model.instantiate(HashMap::new()).await.unwrap();
+
+    // 10 data points are requested in 4 chunks, which are served in the order of the seed.
+    let outputs = instantiated
+        .try_evaluate(evaluate_options([("input", input(10))]))
+        .await
+        .unwrap();
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(outputs[0].name, "output");
+    let expected: Vec<f32> = decthings_model::shuffle_permutation(10, SEED)
+        .into_iter()
+        .map(|i| i as f32)
+        .collect();
+    assert_eq!(values(&outputs[0].data), expected);
+
+    let res = instantiated
+        .try_evaluate(evaluate_options([("other", input(1))]))
+        .await;
+    assert!(
+        matches!(&res, Err(ChildModelError::InvalidParameters { details }) if details.contains("\"other\"")),
+        "{:?}",
+        res.err()
+    );
+
+    instantiated.try_dispose().await.unwrap();
+    let res = instantiated
+        .try_evaluate(evaluate_options([("input", input(1))]))
+        .await;
+    assert!(
+        matches!(res, Err(ChildModelError::InstantiatedModelNotFound)),
+        "{:?}",
+        res.err()
+    );
+    // Disposing again does nothing.
+    instantiated.try_dispose().await.unwrap();
+}
+
+#[test]
+fn instantiated_can_be_dropped_outside_a_runtime() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (model, instantiated) = runtime.block_on(async {
+        let model = ChildModel::spawn(child_command()).await.unwrap();
+        let instantiated = model.instantiate(HashMap::new()).await.unwrap();
+        (model, instantiated)
+    });
+    drop(instantiated);
+
+    runtime.block_on(async {
+        let instantiated = model.instantiate(HashMap::new()).await.unwrap();
+        let outputs = instantiated
+            .try_evaluate(evaluate_options([("input", input(2))]))
+            .await
+            .unwrap();
+        assert_eq!(outputs[0].data.len(), 2);
+    });
+}
@@ -0,0 +1,7 @@
+/// Checks that misuses of the derive and attribute macros fail to compile with a clear error. The
+/// expected errors are in the .stderr files next to each case, which can be regenerated by running
+/// the test with TRYBUILD=overwrite.
+#[test]
+fn compile_fail() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use decthings_model::{
+    DataLoader, EvaluateOptions, EvaluateOutput, GetWeightsOptions, InMemoryDataLoader,
+    InstantiateModelOptions, Instantiated, MetricBinary, ModelBinary, ModelWeights, SharedContext,
+    TrainTrackerBinary, WeightsLoader, WeightsProvider,
+    decthings_api::tensor::{DecthingsTensor, OwnedDecthingsTensor},
+    futures::{self, executor::block_on, future::BoxFuture},
+};
+use ndarray::{ArrayD, arr1};
+
+#[derive(ModelWeights)]
+struct Weights {
+    scale: f32,
+}
+
+/// The offset that Scaler::setup stores in the context.
+struct Offset(f32);
+
+struct Scaler;
+
+#[decthings_model::model(main = false)]
+impl Scaler {
+    async fn setup(context: &mut SharedContext) {
+        context.insert(Offset(1.0));
+    }
+
+    async fn instantiate_model(
+        options: InstantiateModelOptions<impl WeightsLoader>,
+    ) -> ScalerInstantiated {
+        let mut weights = options.weights;
+        let weights: Weights = decthings_model::load_weights(&mut weights).await.unwrap();
+        ScalerInstantiated {
+            scale: weights.scale,
+            offset: options.context.get::<Offset>().unwrap().0,
+            disposed: AtomicBool::new(false),
+        }
+    }
+
+    fn name() -> &'static str {
+        "scaler"
+    }
+}
+
+struct ScalerInstantiated {
+    scale: f32,
+    offset: f32,
+    disposed: AtomicBool,
+}
+
+#[decthings_model::instantiated]
+impl ScalerInstantiated {
+    async fn evaluate(&self, options: EvaluateOptions<impl DataLoader>) -> Vec<EvaluateOutput> {
+        let mut params = options.params;
+        let input = params.get_mut("input").unwrap();
+        let data = input.next(input.remaining()).await;
+        let data = data
+            .iter()
+            .map(|tensor| match tensor.tensor() {
+                DecthingsTensor::F32(array) => {
+                    let array: ArrayD<f32> = array.mapv(|x| self.transform(x));
+                    OwnedDecthingsTensor::from(DecthingsTensor::F32(array.into()))
+                }
+                _ => panic!("Expected f32"),
+            })
+            .collect();
+        vec![EvaluateOutput {
+            name: "output".to_owned(),
+            data,
+        }]
+    }
+
+    async fn get_weights(&self, options: GetWeightsOptions<impl WeightsProvider>) {
+        let mut provider = options.weights_provider;
+        decthings_model::save_weights(&Weights { scale: self.scale }, &mut provider).await;
+    }
+
+    async fn dispose(&self) {
+        self.disposed.store(true, Ordering::Relaxed);
+    }
+
+    fn transform(&self, x: f32) -> f32 {
+        x * self.scale + self.offset
+    }
+}
+
+#[derive(Clone, Default)]
+struct CollectingProvider(Arc<Mutex<HashMap<String, decthings_model::bytes::Bytes>>>);
+
+impl WeightsProvider for CollectingProvider {
+    fn provide_all<'a>(
+        &'a mut self,
+        data: &'a [(
+            impl AsRef<str> + Send + Sync + 'a,
+            decthings_model::bytes::Bytes,
+        )],
+    ) -> BoxFuture<'a, ()> {
+        let mut weights = self.0.lock().unwrap();
+        for (key, data) in data {
+            weights.insert(key.as_ref().to_owned(), data.clone());
+        }
+        Box::pin(async {})
+    }
+}
+
+struct NoTracker;
+
+impl TrainTrackerBinary for NoTracker {
+    fn wait_for_cancelled(&self) -> BoxFuture<'_, ()> {
+        Box::pin(futures::future::pending())
+    }
+
+    fn progress(&self, progress: f32) -> BoxFuture<'_, ()> {
+        let _ = progress;
+        Box::pin(async {})
+    }
+
+    fn metrics<'a>(
+        &'a self,
+        metrics: &'a [MetricBinary<impl AsRef<str> + Sync + 'a>],
+    ) -> BoxFuture<'a, ()> {
+        let _ = metrics;
+        Box::pin(async {})
+    }
+}
+
+fn instantiate() -> ScalerInstantiated {
+    let mut context = SharedContext::default();
+    block_on(<Scaler as ModelBinary>::setup(&mut context));
+    let weights = Weights { scale: 2.0 }.to_weights().into_iter().collect();
+    block_on(decthings_model::instantiate_in_memory::<Scaler>(
+        weights,
+        HashMap::new(),
+        context,
+    ))
+}
+
+#[test]
+fn methods_implement_the_traits() {
+    let instantiated = instantiate();
+    assert_eq!(Scaler::name(), "scaler");
+
+    let input = InMemoryDataLoader::from_tensors([DecthingsTensor::F32(
+        arr1(&[1.0f32, 2.0]).into_dyn().into(),
+    )
+    .into()]);
+    let outputs = block_on(Instantiated::evaluate(
+        &instantiated,
+        EvaluateOptions {
+            params: HashMap::from([("input".to_owned(), input)]),
+            expected_output_types: HashMap::new(),
+        },
+    ));
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(outputs[0].name, "output");
+    let DecthingsTensor::F32(output) = outputs[0].data[0].tensor() else {
+        panic!("Expected f32");
+    };
+    assert_eq!(output.as_slice().unwrap(), [3.0, 5.0]);
+
+    let provider = CollectingProvider::default();
+    block_on(Instantiated::get_weights(
+        &instantiated,
+        GetWeightsOptions {
+            weights_provider: provider.clone(),
+            instantiated_weights: vec![],
+        },
+    ));
+    let weights = Weights::from_weights(&provider.0.lock().unwrap()).unwrap();
+    assert_eq!(weights.scale, 2.0);
+
+    block_on(Instantiated::dispose(&instantiated));
+    assert!(instantiated.disposed.load(Ordering::Relaxed));
+}
+
+#[test]
+#[should_panic(expected = "Train was called but was not implemented.")]
+fn methods_that_are_not_given_keep_the_default() {
+    let instantiated = instantiate();
+    block_on(Instantiated::train(
+        &instantiated,
+        decthings_model::TrainOptions {
+            params: HashMap::<String, InMemoryDataLoader>::new(),
+            tracker: NoTracker,
+        },
+    ));
+}
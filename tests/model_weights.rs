@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use decthings_model::{ModelWeights, WeightsError, bytes::Bytes};
+use ndarray::{Array1, Array2, arr1, arr2};
+
+#[derive(ModelWeights, Debug, PartialEq)]
+struct Encoder {
+    weight: Array2<f32>,
+    bias: Vec<f32>,
+}
+
+#[derive(ModelWeights, Debug, PartialEq)]
+struct State {
+    encoder: Encoder,
+    #[weights(rename = "out")]
+    output: Array1<f64>,
+    #[weights(skip)]
+    steps: u64,
+    r#type: u8,
+}
+
+#[derive(ModelWeights, Debug, PartialEq)]
+struct Pair(f32, Vec<i32>);
+
+#[derive(ModelWeights, Debug, PartialEq)]
+struct Layer<T> {
+    weight: T,
+    bias: f32,
+}
+
+fn state() -> State {
+    State {
+        encoder: Encoder {
+            weight: arr2(&[[1.0, 2.0], [3.0, 4.0]]),
+            bias: vec![0.5, -0.5],
+        },
+        output: arr1(&[1.5, 2.5, 3.5]),
+        steps: 100,
+        r#type: 7,
+    }
+}
+
+fn keys(weights: &[(String, Bytes)]) -> Vec<&str> {
+    let mut keys: Vec<_> = weights.iter().map(|(key, _)| key.as_str()).collect();
+    keys.sort_unstable();
+    keys
+}
+
+fn to_map(weights: Vec<(String, Bytes)>) -> HashMap<String, Bytes> {
+    weights.into_iter().collect()
+}
+
+#[test]
+fn fields_are_stored_under_dotted_keys() {
+    let weights = state().to_weights();
+    assert_eq!(
+        keys(&weights),
+        ["encoder.bias", "encoder.weight", "out", "type"]
+    );
+}
+
+#[test]
+fn round_trip_resets_skipped_fields() {
+    let loaded = State::from_weights(&to_map(state().to_weights())).unwrap();
+    assert_eq!(
+        loaded,
+        State {
+            steps: 0,
+            ..state()
+        }
+    );
+}
+
+#[test]
+fn tuple_structs_use_indices_as_keys() {
+    let pair = Pair(1.0, vec![1, 2, 3]);
+    let weights = pair.to_weights();
+    assert_eq!(keys(&weights), ["0", "1"]);
+    assert_eq!(Pair::from_weights(&to_map(weights)).unwrap(), pair);
+}
+
+#[test]
+fn generic_structs() {
+    let layer = Layer {
+        weight: arr2(&[[1.0f64, 2.0]]),
+        bias: 3.0,
+    };
+    let weights = layer.to_weights();
+    assert_eq!(keys(&weights), ["bias", "weight"]);
+    assert_eq!(Layer::from_weights(&to_map(weights)).unwrap(), layer);
+
+    let nested = Layer {
+        weight: Pair(1.0, vec![2]),
+        bias: 3.0,
+    };
+    assert_eq!(keys(&nested.to_weights()), ["bias", "weight.0", "weight.1"]);
+}
+
+#[test]
+fn missing_and_mistyped_keys_fail() {
+    let mut weights = to_map(state().to_weights());
+    weights.remove("out");
+    assert!(matches!(
+        State::from_weights(&weights),
+        Err(WeightsError::Missing { key }) if key == "out"
+    ));
+
+    let mut weights = to_map(state().to_weights());
+    let bias = weights["encoder.bias"].clone();
+    weights.insert("type".to_owned(), bias);
+    assert!(matches!(
+        State::from_weights(&weights),
+        Err(WeightsError::WrongType { key, .. }) if key == "type"
+    ));
+}
+
+#[derive(ModelWeights)]
+#[weights(schema_version = 1)]
+struct V1 {
+    w: Vec<f32>,
+}
+
+#[derive(ModelWeights, Debug)]
+#[weights(schema_version = 2, migrate = migrate_v1)]
+struct V2 {
+    weight: Vec<f32>,
+}
+
+fn migrate_v1(from_version: u32, weights: &mut HashMap<String, Bytes>) -> Result<(), WeightsError> {
+    if from_version != 1 {
+        return Err(WeightsError::SchemaMismatch {
+            expected: 2,
+            found: from_version,
+        });
+    }
+    let w = weights.remove("w").ok_or_else(|| WeightsError::Missing {
+        key: "w".to_owned(),
+    })?;
+    weights.insert("weight".to_owned(), w);
+    Ok(())
+}
+
+#[derive(ModelWeights, Debug)]
+#[weights(schema_version = 3)]
+struct V3 {
+    w: Vec<f32>,
+}
+
+#[test]
+fn schema_version_and_migrate() {
+    assert_eq!(V1::SCHEMA_VERSION, 1);
+    assert_eq!(V2::SCHEMA_VERSION, 2);
+    assert_eq!(State::SCHEMA_VERSION, 0);
+
+    let v1 = to_map(V1 { w: vec![1.0, 2.0] }.to_weights());
+    let v2 = V2::from_weights(&v1).unwrap();
+    assert_eq!(v2.weight, [1.0, 2.0]);
+
+    // Without migrate, other schema versions are rejected.
+    assert!(matches!(
+        V3::from_weights(&v1),
+        Err(WeightsError::SchemaMismatch {
+            expected: 3,
+            found: 1
+        })
+    ));
+}
+
+#[test]
+fn corrupt_weights_fail_the_checksum() {
+    let mut weights = to_map(state().to_weights());
+    let mut out = weights["out"].to_vec();
+    *out.last_mut().unwrap() ^= 1;
+    weights.insert("out".to_owned(), out.into());
+    assert!(matches!(
+        State::from_weights(&weights),
+        Err(WeightsError::ChecksumMismatch { key }) if key == "out"
+    ));
+}
+
+#[derive(ModelWeights, Debug, PartialEq)]
+#[weights(accept_headerless)]
+struct Legacy {
+    weight: Vec<f32>,
+}
+
+#[test]
+fn keys_without_header_need_accept_headerless() {
+    // Weights saved without headers, as before ModelWeights::to_weights added them.
+    let mut weights = vec![];
+    Legacy {
+        weight: vec![1.0, 2.0],
+    }
+    .save_weights("", &mut weights);
+    let weights = to_map(weights);
+
+    assert_eq!(
+        Legacy::from_weights(&weights).unwrap(),
+        Legacy {
+            weight: vec![1.0, 2.0]
+        }
+    );
+    assert!(matches!(
+        V3::from_weights(&HashMap::from([("w".to_owned(), weights["weight"].clone())])),
+        Err(WeightsError::MissingHeader { key }) if key == "w"
+    ));
+}
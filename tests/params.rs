@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use decthings_model::{
+    InMemoryDataLoader, InputParam, ModelInputs, ModelOutputs, ParamsError,
+    decthings_api::tensor::{DecthingsElementType, DecthingsTensor, OwnedDecthingsTensor},
+    futures::executor::block_on,
+};
+use ndarray::{Array1, Array2, ArrayD, arr1, arr2};
+
+#[derive(ModelInputs, Debug, PartialEq)]
+struct Inputs {
+    image: Vec<Array2<f32>>,
+    #[param(rename = "classLabel")]
+    label: Vec<Array1<u8>>,
+    #[param(shape = [-1, 3])]
+    features: Option<Vec<ArrayD<f64>>>,
+    r#type: Option<Vec<Array1<i64>>>,
+}
+
+#[derive(ModelInputs)]
+struct Raw {
+    #[param(shape = [-1, 2])]
+    data: Vec<OwnedDecthingsTensor>,
+}
+
+#[derive(ModelOutputs)]
+struct Outputs {
+    #[param(shape = [2])]
+    probabilities: Vec<Array1<f32>>,
+    #[param(rename = "debugInfo")]
+    debug: Option<Vec<Array1<u8>>>,
+}
+
+#[derive(ModelInputs)]
+struct Generic<P: InputParam> {
+    value: P,
+}
+
+fn loader<T: Clone>(
+    arrays: &[ndarray::Array<T, impl ndarray::Dimension>],
+    f: fn(ArrayD<T>) -> DecthingsTensor<'static>,
+) -> InMemoryDataLoader {
+    InMemoryDataLoader::from_tensors(
+        arrays
+            .iter()
+            .map(|array| OwnedDecthingsTensor::from(f(array.clone().into_dyn()))),
+    )
+}
+
+fn image() -> InMemoryDataLoader {
+    loader(&[arr2(&[[1.0f32, 2.0], [3.0, 4.0]])], |x| {
+        DecthingsTensor::F32(x.into())
+    })
+}
+
+fn label() -> InMemoryDataLoader {
+    loader(&[arr1(&[7u8])], |x| DecthingsTensor::U8(x.into()))
+}
+
+fn from_params(
+    params: impl IntoIterator<Item = (&'static str, InMemoryDataLoader)>,
+) -> Result<Inputs, ParamsError> {
+    block_on(Inputs::from_params(
+        params
+            .into_iter()
+            .map(|(name, data_loader)| (name.to_owned(), data_loader))
+            .collect::<HashMap<_, _>>(),
+    ))
+}
+
+#[test]
+fn input_definitions_follow_the_fields() {
+    let definitions = Inputs::parameter_definitions();
+    let names: Vec<_> = definitions.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, ["image", "classLabel", "features", "type"]);
+    let required: Vec<_> = definitions.iter().map(|x| x.required).collect();
+    assert_eq!(required, [true, true, false, false]);
+
+    assert_eq!(definitions[0].rules.shape, [None, None]);
+    assert_eq!(
+        definitions[1].rules.allowed_types,
+        [DecthingsElementType::U8]
+    );
+    assert_eq!(definitions[2].rules.shape, [None, Some(3)]);
+    assert_eq!(
+        definitions[3].rules.allowed_types,
+        [DecthingsElementType::I64]
+    );
+
+    let definitions = Raw::parameter_definitions();
+    assert_eq!(definitions[0].rules.shape, [None, Some(2)]);
+    assert_eq!(definitions[0].rules.allowed_types.len(), 16);
+}
+
+#[test]
+fn tensors_of_any_type_are_read_with_their_shape_checked() {
+    let data = loader(&[arr2(&[[1u8, 2]]), arr2(&[[3, 4], [5, 6]])], |x| {
+        DecthingsTensor::U8(x.into())
+    });
+    let raw = block_on(Raw::from_params(HashMap::from([("data".to_owned(), data)]))).unwrap();
+    assert_eq!(raw.data.len(), 2);
+
+    let data = loader(&[arr2(&[[1u8, 2, 3]])], |x| DecthingsTensor::U8(x.into()));
+    let res = block_on(Raw::from_params(HashMap::from([("data".to_owned(), data)])));
+    assert!(matches!(res, Err(ParamsError::WrongShape { index: 0, .. })));
+}
+
+#[test]
+fn output_definitions_follow_the_fields() {
+    let definitions = Outputs::parameter_definitions();
+    let names: Vec<_> = definitions.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, ["probabilities", "debugInfo"]);
+    assert_eq!(definitions[0].rules.shape, [Some(2)]);
+    assert!(definitions[0].required);
+    assert!(!definitions[1].required);
+}
+
+#[test]
+fn inputs_are_read_from_params() {
+    let features = loader(&[arr2(&[[1.0f64, 2.0, 3.0]])], |x| {
+        DecthingsTensor::F64(x.into())
+    });
+    let inputs = from_params([
+        ("image", image()),
+        ("classLabel", label()),
+        ("features", features),
+    ])
+    .unwrap();
+    assert_eq!(inputs.image, [arr2(&[[1.0, 2.0], [3.0, 4.0]])]);
+    assert_eq!(inputs.label, [arr1(&[7])]);
+    assert_eq!(
+        inputs.features,
+        Some(vec![arr2(&[[1.0, 2.0, 3.0]]).into_dyn()])
+    );
+    assert!(inputs.r#type.is_none());
+}
+
+#[test]
+fn missing_required_param_is_an_error() {
+    let res = from_params([("image", image())]);
+    assert!(
+        matches!(&res, Err(ParamsError::Missing { name }) if name == "classLabel"),
+        "{res:?}"
+    );
+}
+
+#[test]
+fn undefined_param_is_an_error() {
+    let res = from_params([
+        ("image", image()),
+        ("classLabel", label()),
+        ("label", label()),
+    ]);
+    assert!(
+        matches!(&res, Err(ParamsError::Unexpected { name }) if name == "label"),
+        "{res:?}"
+    );
+}
+
+#[test]
+fn data_that_does_not_fit_the_field_is_an_error() {
+    let res = from_params([("image", label()), ("classLabel", label())]);
+    assert!(
+        matches!(
+            &res,
+            Err(ParamsError::WrongType { name, index: 0, expected: DecthingsElementType::F32, .. })
+                if name == "image"
+        ),
+        "{res:?}"
+    );
+
+    let features = loader(&[arr1(&[1.0f64, 2.0])], |x| DecthingsTensor::F64(x.into()));
+    let res = from_params([
+        ("image", image()),
+        ("classLabel", label()),
+        ("features", features),
+    ]);
+    assert!(
+        matches!(&res, Err(ParamsError::WrongShape { name, index: 0, shape }) if name == "features" && shape == &[2]),
+        "{res:?}"
+    );
+}
+
+#[test]
+fn optional_outputs_that_are_none_are_left_out() {
+    let outputs = Outputs {
+        probabilities: vec![arr1(&[0.25, 0.75])],
+        debug: None,
+    }
+    .into_outputs();
+    assert_eq!(outputs.len(), 1);
+    assert_eq!(outputs[0].name, "probabilities");
+    let DecthingsTensor::F32(data) = outputs[0].data[0].tensor() else {
+        panic!("Expected f32");
+    };
+    assert_eq!(data.as_slice().unwrap(), [0.25, 0.75]);
+
+    let outputs = Outputs {
+        probabilities: vec![],
+        debug: Some(vec![arr1(&[1, 2])]),
+    }
+    .into_outputs();
+    let names: Vec<_> = outputs.iter().map(|x| x.name.as_str()).collect();
+    assert_eq!(names, ["probabilities", "debugInfo"]);
+}
+
+#[test]
+fn generic_inputs_are_read() {
+    let definitions = Generic::<Vec<Array1<i32>>>::parameter_definitions();
+    assert_eq!(definitions[0].rules.shape, [None]);
+    assert_eq!(
+        definitions[0].rules.allowed_types,
+        [DecthingsElementType::I32]
+    );
+
+    let value = loader(&[arr1(&[1i32, 2])], |x| DecthingsTensor::I32(x.into()));
+    let inputs = block_on(Generic::<Vec<Array1<i32>>>::from_params(HashMap::from([(
+        "value".to_owned(),
+        value,
+    )])))
+    .unwrap();
+    assert_eq!(inputs.value, [arr1(&[1, 2])]);
+}
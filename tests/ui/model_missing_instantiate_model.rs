@@ -0,0 +1,8 @@
+struct Model;
+
+#[decthings_model::model(main = false)]
+impl Model {
+    fn helper() {}
+}
+
+fn main() {}
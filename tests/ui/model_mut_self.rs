@@ -0,0 +1,8 @@
+struct Instantiated;
+
+#[decthings_model::instantiated]
+impl Instantiated {
+    async fn dispose(&mut self) {}
+}
+
+fn main() {}
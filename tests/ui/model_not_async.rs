@@ -0,0 +1,18 @@
+struct Model;
+
+struct Instantiated;
+
+#[decthings_model::instantiated]
+impl Instantiated {}
+
+#[decthings_model::model(main = false)]
+impl Model {
+    fn instantiate_model(
+        options: decthings_model::InstantiateModelOptions<impl decthings_model::WeightsLoader>,
+    ) -> Instantiated {
+        let _ = options;
+        Instantiated
+    }
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+struct Instantiated;
+
+#[decthings_model::instantiated]
+impl decthings_model::Instantiated for Instantiated {}
+
+fn main() {}
@@ -0,0 +1,6 @@
+struct Instantiated;
+
+#[decthings_model::instantiated(main = false)]
+impl Instantiated {}
+
+fn main() {}
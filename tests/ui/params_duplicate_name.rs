@@ -0,0 +1,11 @@
+use decthings_model::ModelInputs;
+use ndarray::Array1;
+
+#[derive(ModelInputs)]
+struct Inputs {
+    label: Vec<Array1<u8>>,
+    #[param(rename = "label")]
+    target: Vec<Array1<u8>>,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use decthings_model::ModelInputs;
+
+#[derive(ModelInputs)]
+enum Inputs {
+    A,
+    B,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use decthings_model::ModelInputs;
+use ndarray::ArrayD;
+
+#[derive(ModelInputs)]
+struct Inputs {
+    image: Vec<ArrayD<f32>>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use decthings_model::ModelOutputs;
+use ndarray::Array2;
+
+#[derive(ModelOutputs)]
+struct Outputs {
+    #[param(shape = [-1, 28, 28])]
+    image: Vec<Array2<f32>>,
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+use decthings_model::ModelOutputs;
+use ndarray::Array1;
+
+#[derive(ModelOutputs)]
+struct Outputs(Vec<Array1<f32>>);
+
+fn main() {}
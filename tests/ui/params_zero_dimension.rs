@@ -0,0 +1,10 @@
+use decthings_model::ModelInputs;
+use ndarray::Array1;
+
+#[derive(ModelInputs)]
+struct Inputs {
+    #[param(shape = [0])]
+    label: Vec<Array1<u8>>,
+}
+
+fn main() {}
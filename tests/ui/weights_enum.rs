@@ -0,0 +1,9 @@
+use decthings_model::ModelWeights;
+
+#[derive(ModelWeights)]
+enum State {
+    A,
+    B,
+}
+
+fn main() {}
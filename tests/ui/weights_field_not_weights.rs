@@ -0,0 +1,8 @@
+use decthings_model::ModelWeights;
+
+#[derive(ModelWeights)]
+struct State {
+    name: String,
+}
+
+fn main() {}
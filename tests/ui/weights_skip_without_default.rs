@@ -0,0 +1,12 @@
+use decthings_model::ModelWeights;
+
+struct Optimizer;
+
+#[derive(ModelWeights)]
+struct State {
+    bias: Vec<f32>,
+    #[weights(skip)]
+    optimizer: Optimizer,
+}
+
+fn main() {}
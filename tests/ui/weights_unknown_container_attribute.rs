@@ -0,0 +1,9 @@
+use decthings_model::ModelWeights;
+
+#[derive(ModelWeights)]
+#[weights(version = 2)]
+struct State {
+    bias: Vec<f32>,
+}
+
+fn main() {}
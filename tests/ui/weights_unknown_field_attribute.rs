@@ -0,0 +1,9 @@
+use decthings_model::ModelWeights;
+
+#[derive(ModelWeights)]
+struct State {
+    #[weights(flatten)]
+    bias: Vec<f32>,
+}
+
+fn main() {}